@@ -0,0 +1,158 @@
+//! # Basis
+//! A compact representation of the computational basis state labelling a ket. Registers of up to
+//! 128 qubits are stored inline in an integer, so copying, comparing and hashing them never touches
+//! the heap. Wider registers fall back to a bit vector.
+
+extern crate bit_vec;
+use std::convert::TryFrom;
+use std::fmt;
+use bit_vec::BitVec;
+use serde::{Deserialize, Serialize};
+
+/// The widest register which is stored inline.
+pub const INLINE_QUBITS:usize = 128;
+
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "SerializedBasisState", try_from = "SerializedBasisState")]
+pub enum BasisState {
+    Inline { bits: u128, len: usize },
+    Wide(BitVec)
+}
+
+/// Initializes the all-zero basis state of the given number of qubits.
+pub fn create_basis_state(len:usize) -> BasisState {
+    if len <= INLINE_QUBITS {
+        BasisState::Inline{bits: 0, len}
+    }
+    else {
+        BasisState::Wide(BitVec::from_elem(len, false))
+    }
+}
+
+/// Initializes a basis state of the given number of qubits from an integer, where bit `i` of the
+/// integer is the value of qubit `i`.
+pub fn create_basis_state_from_int(bits:u128, len:usize) -> BasisState {
+    let mut state = create_basis_state(len);
+    for qubit in 0..len.min(INLINE_QUBITS) {
+        if (bits >> qubit) & 1 == 1 {
+            state.set(qubit, true);
+        }
+    }
+    state
+}
+
+impl From<BitVec> for BasisState {
+    fn from(val:BitVec) -> BasisState {
+        if val.len() <= INLINE_QUBITS {
+            let mut state = create_basis_state(val.len());
+            for (qubit, bit) in val.iter().enumerate() {
+                state.set(qubit, bit);
+            }
+            state
+        }
+        else {
+            BasisState::Wide(val)
+        }
+    }
+}
+
+impl BasisState {
+
+    /// The number of qubits in the basis state.
+    pub fn len(&self) -> usize {
+        match self {
+            BasisState::Inline{len, ..} => *len,
+            BasisState::Wide(val) => val.len()
+        }
+    }
+
+    /// Whether the basis state has no qubits.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The value of the given qubit, or `None` if it is out of range.
+    pub fn get(&self, qubit:usize) -> Option<bool> {
+        match self {
+            BasisState::Inline{bits, len} => {
+                if qubit < *len { Some((bits >> qubit) & 1 == 1) } else { None }
+            },
+            BasisState::Wide(val) => val.get(qubit)
+        }
+    }
+
+    /// Sets the value of the given qubit.
+    pub fn set(&mut self, qubit:usize, value:bool) {
+        if qubit >= self.len() {
+            panic!("attempt to set non-existent qubit.");
+        }
+        match self {
+            BasisState::Inline{bits, ..} => {
+                if value { *bits |= 1 << qubit } else { *bits &= !(1 << qubit) }
+            },
+            BasisState::Wide(val) => val.set(qubit, value)
+        }
+    }
+
+    /// Flips the value of the given qubit.
+    pub fn flip(&mut self, qubit:usize) {
+        match self.get(qubit) {
+            Some(value) => self.set(qubit, !value),
+            None => panic!("attempt to flip non-existent qubit.")
+        }
+    }
+
+    /// The basis state as an integer, where bit `i` is the value of qubit `i`. Returns `None` for
+    /// registers too wide to be stored inline.
+    pub fn to_int(&self) -> Option<u128> {
+        match self {
+            BasisState::Inline{bits, ..} => Some(*bits),
+            BasisState::Wide(_) => None
+        }
+    }
+
+    /// Copies the basis state into a bit vector.
+    pub fn to_bitvec(&self) -> BitVec {
+        match self {
+            BasisState::Inline{len, ..} => BitVec::from_fn(*len, |qubit| self.get(qubit) == Some(true)),
+            BasisState::Wide(val) => val.clone()
+        }
+    }
+}
+
+/// The serialized form of a basis state: its number of qubits and its bits packed into bytes,
+/// most significant bit first.
+#[derive(Serialize, Deserialize)]
+struct SerializedBasisState {
+    len: usize,
+    bytes: Vec<u8>
+}
+
+impl From<BasisState> for SerializedBasisState {
+    fn from(state:BasisState) -> SerializedBasisState {
+        SerializedBasisState{len: state.len(), bytes: state.to_bitvec().to_bytes()}
+    }
+}
+
+impl TryFrom<SerializedBasisState> for BasisState {
+    type Error = String;
+
+    fn try_from(state:SerializedBasisState) -> Result<BasisState, String> {
+        let expected = state.len.div_ceil(8);
+        if state.bytes.len() != expected {
+            return Err(format!("expected {} bytes for a {} qubit basis state but found {}", expected, state.len, state.bytes.len()));
+        }
+        let mut val = BitVec::from_bytes(&state.bytes);
+        val.truncate(state.len);
+        Ok(BasisState::from(val))
+    }
+}
+
+impl fmt::Debug for BasisState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for qubit in 0..self.len() {
+            write!(f, "{}", if self.get(qubit) == Some(true) { 1 } else { 0 })?;
+        }
+        Ok(())
+    }
+}
@@ -1,25 +1,22 @@
 extern crate bit_vec;
 use bit_vec::BitVec;
-use std::time::Instant;
-use std::collections::BTreeMap;
-mod ket;
-mod state;
-mod coefficient;
+use std::thread;
+use std::time::{Duration, Instant};
+use rustsimulationservice::{basis, coefficient, ket, state};
 
-fn create_eleven_bit_ket() -> ket::Ket {
+fn create_zero_ket(num_bits:usize) -> ket::Ket {
     let imaginary_coeff = coefficient::create_coefficient(1.0, true);
     let real_coeff = coefficient::create_coefficient(1.0, false);
     let complex_coeff = coefficient::create_complex_coefficient(real_coeff, imaginary_coeff);
-    let init_state = BitVec::from_elem(11, false);
-    let ket = ket::create_ket(complex_coeff, init_state.clone(), vec![]);
-    ket
+    ket::create_ket(complex_coeff, basis::create_basis_state(num_bits), vec![])
 }
 
-fn ten_qubit_counterfeit_coin_finding() -> BitVec {
+fn ten_qubit_counterfeit_coin_finding(threads:usize) -> BitVec {
 
     let num_qubits = 10;
-    let symbol = 'q';
-    let mut state = state::create_state(vec![create_eleven_bit_ket()], num_qubits, symbol);
+    let symbol = "q";
+    let mut state = state::create_state(vec![create_zero_ket(11)], num_qubits, symbol);
+    state.set_threads(threads);
     let mut creg = BitVec::from_elem(10, false);
 
     state.h(0);
@@ -45,7 +42,7 @@ fn ten_qubit_counterfeit_coin_finding() -> BitVec {
     let result = state.m(9);
     creg.set(9, result);
 
-    if result == false { 
+    if !result {
         state.x(9);
         state.h(9);
         state.cx(6, 9);
@@ -60,7 +57,7 @@ fn ten_qubit_counterfeit_coin_finding() -> BitVec {
         state.h(8);
     }
 
-    if result == true { 
+    if result {
         state.h(0);
         state.h(1);
         state.h(2);
@@ -82,11 +79,12 @@ fn ten_qubit_counterfeit_coin_finding() -> BitVec {
     creg
 }
 
-fn eleven_qubit_counterfeit_coin_finding() -> BitVec {
+fn eleven_qubit_counterfeit_coin_finding(threads:usize) -> BitVec {
 
     let num_qubits = 11;
-    let symbol = 'q';
-    let mut state = state::create_state(vec![create_eleven_bit_ket()], num_qubits, symbol);
+    let symbol = "q";
+    let mut state = state::create_state(vec![create_zero_ket(11)], num_qubits, symbol);
+    state.set_threads(threads);
     let mut creg = BitVec::from_elem(11, false);
 
     state.h(0);
@@ -114,7 +112,7 @@ fn eleven_qubit_counterfeit_coin_finding() -> BitVec {
     let result = state.m(10);
     creg.set(10, result);
 
-    if result == false { 
+    if !result {
         state.x(10);
         state.h(10);
         state.cx(6, 10);
@@ -130,7 +128,7 @@ fn eleven_qubit_counterfeit_coin_finding() -> BitVec {
         state.h(9);
     }
 
-    if result == true { 
+    if result {
         state.h(0);
         state.h(1);
         state.h(2);
@@ -153,11 +151,12 @@ fn eleven_qubit_counterfeit_coin_finding() -> BitVec {
     creg
 }
 
-fn twelve_qubit_counterfeit_coin_finding() -> BitVec {
+fn twelve_qubit_counterfeit_coin_finding(threads:usize) -> BitVec {
 
     let num_qubits = 12;
-    let symbol = 'q';
-    let mut state = state::create_state(vec![create_eleven_bit_ket()], num_qubits, symbol);
+    let symbol = "q";
+    let mut state = state::create_state(vec![create_zero_ket(12)], num_qubits, symbol);
+    state.set_threads(threads);
     let mut creg = BitVec::from_elem(12, false);
 
     state.h(0);
@@ -188,7 +187,7 @@ fn twelve_qubit_counterfeit_coin_finding() -> BitVec {
     let result = state.m(11);
     creg.set(11, result);
 
-    if result == false { 
+    if !result {
         state.x(10);
         state.h(10);
         state.cx(6, 10);
@@ -204,7 +203,7 @@ fn twelve_qubit_counterfeit_coin_finding() -> BitVec {
         state.h(9);
     }
     
-    if result == true { 
+    if result {
         state.h(0);
         state.h(1);
         state.h(2);
@@ -228,24 +227,25 @@ fn twelve_qubit_counterfeit_coin_finding() -> BitVec {
 }
 
 
+/// Runs a circuit on a single thread and then on all available threads, reporting both times.
+fn compare(label:&str, circuit:fn(usize) -> BitVec, threads:usize) {
+    let serial = time(|| { circuit(1); });
+    let parallel = time(|| { circuit(threads); });
+    println!("Elapsed time for {}: {:.3} ms on 1 thread, {:.3} ms on {} threads ({:.2}x)",
+             label, serial.as_secs_f64() * 1_000.0, parallel.as_secs_f64() * 1_000.0, threads,
+             serial.as_secs_f64() / parallel.as_secs_f64());
+}
+
+fn time<F: FnOnce()>(f:F) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
 fn main() {
-    let mut start = Instant::now();
-    let mut output = ten_qubit_counterfeit_coin_finding();
-    let mut elapsed = start.elapsed();
-    println!("Elapsed time for 10 qubits: {} ms",
-             (elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64);
-
-    start = Instant::now();
-    output = eleven_qubit_counterfeit_coin_finding();
-    elapsed = start.elapsed();
-    println!("Elapsed time for 11 qubits: {} ms",
-             (elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64);
-
-    start = Instant::now();
-    output = twelve_qubit_counterfeit_coin_finding();
-    elapsed = start.elapsed();
-    println!("Elapsed time for 12 qubits: {} ms",
-             (elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64);
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
 
-    
+    compare("10 qubits", ten_qubit_counterfeit_coin_finding, threads);
+    compare("11 qubits", eleven_qubit_counterfeit_coin_finding, threads);
+    compare("12 qubits", twelve_qubit_counterfeit_coin_finding, threads);
 }
@@ -0,0 +1,155 @@
+//! # Builder
+//! A fluent interface for assembling circuits in Rust. Registers are declared by name, and each
+//! method appends an instruction and hands the builder back so that calls can be chained:
+//!
+//! ```
+//! use rustsimulationservice::create_circuit_builder;
+//!
+//! let circuit = create_circuit_builder()
+//!     .qreg("q", 2)
+//!     .creg("c", 2)
+//!     .h(("q", 0))
+//!     .cx(("q", 0), ("q", 1))
+//!     .measure_all("q", "c")
+//!     .build();
+//! ```
+
+use std::ops::Range;
+use crate::circuit;
+use crate::circuit::{Bit, Circuit};
+
+#[derive(Clone, Debug)]
+pub struct CircuitBuilder {
+    circuit: Circuit
+}
+
+/// Initializes a builder for an empty circuit with no registers.
+pub fn create_circuit_builder() -> CircuitBuilder {
+    CircuitBuilder{circuit: circuit::create_circuit()}
+}
+
+impl CircuitBuilder {
+
+    /// Declares a quantum register.
+    pub fn qreg(mut self, name:&str, size:usize) -> Self {
+        self.circuit.add_qreg(name, size);
+        self
+    }
+
+    /// Declares a classical register.
+    pub fn creg(mut self, name:&str, size:usize) -> Self {
+        self.circuit.add_creg(name, size);
+        self
+    }
+
+    /// Appends a gate by name on the given qubits, controls first.
+    pub fn gate(mut self, name:&str, qubits:Vec<Bit>, params:Vec<f64>) -> Self {
+        self.circuit.push(circuit::create_instruction(name, qubits, params));
+        self
+    }
+
+    /// Appends a Pauli X gate.
+    pub fn x<Q: Into<Bit>>(self, qubit:Q) -> Self {
+        self.gate("x", vec![qubit.into()], vec![])
+    }
+
+    /// Appends a Pauli Y gate.
+    pub fn y<Q: Into<Bit>>(self, qubit:Q) -> Self {
+        self.gate("y", vec![qubit.into()], vec![])
+    }
+
+    /// Appends a Pauli Z gate.
+    pub fn z<Q: Into<Bit>>(self, qubit:Q) -> Self {
+        self.gate("z", vec![qubit.into()], vec![])
+    }
+
+    /// Appends a Hadamard gate.
+    pub fn h<Q: Into<Bit>>(self, qubit:Q) -> Self {
+        self.gate("h", vec![qubit.into()], vec![])
+    }
+
+    /// Appends a Controlled X gate.
+    pub fn cx<C: Into<Bit>, T: Into<Bit>>(self, control:C, target:T) -> Self {
+        self.gate("cx", vec![control.into(), target.into()], vec![])
+    }
+
+    /// Appends a rotation about the X axis by the given angle.
+    pub fn rx<Q: Into<Bit>>(self, theta:f64, qubit:Q) -> Self {
+        self.gate("rx", vec![qubit.into()], vec![theta])
+    }
+
+    /// Appends a rotation about the Y axis by the given angle.
+    pub fn ry<Q: Into<Bit>>(self, theta:f64, qubit:Q) -> Self {
+        self.gate("ry", vec![qubit.into()], vec![theta])
+    }
+
+    /// Appends a rotation about the Z axis by the given angle.
+    pub fn rz<Q: Into<Bit>>(self, theta:f64, qubit:Q) -> Self {
+        self.gate("rz", vec![qubit.into()], vec![theta])
+    }
+
+    /// Appends a Hadamard gate on every qubit of a register.
+    pub fn h_all(self, register:&str) -> Self {
+        let size = self.get_qreg_size(register);
+        self.h_range(register, 0..size)
+    }
+
+    /// Appends a Hadamard gate on each of a range of qubits of a register.
+    pub fn h_range(self, register:&str, qubits:Range<usize>) -> Self {
+        qubits.fold(self, |builder, index| builder.h((register, index)))
+    }
+
+    /// Appends a Pauli X gate on every qubit of a register.
+    pub fn x_all(self, register:&str) -> Self {
+        let size = self.get_qreg_size(register);
+        (0..size).fold(self, |builder, index| builder.x((register, index)))
+    }
+
+    /// Appends a measurement of a qubit into a classical bit.
+    pub fn measure<Q: Into<Bit>, C: Into<Bit>>(mut self, qubit:Q, clbit:C) -> Self {
+        self.circuit.push(circuit::create_measurement(qubit.into(), clbit.into()));
+        self
+    }
+
+    /// Appends a measurement of each qubit of a quantum register into the bit of the same index
+    /// of a classical register.
+    pub fn measure_all(self, qreg:&str, creg:&str) -> Self {
+        let size = self.get_qreg_size(qreg);
+        (0..size).fold(self, |builder, index| builder.measure((qreg, index), (creg, index)))
+    }
+
+    /// Appends a reset of a qubit to zero.
+    pub fn reset<Q: Into<Bit>>(mut self, qubit:Q) -> Self {
+        self.circuit.push(circuit::create_reset(qubit.into()));
+        self
+    }
+
+    /// Appends a barrier across every qubit declared so far.
+    pub fn barrier(mut self) -> Self {
+        let qubits = self.circuit.get_qubits();
+        self.circuit.push(circuit::create_barrier(qubits));
+        self
+    }
+
+    /// Conditions the most recently appended instruction on a classical register equalling the
+    /// value.
+    pub fn c_if(mut self, register:&str, value:u64) -> Self {
+        match self.circuit.get_instructions_mut().last_mut() {
+            Some(instruction) => instruction.set_condition(Some(circuit::create_condition(register, value))),
+            None => panic!("attempt to condition an empty circuit")
+        }
+        self
+    }
+
+    /// Finishes the circuit, which can then be simulated with `parser::execute_circuit`.
+    pub fn build(self) -> Circuit {
+        self.circuit
+    }
+
+    fn get_qreg_size(&self, register:&str) -> usize {
+        match self.circuit.get_qregs().iter().find(|qreg| qreg.get_name() == register) {
+            Some(qreg) => qreg.get_size(),
+            None => panic!("attempt to use non-existent register {}", register)
+        }
+    }
+}
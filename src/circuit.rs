@@ -0,0 +1,440 @@
+//! # Circuit
+//! An intermediate representation of a quantum program, independent of how it was written and of
+//! how it is simulated. A circuit declares quantum and classical registers and holds a list of
+//! instructions on their bits, so that a parsed program can be inspected, transformed and executed
+//! any number of times.
+
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::gate;
+use crate::unitary::UnitaryError;
+
+/// The names of the instructions which are not gates.
+pub const MEASURE:&str = "measure";
+pub const RESET:&str = "reset";
+pub const BARRIER:&str = "barrier";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Register {
+    name: String,
+    size: usize
+}
+
+/// Initializes a register declaration.
+pub fn create_register(name:&str, size:usize) -> Register {
+    Register{name: name.to_string(), size}
+}
+
+impl Register {
+
+    /// The name the register was declared with.
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of bits in the register.
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+}
+
+/// A single quantum or classical bit, identified by its register and its index within it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Bit {
+    register: String,
+    index: usize
+}
+
+/// Initializes a reference to bit `index` of the named register.
+pub fn create_bit(register:&str, index:usize) -> Bit {
+    Bit{register: register.to_string(), index}
+}
+
+impl Bit {
+
+    /// The name of the register the bit belongs to.
+    pub fn get_register(&self) -> &str {
+        &self.register
+    }
+
+    /// The index of the bit within its register.
+    pub fn get_index(&self) -> usize {
+        self.index
+    }
+}
+
+impl From<(&str, usize)> for Bit {
+    fn from((register, index):(&str, usize)) -> Bit {
+        create_bit(register, index)
+    }
+}
+
+impl fmt::Display for Bit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}[{}]", self.register, self.index)
+    }
+}
+
+/// How a condition compares its register with its value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Comparison {
+    #[default]
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual
+}
+
+impl Comparison {
+
+    /// The operator as written in QASM, e.g. `<=`.
+    pub fn get_symbol(&self) -> &'static str {
+        match self {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterEqual => ">="
+        }
+    }
+
+    /// The comparison which holds exactly when this one does not.
+    pub fn negate(&self) -> Comparison {
+        match self {
+            Comparison::Equal => Comparison::NotEqual,
+            Comparison::NotEqual => Comparison::Equal,
+            Comparison::Less => Comparison::GreaterEqual,
+            Comparison::LessEqual => Comparison::Greater,
+            Comparison::Greater => Comparison::LessEqual,
+            Comparison::GreaterEqual => Comparison::Less
+        }
+    }
+
+    /// Whether the left value compares with the right one this way.
+    pub fn compare(&self, left:u64, right:u64) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterEqual => left >= right
+        }
+    }
+}
+
+/// A classical condition on an instruction: it only takes effect if the named classical register,
+/// read as an integer with bit `i` worth 2^i, compares with the value as the comparison says.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Condition {
+    register: String,
+    #[serde(default)]
+    comparison: Comparison,
+    value: u64
+}
+
+/// Initializes a condition that the named classical register equals the value.
+pub fn create_condition(register:&str, value:u64) -> Condition {
+    create_comparison_condition(register, Comparison::Equal, value)
+}
+
+/// Initializes a condition that the named classical register compares with the value, e.g. that
+/// it is less than 3.
+pub fn create_comparison_condition(register:&str, comparison:Comparison, value:u64) -> Condition {
+    Condition{register: register.to_string(), comparison, value}
+}
+
+impl Condition {
+
+    /// The name of the classical register compared.
+    pub fn get_register(&self) -> &str {
+        &self.register
+    }
+
+    /// How the register is compared with the value.
+    pub fn get_comparison(&self) -> Comparison {
+        self.comparison
+    }
+
+    /// The value the register is compared with.
+    pub fn get_value(&self) -> u64 {
+        self.value
+    }
+
+    /// Whether the condition holds when the register holds the given integer.
+    pub fn is_satisfied(&self, register_value:u64) -> bool {
+        self.comparison.compare(register_value, self.value)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Instruction {
+    name: String,
+    qubits: Vec<Bit>,
+    params: Vec<f64>,
+    clbits: Vec<Bit>,
+    condition: Option<Condition>
+}
+
+/// Initializes a gate instruction on the given qubits, controls first.
+pub fn create_instruction(name:&str, qubits:Vec<Bit>, params:Vec<f64>) -> Instruction {
+    Instruction{name: name.to_string(), qubits, params, clbits: vec![], condition: None}
+}
+
+/// Initializes a measurement of a qubit into a classical bit.
+pub fn create_measurement(qubit:Bit, clbit:Bit) -> Instruction {
+    Instruction{name: MEASURE.to_string(), qubits: vec![qubit], params: vec![], clbits: vec![clbit], condition: None}
+}
+
+/// Initializes a reset of a qubit to zero.
+pub fn create_reset(qubit:Bit) -> Instruction {
+    create_instruction(RESET, vec![qubit], vec![])
+}
+
+/// Initializes a barrier across the given qubits.
+pub fn create_barrier(qubits:Vec<Bit>) -> Instruction {
+    create_instruction(BARRIER, qubits, vec![])
+}
+
+impl Instruction {
+
+    /// The name of the gate, or `measure`, `reset` or `barrier`.
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// The qubits the instruction acts on, controls first.
+    pub fn get_qubits(&self) -> &[Bit] {
+        &self.qubits
+    }
+
+    /// Replaces the qubits the instruction acts on.
+    pub fn set_qubits(&mut self, qubits:Vec<Bit>) {
+        self.qubits = qubits;
+    }
+
+    /// The instruction's parameters, such as rotation angles in radians.
+    pub fn get_params(&self) -> &[f64] {
+        &self.params
+    }
+
+    /// The classical bits the instruction writes, i.e. the destination of a measurement.
+    pub fn get_clbits(&self) -> &[Bit] {
+        &self.clbits
+    }
+
+    /// The classical condition on the instruction, if any.
+    pub fn get_condition(&self) -> Option<&Condition> {
+        self.condition.as_ref()
+    }
+
+    /// Sets or clears the classical condition on the instruction.
+    pub fn set_condition(&mut self, condition:Option<Condition>) {
+        self.condition = condition;
+    }
+
+    /// Whether the instruction is a gate rather than a measurement, reset or barrier.
+    pub fn is_gate(&self) -> bool {
+        self.name != MEASURE && self.name != RESET && self.name != BARRIER
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(condition) = &self.condition {
+            write!(f, "if({}{}{}) ", condition.register, condition.comparison.get_symbol(), condition.value)?;
+        }
+        write!(f, "{}", self.name)?;
+        if !self.params.is_empty() {
+            let params:Vec<String> = self.params.iter().map(|param| param.to_string()).collect();
+            write!(f, "({})", params.join(", "))?;
+        }
+        let qubits:Vec<String> = self.qubits.iter().map(|qubit| qubit.to_string()).collect();
+        write!(f, " {}", qubits.join(", "))?;
+        if !self.clbits.is_empty() {
+            let clbits:Vec<String> = self.clbits.iter().map(|clbit| clbit.to_string()).collect();
+            write!(f, " -> {}", clbits.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Circuit {
+    qregs: Vec<Register>,
+    cregs: Vec<Register>,
+    instructions: Vec<Instruction>
+}
+
+/// Initializes an empty circuit with no registers.
+pub fn create_circuit() -> Circuit {
+    Circuit::default()
+}
+
+impl Circuit {
+
+    /// Declares a quantum register.
+    pub fn add_qreg(&mut self, name:&str, size:usize) {
+        self.qregs.push(create_register(name, size));
+    }
+
+    /// Declares a classical register.
+    pub fn add_creg(&mut self, name:&str, size:usize) {
+        self.cregs.push(create_register(name, size));
+    }
+
+    /// Appends an instruction to the end of the circuit.
+    pub fn push(&mut self, instruction:Instruction) {
+        self.instructions.push(instruction);
+    }
+
+    /// The quantum registers, in declaration order.
+    pub fn get_qregs(&self) -> &[Register] {
+        &self.qregs
+    }
+
+    /// The classical registers, in declaration order.
+    pub fn get_cregs(&self) -> &[Register] {
+        &self.cregs
+    }
+
+    /// The instructions, in program order.
+    pub fn get_instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// The instructions, in program order, for modification in place.
+    pub fn get_instructions_mut(&mut self) -> &mut [Instruction] {
+        &mut self.instructions
+    }
+
+    /// Replaces the instructions, keeping the register declarations.
+    pub fn set_instructions(&mut self, instructions:Vec<Instruction>) {
+        self.instructions = instructions;
+    }
+
+    /// The total number of qubits across every quantum register.
+    pub fn num_qubits(&self) -> usize {
+        self.qregs.iter().map(|register| register.size).sum()
+    }
+
+    /// The total number of bits across every classical register.
+    pub fn num_clbits(&self) -> usize {
+        self.cregs.iter().map(|register| register.size).sum()
+    }
+
+    /// The position of a qubit when the quantum registers are laid end to end in declaration
+    /// order, or `None` if it is not declared.
+    pub fn get_qubit_index(&self, qubit:&Bit) -> Option<usize> {
+        flat_index(&self.qregs, qubit)
+    }
+
+    /// The position of a classical bit when the classical registers are laid end to end in
+    /// declaration order, or `None` if it is not declared.
+    pub fn get_clbit_index(&self, clbit:&Bit) -> Option<usize> {
+        flat_index(&self.cregs, clbit)
+    }
+
+    /// Every qubit of the circuit, in the order of `get_qubit_index`.
+    pub fn get_qubits(&self) -> Vec<Bit> {
+        self.qregs.iter().flat_map(|register| (0..register.size).map(move |index| create_bit(&register.name, index))).collect()
+    }
+}
+
+impl Circuit {
+
+    /// The circuit which undoes this one: its gates in reverse order, each replaced by its
+    /// inverse. Fails if the circuit measures, resets, conditions on classical bits or uses a gate
+    /// whose inverse is not known.
+    pub fn inverse(&self) -> Result<Circuit, UnitaryError> {
+        let mut instructions:Vec<Instruction> = vec![];
+        for instruction in self.instructions.iter().rev() {
+            check_unitary(instruction)?;
+            if instruction.name == BARRIER {
+                instructions.push(instruction.clone());
+                continue;
+            }
+            match gate::get_inverse(&instruction.name, &instruction.params) {
+                Some((name, params)) => instructions.push(create_instruction(&name, instruction.qubits.clone(), params)),
+                None => return Err(UnitaryError::UnsupportedGate(instruction.to_string()))
+            }
+        }
+        let mut inverse = self.clone();
+        inverse.instructions = instructions;
+        Ok(inverse)
+    }
+
+    /// The circuit which applies this one only if `num_controls` additional qubits are all set.
+    /// Each gate gains the control qubits ahead of its own, so `x` becomes `cx`, `ccx` and so on.
+    /// Since the simulator only applies such gates within a register, the controlled circuit has a
+    /// single quantum register, named after this circuit's first, holding this circuit's qubits in
+    /// declaration order followed by the controls. Fails for the same instructions as `inverse`,
+    /// and for gates with no controlled form.
+    pub fn controlled(&self, num_controls:usize) -> Result<Circuit, UnitaryError> {
+        let name = self.qregs.first().map(|register| register.name.clone()).unwrap_or_else(|| "q".to_string());
+        let num_qubits = self.num_qubits();
+        let controls:Vec<Bit> = (num_qubits..num_qubits + num_controls).map(|index| create_bit(&name, index)).collect();
+
+        let mut controlled = create_circuit();
+        controlled.add_qreg(&name, num_qubits + num_controls);
+        for instruction in &self.instructions {
+            check_unitary(instruction)?;
+            let mut qubits:Vec<Bit> = vec![];
+            for qubit in &instruction.qubits {
+                match self.get_qubit_index(qubit) {
+                    Some(index) => qubits.push(create_bit(&name, index)),
+                    None => return Err(UnitaryError::QubitOutOfRange(qubit.to_string()))
+                }
+            }
+            if instruction.name == BARRIER {
+                controlled.push(create_barrier(qubits));
+                continue;
+            }
+            let gate_name = format!("{}{}", "c".repeat(num_controls), instruction.name);
+            if gate::get_signature(&gate_name) != Some((qubits.len() + num_controls, instruction.params.len())) {
+                return Err(UnitaryError::UnsupportedGate(instruction.to_string()));
+            }
+            controlled.push(create_instruction(&gate_name, controls.iter().cloned().chain(qubits).collect(), instruction.params.clone()));
+        }
+        Ok(controlled)
+    }
+}
+
+/// Fails for instructions which are not unitary.
+pub(crate) fn check_unitary(instruction:&Instruction) -> Result<(), UnitaryError> {
+    match instruction.name.as_str() {
+        MEASURE => Err(UnitaryError::NonUnitaryOperation("a measurement".to_string())),
+        RESET => Err(UnitaryError::NonUnitaryOperation("a reset".to_string())),
+        _ if instruction.condition.is_some() => Err(UnitaryError::NonUnitaryOperation("a classically conditioned operation".to_string())),
+        _ => Ok(())
+    }
+}
+
+impl fmt::Display for Circuit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for register in &self.qregs {
+            writeln!(f, "qreg {}[{}]", register.name, register.size)?;
+        }
+        for register in &self.cregs {
+            writeln!(f, "creg {}[{}]", register.name, register.size)?;
+        }
+        for instruction in &self.instructions {
+            writeln!(f, "{}", instruction)?;
+        }
+        Ok(())
+    }
+}
+
+fn flat_index(registers:&[Register], bit:&Bit) -> Option<usize> {
+    let mut offset = 0;
+    for register in registers {
+        if register.name == bit.register {
+            return if bit.index < register.size { Some(offset + bit.index) } else { None };
+        }
+        offset += register.size;
+    }
+    None
+}
@@ -1,6 +1,9 @@
 //! Data structures that represent the information contained in the complex coefficient of a ket.
 
-#[derive(Copy, Clone)]
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct Coefficient {
     magnitude: f64,
     imaginary: bool
@@ -21,10 +24,10 @@ impl Coefficient {
     /// Checks whether the coefficient is equal to another which has both real and imaginary components.
     pub fn equals_complex_coefficient(&self, other: ComplexCoefficient) -> bool {
         if self.imaginary == false {
-            (self.magnitude == other.get_real_component().get_magnitude() && other.get_imaginary_component().get_magnitude() == 0.0)
+            self.magnitude == other.get_real_component().get_magnitude() && other.get_imaginary_component().get_magnitude() == 0.0
         }
         else {
-            (self.magnitude == other.get_imaginary_component().get_magnitude() && other.get_real_component().get_magnitude() == 0.0)
+            self.magnitude == other.get_imaginary_component().get_magnitude() && other.get_real_component().get_magnitude() == 0.0
         }
     }
 
@@ -141,16 +144,22 @@ impl Coefficient {
 
     /// Prints the coefficient.
     pub fn print(&self) {
+        print!("{}", self);
+    }
+}
+
+impl fmt::Display for Coefficient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let sign:char = if self.magnitude < 0.0 { '-' } else { '+' };
-        print!("{}", sign);
+        write!(f, "{}", sign)?;
         if self.imaginary {
-            print!(" i");
+            write!(f, " i")?;
         }
-        print!(" {:.3} ", self.magnitude);
+        write!(f, " {:.3} ", self.magnitude)
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct ComplexCoefficient {
     real_component: Coefficient,
     imaginary_component: Coefficient
@@ -158,19 +167,29 @@ pub struct ComplexCoefficient {
 
 /// Initializes a complex coefficient with optional real and imaginary components.
 pub fn create_complex_coefficient(real_component:Coefficient, imaginary_component:Coefficient) -> ComplexCoefficient {
-    ComplexCoefficient{real_component: real_component, imaginary_component: imaginary_component}
+    ComplexCoefficient{real_component, imaginary_component}
+}
+
+/// Initializes a complex coefficient from the magnitudes of its real and imaginary parts.
+pub fn create_complex_coefficient_from_parts(real:f64, imaginary:f64) -> ComplexCoefficient {
+    create_complex_coefficient(create_coefficient(real, false), create_coefficient(imaginary, true))
+}
+
+/// Initializes the complex coefficient with the given modulus and argument.
+pub fn create_complex_coefficient_from_polar(modulus:f64, argument:f64) -> ComplexCoefficient {
+    create_complex_coefficient_from_parts(modulus*argument.cos(), modulus*argument.sin())
 }
 
 impl ComplexCoefficient {
 
     /// Checks whether the coefficient is equal to another which has both real and imaginary components.
     pub fn equals_complex_coefficient(&self, other: ComplexCoefficient) -> bool {
-        (self.real_component.equals_coefficient(other.get_real_component()) && self.imaginary_component.equals_coefficient(other.get_imaginary_component()))
+        self.real_component.equals_coefficient(other.get_real_component()) && self.imaginary_component.equals_coefficient(other.get_imaginary_component())
     }
 
     /// Checks whether the coefficient is equal to another which is purely real or purely imaginary.
     pub fn equals_coefficient(&self, other: Coefficient) -> bool {
-        (self.real_component.equals_coefficient(other) || self.imaginary_component.equals_coefficient(other))
+        self.real_component.equals_coefficient(other) || self.imaginary_component.equals_coefficient(other)
     }
 
     /// Multiplies the coefficient by another which is purely real or purely imaginary.
@@ -270,11 +289,24 @@ impl ComplexCoefficient {
         self.imaginary_component.negate_magnitude();
     }
 
+    /// Determines the modulus of the coefficient.
+    pub fn modulus(&self) -> f64 {
+        self.to_probability().sqrt()
+    }
+
+    /// Determines the argument (phase angle) of the coefficient.
+    pub fn argument(&self) -> f64 {
+        self.imaginary_component.get_magnitude().atan2(self.real_component.get_magnitude())
+    }
+
     /// Prints the complex coefficient.
     pub fn print(&self) {
-        print!(" + (");
-        self.real_component.print();
-        self.imaginary_component.print();
-        print!(" )");
+        print!("{}", self);
+    }
+}
+
+impl fmt::Display for ComplexCoefficient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, " + ({}{} )", self.real_component, self.imaginary_component)
     }
 }
\ No newline at end of file
@@ -0,0 +1,316 @@
+//! # Drawer
+//! Text diagrams of circuits for debugging. Each qubit and classical bit is drawn as a horizontal
+//! wire, with instructions laid out left to right in columns: gates as boxes, controls as dots,
+//! measurements as a box with an arrow down to their classical bit, and barriers as a vertical
+//! band. Diagrams wider than the requested width are wrapped into several pages.
+
+use std::collections::HashMap;
+use crate::circuit;
+use crate::circuit::{Bit, Circuit, Comparison, Instruction};
+use crate::gate;
+
+/// The width diagrams are wrapped at unless asked otherwise.
+pub const DEFAULT_WIDTH:usize = 80;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Style {
+    Ascii,
+    Unicode
+}
+
+/// What is drawn where an instruction meets a wire.
+enum Element {
+    Gate(String),
+    Control,
+    Target,
+    Measure,
+    Arrow,
+    Condition(bool),
+    Barrier
+}
+
+/// The kinds of vertical line which join the wires of an instruction.
+#[derive(Clone, Copy, PartialEq)]
+enum Connector {
+    Quantum,
+    Classical,
+    Barrier
+}
+
+#[derive(Default)]
+struct Column {
+    elements: HashMap<usize, Element>,
+    connectors: Vec<(usize, usize, Connector)>
+}
+
+struct Glyphs {
+    quantum_wire: char,
+    classical_wire: char,
+    open: &'static str,
+    close: &'static str,
+    control: &'static str,
+    target: &'static str,
+    arrow: &'static str,
+    condition: [&'static str; 2],
+    barrier: &'static str,
+    vertical: [&'static str; 2],
+    quantum_crossing: [&'static str; 2],
+    classical_crossing: [&'static str; 2]
+}
+
+const ASCII:Glyphs = Glyphs{
+    quantum_wire: '-',
+    classical_wire: '=',
+    open: "[",
+    close: "]",
+    control: "*",
+    target: "(+)",
+    arrow: "v",
+    condition: ["o", "*"],
+    barrier: "|",
+    vertical: ["|", "|"],
+    quantum_crossing: ["+", "+"],
+    classical_crossing: ["+", "+"]
+};
+
+const UNICODE:Glyphs = Glyphs{
+    quantum_wire: '─',
+    classical_wire: '═',
+    open: "┤",
+    close: "├",
+    control: "■",
+    target: "⊕",
+    arrow: "▼",
+    condition: ["□", "■"],
+    barrier: "░",
+    vertical: ["│", "║"],
+    quantum_crossing: ["┼", "╫"],
+    classical_crossing: ["╪", "╬"]
+};
+
+/// Draws a circuit, wrapping the diagram into pages no wider than `width` characters where
+/// possible. Every page repeats the wire labels.
+pub fn draw(circuit:&Circuit, style:Style, width:usize) -> String {
+    let glyphs = match style {
+        Style::Ascii => &ASCII,
+        Style::Unicode => &UNICODE
+    };
+
+    let qubits = circuit.get_qubits();
+    let clbits:Vec<Bit> = circuit.get_cregs().iter()
+        .flat_map(|register| (0..register.get_size()).map(move |index| circuit::create_bit(register.get_name(), index)))
+        .collect();
+    let mut rows:HashMap<(bool, Bit), usize> = HashMap::new();
+    for (row, qubit) in qubits.iter().enumerate() {
+        rows.insert((true, qubit.clone()), row);
+    }
+    for (row, clbit) in clbits.iter().enumerate() {
+        rows.insert((false, clbit.clone()), qubits.len() + row);
+    }
+    let labels:Vec<String> = qubits.iter().chain(clbits.iter()).map(|bit| format!("{}: ", bit)).collect();
+    if labels.is_empty() {
+        return String::new();
+    }
+    let label_width = labels.iter().map(|label| label.chars().count()).max().unwrap_or(0);
+
+    let columns = layout(circuit, &rows, qubits.len() + clbits.len());
+    let rendered:Vec<Vec<String>> = columns.iter().map(|column| render_column(column, glyphs, qubits.len(), labels.len())).collect();
+
+    let mut pages:Vec<Vec<&Vec<String>>> = vec![vec![]];
+    let mut page_width = label_width;
+    for column in &rendered {
+        let column_width = column[0].chars().count();
+        let page = pages.last_mut().unwrap();
+        if !page.is_empty() && page_width + column_width > width {
+            pages.push(vec![column]);
+            page_width = label_width + column_width;
+        }
+        else {
+            page.push(column);
+            page_width += column_width;
+        }
+    }
+
+    let mut diagram:Vec<String> = vec![];
+    for page in pages {
+        if !diagram.is_empty() {
+            diagram.push(String::new());
+        }
+        for line in 0..2*labels.len() - 1 {
+            let mut text = if line % 2 == 0 {
+                format!("{:>width$}", labels[line/2], width = label_width)
+            }
+            else {
+                " ".repeat(label_width)
+            };
+            for column in &page {
+                text.push_str(&column[line]);
+            }
+            if line % 2 == 0 {
+                text.push(if line/2 < qubits.len() { glyphs.quantum_wire } else { glyphs.classical_wire });
+            }
+            diagram.push(text.trim_end().to_string());
+        }
+    }
+    diagram.join("\n")
+}
+
+/// Assigns each instruction to the leftmost column in which none of the wires between its topmost
+/// and bottommost bits are in use.
+fn layout(circuit:&Circuit, rows:&HashMap<(bool, Bit), usize>, num_rows:usize) -> Vec<Column> {
+    let mut columns:Vec<Column> = vec![];
+    let mut levels = vec![0; num_rows];
+
+    for instruction in circuit.get_instructions() {
+        let elements = get_elements(circuit, instruction, rows);
+        if elements.is_empty() {
+            continue;
+        }
+        let top = elements.iter().map(|(row, _)| *row).min().unwrap();
+        let bottom = elements.iter().map(|(row, _)| *row).max().unwrap();
+        let level = levels[top..=bottom].iter().cloned().max().unwrap();
+        for row_level in &mut levels[top..=bottom] {
+            *row_level = level + 1;
+        }
+        if columns.len() <= level {
+            columns.push(Column::default());
+        }
+
+        let connector = if instruction.get_name() == circuit::BARRIER {
+            Connector::Barrier
+        }
+        else if instruction.get_name() == circuit::MEASURE || instruction.get_condition().is_some() {
+            Connector::Classical
+        }
+        else {
+            Connector::Quantum
+        };
+        let column = &mut columns[level];
+        if top != bottom {
+            column.connectors.push((top, bottom, connector));
+        }
+        for (row, element) in elements {
+            column.elements.insert(row, element);
+        }
+    }
+    columns
+}
+
+/// The element the instruction draws on each of the wires it involves.
+fn get_elements(circuit:&Circuit, instruction:&Instruction, rows:&HashMap<(bool, Bit), usize>) -> Vec<(usize, Element)> {
+    let qubit_rows:Vec<usize> = instruction.get_qubits().iter().filter_map(|qubit| rows.get(&(true, qubit.clone())).cloned()).collect();
+    let mut elements:Vec<(usize, Element)> = match instruction.get_name() {
+        circuit::BARRIER => qubit_rows.into_iter().map(|row| (row, Element::Barrier)).collect(),
+        circuit::MEASURE => {
+            let mut elements:Vec<(usize, Element)> = qubit_rows.into_iter().map(|row| (row, Element::Measure)).collect();
+            for clbit in instruction.get_clbits() {
+                if let Some(row) = rows.get(&(false, clbit.clone())) {
+                    elements.push((*row, Element::Arrow));
+                }
+            }
+            elements
+        },
+        name => {
+            // Controls are drawn as dots and a controlled x as a target, e.g. `ccx` as two dots and
+            // a target, and whatever else they control as a box.
+            let (num_controls, base) = match name {
+                "cswap" => (1, "swap"),
+                _ => gate::split_controls(name).unwrap_or((0, name))
+            };
+            let num_controls = if num_controls < qubit_rows.len() { num_controls } else { 0 };
+            let base = if num_controls == 0 { name } else { base };
+            let mut label = base.to_string();
+            if !instruction.get_params().is_empty() {
+                let params:Vec<String> = instruction.get_params().iter().map(|param| format_param(*param)).collect();
+                label = format!("{}({})", label, params.join(","));
+            }
+            qubit_rows.into_iter().enumerate().map(|(index, row)| {
+                let element = if index < num_controls {
+                    Element::Control
+                }
+                else if base == "x" && num_controls > 0 {
+                    Element::Target
+                }
+                else {
+                    Element::Gate(label.clone())
+                };
+                (row, element)
+            }).collect()
+        }
+    };
+    if let Some(condition) = instruction.get_condition() {
+        if let Some(register) = circuit.get_cregs().iter().find(|register| register.get_name() == condition.get_register()) {
+            // Other comparisons are labelled on the register's first bit, e.g. `[<3]`.
+            if condition.get_comparison() != Comparison::Equal {
+                if let Some(row) = rows.get(&(false, circuit::create_bit(register.get_name(), 0))) {
+                    let label = format!("{}{}", condition.get_comparison().get_symbol(), condition.get_value());
+                    elements.push((*row, Element::Gate(label)));
+                }
+                return elements;
+            }
+            for index in 0..register.get_size() {
+                if let Some(row) = rows.get(&(false, circuit::create_bit(register.get_name(), index))) {
+                    let set = index < 64 && (condition.get_value() >> index) & 1 == 1;
+                    elements.push((*row, Element::Condition(set)));
+                }
+            }
+        }
+    }
+    elements
+}
+
+/// Draws a column as one string per text line, alternating wire lines and the gaps between them.
+fn render_column(column:&Column, glyphs:&Glyphs, num_qubits:usize, num_rows:usize) -> Vec<String> {
+    let texts:HashMap<usize, String> = column.elements.iter().map(|(row, element)| {
+        let text = match element {
+            Element::Gate(label) => format!("{}{}{}", glyphs.open, label, glyphs.close),
+            Element::Control => glyphs.control.to_string(),
+            Element::Target => glyphs.target.to_string(),
+            Element::Measure => format!("{}M{}", glyphs.open, glyphs.close),
+            Element::Arrow => glyphs.arrow.to_string(),
+            Element::Condition(set) => glyphs.condition[*set as usize].to_string(),
+            Element::Barrier => glyphs.barrier.to_string()
+        };
+        (*row, text)
+    }).collect();
+    let width = texts.values().map(|text| text.chars().count()).max().unwrap_or(1);
+
+    let mut lines:Vec<String> = vec![];
+    for line in 0..2*num_rows - 1 {
+        let row = line/2;
+        let on_wire = line % 2 == 0;
+        let fill = if !on_wire { ' ' } else if row < num_qubits { glyphs.quantum_wire } else { glyphs.classical_wire };
+        let crossing = column.connectors.iter().find(|(top, bottom, _)| {
+            if on_wire { *top < row && row < *bottom } else { *top <= row && row < *bottom }
+        }).map(|(_, _, connector)| *connector);
+
+        let content = match (texts.get(&row), crossing) {
+            (Some(text), _) if on_wire => text.clone(),
+            (_, Some(Connector::Barrier)) => glyphs.barrier.to_string(),
+            (_, Some(connector)) => {
+                let double = (connector == Connector::Classical) as usize;
+                if !on_wire {
+                    glyphs.vertical[double].to_string()
+                }
+                else if row < num_qubits {
+                    glyphs.quantum_crossing[double].to_string()
+                }
+                else {
+                    glyphs.classical_crossing[double].to_string()
+                }
+            },
+            _ => String::new()
+        };
+        let padding = width - content.chars().count();
+        let left = padding/2;
+        let right = padding - left;
+        lines.push(format!("{}{}{}{}{}", fill, fill.to_string().repeat(left), content, fill.to_string().repeat(right), fill));
+    }
+    lines
+}
+
+fn format_param(param:f64) -> String {
+    let rounded = format!("{:.3}", param);
+    let trimmed = rounded.trim_end_matches('0').trim_end_matches('.');
+    if trimmed == "-0" { "0".to_string() } else { trimmed.to_string() }
+}
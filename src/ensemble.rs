@@ -1,111 +1,161 @@
 //! A data structure that represents a set of quantum systems and facilitates communication between them.
 
 use std::collections::BTreeMap;
-use std::mem::replace;
-use crate::state::State;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use crate::parallel;
+use crate::state::{PostselectionError, State};
+use crate::tracer;
+use crate::tracer::Tracer;
+use crate::truncation::Truncation;
 use crate::ket::Ket;
 use crate::ket;
 
+#[derive(Serialize, Deserialize)]
 pub struct Ensemble {
-    pub subsystems: BTreeMap<char, State>
+    pub subsystems: BTreeMap<String, State>
 }
 
 /// Initializes an ensemble of quantum systems.
-pub fn create_ensemble(subsystems:BTreeMap<char, State>) -> Ensemble {
-    Ensemble{subsystems: subsystems}
+pub fn create_ensemble(subsystems:BTreeMap<String, State>) -> Ensemble {
+    Ensemble{subsystems}
 }
 
 impl Ensemble {
 
     /// Adds a subsystem to the ensemble.
-    pub fn add_subsystem(&mut self, state:State, name:char) {
-        self.subsystems.insert(name, state);
+    pub fn add_subsystem(&mut self, state:State, name:&str) {
+        self.subsystems.insert(name.to_string(), state);
     }
 
     /// Measures a qubit and collapses quantum state across subsystems accordingly.
-    pub fn m(&mut self, target_system:char, target_qubit:usize) -> bool {
-        let system = match self.subsystems.get(&target_system) {
+    pub fn m(&mut self, target_system:&str, target_qubit:usize) -> bool {
+        let system = match self.subsystems.get_mut(target_system) {
             Some(system) => system,
             None => panic!("attempt to measure non-existent system")
         };
 
-        let outcome = system.clone().m(target_qubit);
+        let outcome = system.m(target_qubit);
+        self.collapse_entangled(target_system, target_qubit, outcome);
+        outcome
+    }
 
-        let mut to_remove:Vec<Ket> = vec![];
-        
-        for (name, subsystem) in &mut self.subsystems {
-            to_remove = vec![];
-            for entangled_ket in &mut subsystem.kets {
-                if entangled_ket.is_entangled() && entangled_ket.is_entangled_with(target_system, target_qubit) {
-                    let collapse = entangled_ket.should_collapse(outcome, target_system, target_qubit);
-                    if collapse {
-                        to_remove.push(entangled_ket.clone());
-                    }
-                }
+    /// Projects a qubit onto the given outcome, renormalizing its system, and collapses
+    /// entangled kets in other subsystems as a measurement with that outcome would. Returns the
+    /// probability of the outcome, or fails if it is impossible.
+    pub fn postselect(&mut self, target_system:&str, target_qubit:usize, outcome:bool) -> Result<f64, PostselectionError> {
+        let system = match self.subsystems.get_mut(target_system) {
+            Some(system) => system,
+            None => return Err(PostselectionError::NonExistentSystem{system: target_system.to_string()})
+        };
+
+        let probability = system.postselect(target_qubit, outcome)?;
+        for name in self.collapse_entangled(target_system, target_qubit, outcome) {
+            if let Some(subsystem) = self.subsystems.get_mut(&name) {
+                subsystem.normalize();
             }
-            let mut new_subsystem = subsystem.clone();
-            for ket in to_remove {
-                new_subsystem.remove_ket(ket);
+        }
+        Ok(probability)
+    }
+
+    /// Removes the kets in every subsystem whose existence depends on the target qubit having
+    /// a different outcome, returning the names of the subsystems which lost kets.
+    fn collapse_entangled(&mut self, target_system:&str, target_qubit:usize, outcome:bool) -> Vec<String> {
+        let mut collapsed:Vec<String> = vec![];
+
+        for (name, subsystem) in &mut self.subsystems {
+            let num_kets = subsystem.kets.len();
+            subsystem.kets.retain_mut(|entangled_ket| {
+                !(entangled_ket.is_entangled()
+                    && entangled_ket.is_entangled_with(target_system, target_qubit)
+                    && entangled_ket.should_collapse(outcome, target_system, target_qubit))
+            });
+            if subsystem.kets.len() != num_kets {
+                collapsed.push(name.clone());
             }
-            replace(subsystem, new_subsystem);
         }
-        outcome
+        collapsed
+    }
+
+    /// Sets the tracer on every subsystem currently in the ensemble.
+    pub fn set_tracer(&mut self, tracer:Arc<dyn Tracer>) {
+        for subsystem in self.subsystems.values_mut() {
+            subsystem.set_tracer(tracer.clone());
+        }
+    }
+
+    /// Spreads the work of every subsystem currently in the ensemble across a shared pool
+    /// of the given number of threads.
+    pub fn set_threads(&mut self, threads:usize) {
+        let parallelism = parallel::create_parallelism(threads);
+        for subsystem in self.subsystems.values_mut() {
+            subsystem.set_parallelism(parallelism.clone());
+        }
+    }
+
+    /// Sets the truncation policy on every subsystem currently in the ensemble.
+    pub fn set_truncation(&mut self, truncation:Option<Truncation>) {
+        for subsystem in self.subsystems.values_mut() {
+            subsystem.set_truncation(truncation);
+        }
+    }
+
+    /// A lower bound on the fidelity of the ensemble with the one exact simulation would have
+    /// produced: the product of the fidelity bounds of its subsystems.
+    pub fn get_fidelity(&self) -> f64 {
+        self.subsystems.values().map(|subsystem| subsystem.get_fidelity()).product()
     }
 
     /// Applies a Controlled X gate within or between subsystems.
-    pub fn cx(&mut self, source_system:char, source_qubit:usize, target_system:char, target_qubit:usize) {
-        let mut source = match self.subsystems.get(&source_system) {
+    pub fn cx(&mut self, source_system:&str, source_qubit:usize, target_system:&str, target_qubit:usize) {
+        let source = match self.subsystems.get(source_system) {
             Some(source) => source,
             None => panic!("attempt to control from non-existent system")
         };
 
-        let mut new_source = source.clone();
-
-        let mut target = match self.subsystems.get(&target_system) {
+        let target = match self.subsystems.get(target_system) {
             Some(target) => target,
             None => panic!("attempt to control to non-existent system")
         };
 
-        let mut new_target = target.clone();
-
         if source_system == target_system {
+            let mut new_source = source.clone();
             new_source.cx(source_qubit, target_qubit);
+            self.subsystems.insert(source_system.to_string(), new_source);
         }
         else {
             let [alpha_source, beta_source] = source.get_components(source_qubit);
 
-            print!("q: ");
-            source.print();
+            let mut new_target = target.clone();
+            let tracer = new_target.get_tracer();
+            let before = if tracer.wants_snapshots() { Some(new_target.kets.clone()) } else { None };
 
             let mut new_kets:Vec<Ket>  = vec![];
 
             for ket in &mut new_target.kets {
-                print!("cx ({}[{}] -> {}[{}])", source_system, source_qubit, target_system, target_qubit);
-                ket.print();
-                print!(" =");
-
                 let new_coeff = ket.get_coefficient();
-                let new_val = ket.get_val();
+                let new_val = ket.get_val().clone();
                 let mut new_ket = ket::create_ket(new_coeff, new_val, vec![]);
                 new_ket.x(target_qubit);
 
-                new_ket.set_coefficient(new_ket.get_coefficient().multiply_by_complex_coefficient(alpha_source));
-                ket.set_coefficient(ket.get_coefficient().multiply_by_complex_coefficient(beta_source));
+                new_ket.set_coefficient(new_ket.get_coefficient().multiply_by_complex_coefficient(beta_source));
+                ket.set_coefficient(ket.get_coefficient().multiply_by_complex_coefficient(alpha_source));
 
-                new_ket.entangle(false, source_system, source_qubit);
+                new_ket.entangle(true, source_system, source_qubit);
                 new_kets.push(new_ket.clone());
 
-                ket.entangle(true, source_system, source_qubit);
-                ket.print();
-                println!();
+                ket.entangle(false, source_system, source_qubit);
             }
 
             for ket in new_kets {
                 new_target.add_ket(ket);
-                new_target.print();
             }
-            replace(&mut target, &new_target);
-            replace(&mut source, &new_source);
+
+            let after = if tracer.wants_snapshots() { Some(new_target.kets.clone()) } else { None };
+            let qubits = vec![(source_system.to_string(), source_qubit), (target_system.to_string(), target_qubit)];
+            tracer.on_operation(&tracer::create_trace_event("cx", qubits, vec![], before, after));
+
+            self.subsystems.insert(target_system.to_string(), new_target);
         }
     }
 }
@@ -0,0 +1,192 @@
+//! # Exporter
+//! Writing of circuits, and of the states of registers, as OpenQASM 2.0 programs which other tools
+//! can read and which the service accepts back. Gates are written by their `qelib1.inc` names, and
+//! any gate the library does not define is first decomposed with the transpiler. A condition
+//! other than equality is written as one `if(c==v)` statement for each value which satisfies it.
+//! States are written as the circuit which prepares them from |0...0>.
+//!
+//! Note that the `qasm` crate reads numbers in single precision, so angles read back from an
+//! exported program may differ from the originals in their eighth significant figure.
+
+use std::error::Error;
+use std::fmt;
+use crate::circuit;
+use crate::circuit::{Circuit, Comparison, Condition, Instruction};
+use crate::coefficient;
+use crate::preparation;
+use crate::qasm3::MAX_CONDITION_BITS;
+use crate::state::{AmplitudeError, State};
+use crate::transpiler;
+
+/// The gates defined by `qelib1.inc` which have the same meaning in the simulator. The library's
+/// `cu` takes a fourth, phase parameter, so the simulator's `cu` is decomposed instead.
+pub const QELIB1_GATES:[&str; 35] = [
+    "u3", "u2", "u1", "cx", "id", "u", "p", "x", "y", "z", "h", "s", "sdg", "t", "tdg", "rx", "ry", "rz",
+    "sx", "sxdg", "cz", "cy", "swap", "ch", "ccx", "cswap", "crx", "cry", "crz", "cu1", "cp", "cu3", "csx",
+    "rxx", "rzz"
+];
+
+/// The widest state which will be exported. Preparing an n qubit state takes roughly 2^(n+1) CNOTs.
+pub const MAX_STATE_QUBITS:usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum ExportError {
+    /// The circuit uses a gate which neither `qelib1.inc` defines nor the transpiler can decompose.
+    UnsupportedGate(String),
+    /// A register name is not a valid OpenQASM 2.0 identifier, which must start with a lower case
+    /// letter.
+    InvalidIdentifier(String),
+    /// The state is entangled with another register, so it has no state of its own to prepare.
+    EntangledState,
+    /// The state has more qubits than `MAX_STATE_QUBITS`.
+    TooManyQubits(usize),
+    /// The state's amplitudes cannot be prepared, because it has no kets.
+    InvalidState(AmplitudeError),
+    /// A condition other than equality reads a register wider than `MAX_CONDITION_BITS`, so it
+    /// cannot be written as equalities.
+    UnsupportedCondition(String)
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportError::UnsupportedGate(gate) => write!(f, "cannot export gate {}", gate),
+            ExportError::InvalidIdentifier(name) => write!(f, "{} is not a valid OpenQASM 2.0 identifier", name),
+            ExportError::EntangledState => write!(f, "cannot export a state entangled with another register"),
+            ExportError::TooManyQubits(num_qubits) => write!(f, "state has {} qubits, more than the {} a state can be exported with", num_qubits, MAX_STATE_QUBITS),
+            ExportError::InvalidState(error) => write!(f, "cannot prepare state: {}", error),
+            ExportError::UnsupportedCondition(instruction) => write!(f, "cannot export the condition of {}", instruction)
+        }
+    }
+}
+
+impl Error for ExportError {}
+
+/// Writes a circuit as an OpenQASM 2.0 program.
+pub fn export_qasm(circuit:&Circuit) -> Result<String, ExportError> {
+    for register in circuit.get_qregs().iter().chain(circuit.get_cregs()) {
+        if !is_identifier(register.get_name()) {
+            return Err(ExportError::InvalidIdentifier(register.get_name().to_string()));
+        }
+    }
+    let circuit = match transpiler::transpile(circuit, &QELIB1_GATES) {
+        Ok(circuit) => circuit,
+        Err(transpiler::TranspileError::UnsupportedGate(gate)) => return Err(ExportError::UnsupportedGate(gate)),
+        Err(e) => return Err(ExportError::UnsupportedGate(e.to_string()))
+    };
+
+    let mut lines:Vec<String> = vec!["OPENQASM 2.0;".to_string(), "include \"qelib1.inc\";".to_string()];
+    for register in circuit.get_qregs() {
+        lines.push(format!("qreg {}[{}];", register.get_name(), register.get_size()));
+    }
+    for register in circuit.get_cregs() {
+        lines.push(format!("creg {}[{}];", register.get_name(), register.get_size()));
+    }
+    for instruction in circuit.get_instructions() {
+        let statements = export_instruction(&circuit, instruction);
+        let prefixes = match instruction.get_condition() {
+            Some(condition) => export_condition(&circuit, condition).ok_or_else(|| ExportError::UnsupportedCondition(instruction.to_string()))?,
+            None => vec![String::new()]
+        };
+        for prefix in &prefixes {
+            for statement in &statements {
+                lines.push(format!("{}{};", prefix, statement));
+            }
+        }
+    }
+    let mut program = lines.join("\n");
+    program.push('\n');
+    Ok(program)
+}
+
+/// The `if` prefixes an instruction is written with, one for each value of the register which
+/// satisfies its condition, or `None` if the register is too wide to list them.
+fn export_condition(circuit:&Circuit, condition:&Condition) -> Option<Vec<String>> {
+    let prefix = |value:u64| format!("if({}=={}) ", condition.get_register(), value);
+    if condition.get_comparison() == Comparison::Equal {
+        return Some(vec![prefix(condition.get_value())]);
+    }
+    let size = circuit.get_cregs().iter().find(|register| register.get_name() == condition.get_register())?.get_size();
+    if size > MAX_CONDITION_BITS {
+        return None;
+    }
+    Some((0..1u64 << size).filter(|value| condition.is_satisfied(*value)).map(prefix).collect())
+}
+
+/// Builds the circuit which prepares a register's state from |0...0>, up to a global phase, on a
+/// register named after the state's symbol.
+pub fn state_to_circuit(state:&State) -> Result<Circuit, ExportError> {
+    if state.num_qubits > MAX_STATE_QUBITS {
+        return Err(ExportError::TooManyQubits(state.num_qubits));
+    }
+    if state.kets.iter().any(|ket| ket.is_entangled()) {
+        return Err(ExportError::EntangledState);
+    }
+    let mut amplitudes = vec![coefficient::create_complex_coefficient_from_parts(0.0, 0.0); 1 << state.num_qubits];
+    for ket in &state.kets {
+        let index = ket.get_val().to_int().unwrap_or(0) as usize;
+        amplitudes[index] = amplitudes[index].add_to_complex_coefficient(ket.get_coefficient());
+    }
+
+    let name = state.symbol.clone();
+    let mut circuit = circuit::create_circuit();
+    circuit.add_qreg(&name, state.num_qubits);
+    for gate in preparation::prepare(&amplitudes).map_err(ExportError::InvalidState)? {
+        let qubits = gate.get_qubits().iter().map(|qubit| circuit::create_bit(&name, *qubit)).collect();
+        circuit.push(circuit::create_instruction(gate.get_name(), qubits, gate.get_params().to_vec()));
+    }
+    Ok(circuit)
+}
+
+/// Writes the circuit which prepares a register's state as an OpenQASM 2.0 program.
+pub fn export_state_qasm(state:&State) -> Result<String, ExportError> {
+    export_qasm(&state_to_circuit(state)?)
+}
+
+/// The statements of an instruction, without its condition or terminating semicolon. The `qasm`
+/// crate reads a single argument per barrier, so a barrier is written as one statement for each
+/// whole register it covers and one for each other qubit.
+fn export_instruction(circuit:&Circuit, instruction:&Instruction) -> Vec<String> {
+    let qubits:Vec<String> = instruction.get_qubits().iter().map(|qubit| qubit.to_string()).collect();
+    match instruction.get_name() {
+        circuit::MEASURE => vec![format!("measure {} -> {}", qubits[0], instruction.get_clbits()[0])],
+        circuit::RESET => vec![format!("reset {}", qubits[0])],
+        circuit::BARRIER => {
+            let mut statements:Vec<String> = vec![];
+            for register in circuit.get_qregs() {
+                let covered:Vec<&String> = instruction.get_qubits().iter().zip(qubits.iter())
+                    .filter(|(qubit, _)| qubit.get_register() == register.get_name())
+                    .map(|(_, qubit)| qubit)
+                    .collect();
+                if covered.len() == register.get_size() && register.get_size() > 0 {
+                    statements.push(format!("barrier {}", register.get_name()));
+                }
+                else {
+                    statements.extend(covered.into_iter().map(|qubit| format!("barrier {}", qubit)));
+                }
+            }
+            statements
+        },
+        name => {
+            if instruction.get_params().is_empty() {
+                vec![format!("{} {}", name, qubits.join(","))]
+            }
+            else {
+                let params:Vec<String> = instruction.get_params().iter().map(|param| format_number(*param)).collect();
+                vec![format!("{}({}) {}", name, params.join(","), qubits.join(","))]
+            }
+        }
+    }
+}
+
+/// Formats a number with a decimal point and no exponent, which the `qasm` crate's lexer requires
+/// of real numbers.
+fn format_number(number:f64) -> String {
+    let text = number.to_string();
+    if text.contains('.') { text } else { format!("{}.0", text) }
+}
+
+fn is_identifier(name:&str) -> bool {
+    let mut chars = name.chars();
+    chars.next().map(|first| first.is_ascii_lowercase()).unwrap_or(false) && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
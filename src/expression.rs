@@ -0,0 +1,241 @@
+//! # Expression
+//! Evaluation of the parameter expressions of OpenQASM 2.0 gates, such as `-pi/2` or
+//! `sin(theta)^2`, into angles. Expressions may use numbers, `pi`, the parameters of an enclosing
+//! gate definition, the operators `+`, `-`, `*`, `/` and `^`, and the functions in `FUNCTIONS`.
+//! The OpenQASM 3 frontend applies the same functions and finiteness check to its own expressions.
+//!
+//! The `qasm` crate hands over expressions as text with every token separated by spaces. It stops
+//! reading an expression at a `^`, so programs cannot raise to powers, and writes `exp` as `^`, so a
+//! `^` which starts an operand is read as `exp`.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fmt;
+
+/// How deeply an expression may nest parentheses, function calls and operators, which bounds the
+/// recursion reading it. Shared by the OpenQASM 2 and 3 frontends. OpenQASM 3 also counts each
+/// operator of a chain such as `a + b + c`, since its expressions are evaluated as trees.
+pub const MAX_DEPTH:usize = 64;
+
+/// The functions an expression may apply. `log` is the natural logarithm, like `ln`.
+pub const FUNCTIONS:[&str; 10] = ["sin", "cos", "tan", "arcsin", "arccos", "arctan", "exp", "ln", "log", "sqrt"];
+
+#[derive(Debug, PartialEq)]
+pub enum ExpressionError {
+    /// A character which cannot start a token.
+    UnexpectedCharacter(char),
+    /// A token where it cannot be, such as a second operator or an unmatched parenthesis.
+    UnexpectedToken(String),
+    /// The expression ends before an operand or a closing parenthesis.
+    UnexpectedEnd,
+    /// An identifier which is neither a function nor a bound parameter.
+    UndefinedIdentifier(String),
+    /// The expression evaluates to an infinity or NaN, e.g. by dividing by zero.
+    NotFinite(f64),
+    /// The expression is nested more than `MAX_DEPTH` deep.
+    TooDeep
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExpressionError::UnexpectedCharacter(c) => write!(f, "unexpected character {}", c),
+            ExpressionError::UnexpectedToken(token) => write!(f, "unexpected {}", token),
+            ExpressionError::UnexpectedEnd => write!(f, "unexpected end of expression"),
+            ExpressionError::UndefinedIdentifier(identifier) => write!(f, "undefined identifier {}", identifier),
+            ExpressionError::NotFinite(value) => write!(f, "expression evaluates to {}", value),
+            ExpressionError::TooDeep => write!(f, "expression is nested more than {} deep", MAX_DEPTH)
+        }
+    }
+}
+
+impl Error for ExpressionError {}
+
+/// Evaluates an expression, looking identifiers up in the bindings of the parameters of the
+/// enclosing gate definition.
+pub fn evaluate(expression:&str, bindings:&HashMap<&str, f64>) -> Result<f64, ExpressionError> {
+    let tokens = tokenize(expression)?;
+    let mut evaluator = Evaluator{tokens: &tokens, position: 0, depth: 0, bindings};
+    let value = evaluator.sum()?;
+    if let Some(token) = tokens.get(evaluator.position) {
+        return Err(ExpressionError::UnexpectedToken(token.clone()));
+    }
+    check_finite(value)
+}
+
+/// Applies one of `FUNCTIONS` to its argument, or returns `None` for any other name.
+pub fn apply_function(function:&str, argument:f64) -> Option<f64> {
+    Some(match function {
+        "sin" => argument.sin(),
+        "cos" => argument.cos(),
+        "tan" => argument.tan(),
+        "arcsin" => argument.asin(),
+        "arccos" => argument.acos(),
+        "arctan" => argument.atan(),
+        "exp" => argument.exp(),
+        "ln" | "log" => argument.ln(),
+        "sqrt" => argument.sqrt(),
+        _ => return None
+    })
+}
+
+/// Checks that a value is usable as an angle, rejecting infinities and NaN.
+pub fn check_finite(value:f64) -> Result<f64, ExpressionError> {
+    if !value.is_finite() {
+        return Err(ExpressionError::NotFinite(value));
+    }
+    Ok(value)
+}
+
+fn tokenize(expression:&str) -> Result<Vec<String>, ExpressionError> {
+    let chars:Vec<char> = expression.chars().collect();
+    let mut tokens:Vec<String> = vec![];
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        let length = if c.is_whitespace() {
+            index += 1;
+            continue;
+        }
+        else if c.is_ascii_digit() || c == '.' {
+            let mut length = 1;
+            while let Some(next) = chars.get(index + length) {
+                let exponent = (*next == 'e' || *next == 'E') && chars.get(index + length + 1).map(|c| c.is_ascii_digit() || *c == '+' || *c == '-').unwrap_or(false);
+                if exponent {
+                    length += 2;
+                }
+                else if next.is_ascii_digit() || *next == '.' {
+                    length += 1;
+                }
+                else {
+                    break;
+                }
+            }
+            length
+        }
+        else if c.is_alphabetic() || c == '_' {
+            chars[index..].iter().position(|c| !(c.is_alphanumeric() || *c == '_')).unwrap_or(chars.len() - index)
+        }
+        else if "+-*/^()".contains(c) {
+            1
+        }
+        else {
+            return Err(ExpressionError::UnexpectedCharacter(c));
+        };
+        tokens.push(chars[index..index + length].iter().collect());
+        index += length;
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent over the tokens, in which `^` binds most tightly and to the right, then
+/// unary minus, then `*` and `/`, then `+` and `-`. Every nested operand is read by `unary`, which
+/// counts how deep it is.
+struct Evaluator<'a> {
+    tokens: &'a [String],
+    position: usize,
+    depth: usize,
+    bindings: &'a HashMap<&'a str, f64>
+}
+
+impl<'a> Evaluator<'a> {
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.position).map(|token| token.as_str())
+    }
+
+    fn next(&mut self) -> Result<&'a str, ExpressionError> {
+        let token = self.peek().ok_or(ExpressionError::UnexpectedEnd)?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn sum(&mut self) -> Result<f64, ExpressionError> {
+        let mut value = self.product()?;
+        while let Some(operator) = self.peek().filter(|token| *token == "+" || *token == "-") {
+            self.position += 1;
+            let operand = self.product()?;
+            value = if operator == "+" { value + operand } else { value - operand };
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<f64, ExpressionError> {
+        let mut value = self.unary()?;
+        while let Some(operator) = self.peek().filter(|token| *token == "*" || *token == "/") {
+            self.position += 1;
+            let operand = self.unary()?;
+            value = if operator == "*" { value*operand } else { value/operand };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<f64, ExpressionError> {
+        if self.depth == MAX_DEPTH {
+            return Err(ExpressionError::TooDeep);
+        }
+        self.depth += 1;
+        let value = self.signed();
+        self.depth -= 1;
+        value
+    }
+
+    fn signed(&mut self) -> Result<f64, ExpressionError> {
+        match self.peek() {
+            Some("-") => {
+                self.position += 1;
+                Ok(-self.unary()?)
+            },
+            Some("+") => {
+                self.position += 1;
+                self.unary()
+            },
+            _ => self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<f64, ExpressionError> {
+        let base = self.primary()?;
+        if self.peek() == Some("^") {
+            self.position += 1;
+            return Ok(base.powf(self.unary()?));
+        }
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<f64, ExpressionError> {
+        let token = self.next()?;
+        match token {
+            "(" => self.parenthesized(),
+            "pi" => Ok(PI),
+            // The qasm crate writes `exp` as `^`.
+            "^" if self.peek() == Some("(") => self.call("exp"),
+            _ if FUNCTIONS.contains(&token) => self.call(token),
+            _ if token.starts_with(|c:char| c.is_ascii_digit() || c == '.') => {
+                token.parse::<f64>().map_err(|_| ExpressionError::UnexpectedToken(token.to_string()))
+            },
+            _ if token.starts_with(|c:char| c.is_alphabetic() || c == '_') => {
+                self.bindings.get(token).cloned().ok_or_else(|| ExpressionError::UndefinedIdentifier(token.to_string()))
+            },
+            _ => Err(ExpressionError::UnexpectedToken(token.to_string()))
+        }
+    }
+
+    fn parenthesized(&mut self) -> Result<f64, ExpressionError> {
+        let value = self.sum()?;
+        match self.next()? {
+            ")" => Ok(value),
+            token => Err(ExpressionError::UnexpectedToken(token.to_string()))
+        }
+    }
+
+    fn call(&mut self, function:&str) -> Result<f64, ExpressionError> {
+        match self.next()? {
+            "(" => {},
+            token => return Err(ExpressionError::UnexpectedToken(token.to_string()))
+        }
+        let argument = self.parenthesized()?;
+        apply_function(function, argument).ok_or_else(|| ExpressionError::UndefinedIdentifier(function.to_string()))
+    }
+}
@@ -0,0 +1,149 @@
+//! # Gate
+//! A gate applied to qubits of a single state, identified by name with any rotation angles as
+//! parameters. Sequences of gates are how synthesized routines such as state preparation are
+//! handed back to callers.
+
+use std::f64::consts::{FRAC_1_SQRT_2, PI};
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::coefficient;
+use crate::coefficient::ComplexCoefficient;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Gate {
+    name: String,
+    qubits: Vec<usize>,
+    params: Vec<f64>
+}
+
+/// Initializes a gate acting on the given qubits, controls first.
+pub fn create_gate(name:&str, qubits:Vec<usize>, params:Vec<f64>) -> Gate {
+    Gate{name: name.to_string(), qubits, params}
+}
+
+/// The number of qubits and parameters of each gate the simulator implements, or `None` for
+/// gates it does not. Each leading `c` adds a control qubit to a single qubit gate, so `cx`,
+/// `ccx` and `crz` are all implemented, as is `swap`.
+pub fn get_signature(name:&str) -> Option<(usize, usize)> {
+    if name == "swap" {
+        return Some((2, 0));
+    }
+    let (controls, base) = split_controls(name)?;
+    Some((controls + 1, get_num_params(base)?))
+}
+
+/// Splits a gate name into its number of controls and the single qubit gate they control, e.g.
+/// `ccx` into `(2, "x")`, or `None` if the gate is not one the simulator implements.
+pub fn split_controls(name:&str) -> Option<(usize, &str)> {
+    let base = name.trim_start_matches('c');
+    get_num_params(base).map(|_| (name.len() - base.len(), base))
+}
+
+/// The number of parameters of each single qubit gate the simulator implements.
+fn get_num_params(name:&str) -> Option<usize> {
+    match name {
+        "id" | "x" | "y" | "z" | "h" | "s" | "sdg" | "t" | "tdg" | "sx" | "sxdg" => Some(0),
+        "rx" | "ry" | "rz" | "p" | "u1" => Some(1),
+        "u2" => Some(2),
+        "u3" | "u" => Some(3),
+        _ => None
+    }
+}
+
+/// The name and parameters of the inverse of a gate, or `None` if it is not known.
+pub fn get_inverse(name:&str, params:&[f64]) -> Option<(String, Vec<f64>)> {
+    if name == "swap" {
+        return Some((name.to_string(), vec![]));
+    }
+    let (controls, base) = split_controls(name)?;
+    let prefix = "c".repeat(controls);
+    let (base, params) = match base {
+        "id" | "x" | "y" | "z" | "h" => (base, params.to_vec()),
+        "s" => ("sdg", vec![]),
+        "sdg" => ("s", vec![]),
+        "t" => ("tdg", vec![]),
+        "tdg" => ("t", vec![]),
+        "sx" => ("sxdg", vec![]),
+        "sxdg" => ("sx", vec![]),
+        "rx" | "ry" | "rz" | "p" | "u1" => (base, vec![-params[0]]),
+        "u2" => ("u3", vec![-PI/2.0, -params[1], -params[0]]),
+        "u3" | "u" => (base, vec![-params[0], -params[2], -params[1]]),
+        _ => return None
+    };
+    Some((format!("{}{}", prefix, base), params))
+}
+
+/// The matrix of a single qubit gate, indexed by [output][input] value of its qubit, or `None` if
+/// the simulator does not implement it.
+pub fn get_matrix(name:&str, params:&[f64]) -> Option<[[ComplexCoefficient; 2]; 2]> {
+    let part = coefficient::create_complex_coefficient_from_parts;
+    let phase = |angle:f64| coefficient::create_complex_coefficient_from_polar(1.0, angle);
+    let (zero, one) = (part(0.0, 0.0), part(1.0, 0.0));
+    let matrix = match name {
+        "id" => [[one, zero], [zero, one]],
+        "x" => [[zero, one], [one, zero]],
+        "y" => [[zero, part(0.0, -1.0)], [part(0.0, 1.0), zero]],
+        "z" => [[one, zero], [zero, part(-1.0, 0.0)]],
+        "h" => {
+            let plus = part(FRAC_1_SQRT_2, 0.0);
+            [[plus, plus], [plus, part(-FRAC_1_SQRT_2, 0.0)]]
+        },
+        "s" => [[one, zero], [zero, part(0.0, 1.0)]],
+        "sdg" => [[one, zero], [zero, part(0.0, -1.0)]],
+        "t" => [[one, zero], [zero, phase(PI/4.0)]],
+        "tdg" => [[one, zero], [zero, phase(-PI/4.0)]],
+        "sx" => [[part(0.5, 0.5), part(0.5, -0.5)], [part(0.5, -0.5), part(0.5, 0.5)]],
+        "sxdg" => [[part(0.5, -0.5), part(0.5, 0.5)], [part(0.5, 0.5), part(0.5, -0.5)]],
+        "rx" => {
+            let (cos, sin) = ((params[0]/2.0).cos(), (params[0]/2.0).sin());
+            [[part(cos, 0.0), part(0.0, -sin)], [part(0.0, -sin), part(cos, 0.0)]]
+        },
+        "ry" => {
+            let (cos, sin) = ((params[0]/2.0).cos(), (params[0]/2.0).sin());
+            [[part(cos, 0.0), part(-sin, 0.0)], [part(sin, 0.0), part(cos, 0.0)]]
+        },
+        "rz" => [[phase(-params[0]/2.0), zero], [zero, phase(params[0]/2.0)]],
+        "p" | "u1" => [[one, zero], [zero, phase(params[0])]],
+        "u2" => return get_matrix("u3", &[PI/2.0, params[0], params[1]]),
+        "u3" | "u" => {
+            let (theta, phi, lambda) = (params[0], params[1], params[2]);
+            let (cos, sin) = ((theta/2.0).cos(), (theta/2.0).sin());
+            [
+                [part(cos, 0.0), coefficient::create_complex_coefficient_from_polar(-sin, lambda)],
+                [coefficient::create_complex_coefficient_from_polar(sin, phi), coefficient::create_complex_coefficient_from_polar(cos, phi + lambda)]
+            ]
+        },
+        _ => return None
+    };
+    Some(matrix)
+}
+
+impl Gate {
+
+    /// The name of the gate, e.g. `h`, `cx` or `rz`.
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// The qubits the gate acts on, controls first.
+    pub fn get_qubits(&self) -> &[usize] {
+        &self.qubits
+    }
+
+    /// The gate's parameters, such as rotation angles in radians.
+    pub fn get_params(&self) -> &[f64] {
+        &self.params
+    }
+}
+
+impl fmt::Display for Gate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.params.is_empty() {
+            let params:Vec<String> = self.params.iter().map(|param| param.to_string()).collect();
+            write!(f, "({})", params.join(", "))?;
+        }
+        let qubits:Vec<String> = self.qubits.iter().map(|qubit| qubit.to_string()).collect();
+        write!(f, " {}", qubits.join(", "))
+    }
+}
@@ -2,32 +2,39 @@
 //! Data structures that represent the information associated with a single ket in a quantum state,
 //! including any relationship to entanglement and non-linear effects.
 
-extern crate bit_vec;
-use bit_vec::BitVec;
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::basis::BasisState;
 use crate::coefficient::ComplexCoefficient;
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Ket {
     coefficient: ComplexCoefficient,
-    val: BitVec,
+    val: BasisState,
     entanglements: Vec<Entanglement>
 }
 
-/// Initializes a ket with a value and coefficient.
-pub fn create_ket(coeff:ComplexCoefficient, val:BitVec, entanglements:Vec<Entanglement>) -> Ket {
-    Ket{coefficient: coeff, val: val, entanglements: entanglements}
+/// Initializes a ket with a value and coefficient. The value may be a `BasisState`
+/// or a `BitVec`.
+pub fn create_ket<V: Into<BasisState>>(coeff:ComplexCoefficient, val:V, entanglements:Vec<Entanglement>) -> Ket {
+    Ket{coefficient: coeff, val: val.into(), entanglements}
 }
 
 impl Ket {
 
     /// The equality of kets compares their qubit strings, not their coefficients.        
     pub fn equals(&self, other:Ket) -> bool {
-        self.val == other.get_val()
+        self.val == other.val
     }
 
     /// The qubit string value of the ket.        
-    pub fn get_val(&self) -> BitVec {
-        self.val.clone()
+    pub fn get_val(&self) -> &BasisState {
+        &self.val
+    }
+
+    /// The value of a single qubit in the ket's qubit string, or `None` if it is out of range.
+    pub fn get_bit(&self, qubit:usize) -> Option<bool> {
+        self.val.get(qubit)
     }
 
     /// Returns the complex coefficient of the ket.    
@@ -36,8 +43,8 @@ impl Ket {
     }
 
     /// Sets the qubit string value for the ket.        
-    pub fn set_val(&mut self, val:BitVec) {
-        self.val = val;
+    pub fn set_val<V: Into<BasisState>>(&mut self, val:V) {
+        self.val = val.into();
     }
 
     /// Sets the coefficient of the ket's term in the overall quantum state.                    
@@ -53,30 +60,13 @@ impl Ket {
 
     /// Performs a Pauli X gate on the target qubit.
     pub fn x(&mut self, qubit:usize) {
-        let mut q:usize = 0;
-        while q < self.val.len() {
-            if q == qubit {
-                match self.val.get(q) {
-                    Some(mut val) => { 
-                        if val == true {
-                            self.val.set(q, false);
-                        }
-                        else {
-                            self.val.set(q, true);
-                        }
-                     },
-                    None => panic!("attempt to flip non-existent qubit.")
-                };
-                break;
-            }
-            q += 1;
-        }
+        self.val.flip(qubit);
     }
     
     /// Performs a Controlled X gate on the target qubit with the source qubit
     /// as controller.
     pub fn cx(&mut self, source:usize, target:usize) {
-        if self.val.get(source) == Some(true) { 
+        if self.val.get(source) == Some(true) {
             self.x(target);
         }
     }
@@ -97,20 +87,19 @@ impl Ket {
 
     /// Prints the state.        
     pub fn print(&self) {
-        self.coefficient.print();
-        print!("|{:?}>", self.val);
+        print!("{}", self);
     }
 
     /// Determines whether the existence of the ket is predicated upon entanglement 
     /// interactions.
     pub fn is_entangled(&self) -> bool {
-        self.entanglements.len() > 0
+        !self.entanglements.is_empty()
     }
 
 
     /// Determines whether the existence of the ket is predicated upon the entanglement 
     /// interaction of the given qubit.
-    pub fn is_entangled_with(&self, system:char, qubit:usize) -> bool {
+    pub fn is_entangled_with(&self, system:&str, qubit:usize) -> bool {
         let mut is_entangled = false;
         for entanglement in &self.entanglements {
             if entanglement.get_system() == system && entanglement.get_qubit() == qubit {
@@ -120,14 +109,19 @@ impl Ket {
         is_entangled
     }
 
+    /// The entanglements the existence of the ket is predicated upon.
+    pub fn get_entanglements(&self) -> &[Entanglement] {
+        &self.entanglements
+    }
+
     /// Registers the ket's dependence on an entanglement of qubits.
-    pub fn entangle(&mut self, outcome:bool, system:char, qubit:usize) {
+    pub fn entangle(&mut self, outcome:bool, system:&str, qubit:usize) {
         self.entanglements.push(create_entanglement(outcome, system, qubit));
     }
 
     /// Determines whether the ket should collapse and disappear following a measurement 
     /// of the given qubit.
-    pub fn should_collapse(&mut self, outcome:bool, system:char, qubit:usize) -> bool {
+    pub fn should_collapse(&mut self, outcome:bool, system:&str, qubit:usize) -> bool {
         let mut should_collapse = false;
         let mut remove_index:isize = -1;
         for (index, entanglement) in self.entanglements.iter().enumerate() {
@@ -152,36 +146,31 @@ impl Ket {
             other_ket.entangle(entanglement.get_outcome(), entanglement.get_system(), entanglement.get_qubit())
         }
     }
+}
 
-    /// Performs a Hadamard gate on the target qubit.
-    pub fn h(&mut self, qubit:usize) -> [Ket; 2] {
-        let mut new_ket = create_ket(self.coefficient, self.val.clone(), self.entanglements.clone());
-        new_ket.x(qubit);
-
-        if self.val.get(qubit) == Some(true) {
-            self.coefficient.negate_magnitude();
-        }
-        [create_ket(self.get_coefficient(), self.get_val(), vec![]), new_ket]
+impl fmt::Display for Ket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}|{:?}>", self.coefficient, self.val)
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Entanglement {
     outcome:bool,
-    system:char,
+    system:String,
     qubit:usize
 }
 
 /// Initializes an entanglement object.
-pub fn create_entanglement(outcome:bool, system:char, qubit:usize) -> Entanglement {
-    Entanglement{outcome: outcome, system: system, qubit: qubit}
+pub fn create_entanglement(outcome:bool, system:&str, qubit:usize) -> Entanglement {
+    Entanglement{outcome, system: system.to_string(), qubit}
 }
 
 impl Entanglement {
 
     /// Gets the system owning the entangled qubit.
-    pub fn get_system(&self) -> char {
-        self.system
+    pub fn get_system(&self) -> &str {
+        &self.system
     }
 
     /// Gets the qubit which is entangled.
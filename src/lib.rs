@@ -1,8 +1,29 @@
-mod coefficient;
-mod ket;
-mod state;
-mod ensemble;
+pub mod basis;
+pub mod coefficient;
+pub mod ket;
+pub mod state;
+pub mod truncation;
+pub mod ensemble;
+pub mod gate;
+pub mod circuit;
+pub mod builder;
+pub mod optimization;
+pub mod transpiler;
+pub mod routing;
+pub mod metrics;
+pub mod drawer;
+pub mod exporter;
+pub mod preparation;
+mod parallel;
+pub mod tracer;
+pub mod snapshot;
+pub mod unitary;
+pub mod expression;
 pub mod parser;
+pub mod qasm3;
+pub mod validation;
+
+pub use builder::{create_circuit_builder, CircuitBuilder};
 
 #[cfg(test)]
 mod tests;
\ No newline at end of file
@@ -1,10 +1,13 @@
 extern crate dotenv;
 extern crate qasm;
 
+use rustsimulationservice::drawer;
 use rustsimulationservice::parser;
+use rustsimulationservice::parser::{ExecutionOptions, ExecutionResult, SimError};
+use rustsimulationservice::routing;
+use rustsimulationservice::truncation::Truncation;
 
 use std::env;
-use std::collections::BTreeMap;
 use amiquip::{
     AmqpProperties, Connection, ConsumerMessage, ConsumerOptions, Exchange, Publish,
     QueueDeclareOptions, Result
@@ -15,11 +18,66 @@ fn main() -> Result<()> {
 
     dotenv::dotenv();
 
+    // `--draw <file> [--ascii] [--width <columns>]` prints a diagram of a QASM program and exits.
+    let args:Vec<String> = env::args().collect();
+    if let Some(position) = args.iter().position(|arg| arg == "--draw") {
+        let path = args.get(position + 1).expect("No QASM file given to draw!");
+        let source = std::fs::read_to_string(path).expect("QASM file could not be read!");
+        let style = if args.iter().any(|arg| arg == "--ascii") { drawer::Style::Ascii } else { drawer::Style::Unicode };
+        let width = match args.iter().position(|arg| arg == "--width") {
+            Some(position) => args.get(position + 1).and_then(|width| width.parse().ok()).expect("Width must be a number!"),
+            None => drawer::DEFAULT_WIDTH
+        };
+        match parser::parse_qasm(&source) {
+            Ok(circuit) => println!("{}", drawer::draw(&circuit, style, width)),
+            Err(e) => println!("Error parsing qasm: {}", e)
+        }
+        return Ok(());
+    }
+
     let rb_user = env::var("RABBIT_USER").expect("Queue user not configured!");
     let rb_pass = env::var("RABBIT_PASSWORD").expect("Queue password not configured!");
     let rb_port = env::var("RABBIT_PORT").expect("Queue port not configured!");
     let rb_host = env::var("RABBIT_HOST").expect("Queue host not configured!");
 
+    let mut options = parser::create_execution_options();
+    if let Ok(threads) = env::var("SIMULATION_THREADS") {
+        options.set_threads(threads.parse().expect("Simulation threads must be a number!"));
+    }
+    if let Ok(optimize) = env::var("OPTIMIZE_CIRCUITS") {
+        options.set_optimize(optimize.parse().expect("Optimize circuits must be true or false!"));
+    }
+    if let Ok(metrics) = env::var("INCLUDE_METRICS") {
+        options.set_metrics(metrics.parse().expect("Include metrics must be true or false!"));
+    }
+    if let Ok(basis) = env::var("BASIS_GATES") {
+        options.set_basis(Some(basis.split(',').map(|gate| gate.trim().to_string()).collect()));
+    }
+    if let Ok(coupling_map) = env::var("COUPLING_MAP") {
+        // Edges between physical qubits, e.g. `0-1,1-2,2-3`.
+        let edges:Vec<(usize, usize)> = coupling_map.split(',').map(|edge| {
+            let qubits:Vec<usize> = edge.split('-').map(|qubit| qubit.trim().parse().expect("Coupling map qubits must be numbers!")).collect();
+            match qubits[..] {
+                [first, second] => (first, second),
+                _ => panic!("Coupling map edges must be pairs of qubits!")
+            }
+        }).collect();
+        let num_qubits = edges.iter().map(|(first, second)| first.max(second) + 1).max().unwrap_or(0);
+        match routing::create_coupling_map(num_qubits, &edges) {
+            Ok(coupling_map) => options.set_coupling_map(Some(coupling_map)),
+            Err(e) => panic!("Coupling map is invalid: {}!", e)
+        }
+    }
+    if env::var("TRUNCATION_THRESHOLD").is_ok() && env::var("TRUNCATION_TOP_K").is_ok() {
+        panic!("Only one of truncation threshold and truncation top K can be configured!");
+    }
+    if let Ok(threshold) = env::var("TRUNCATION_THRESHOLD") {
+        options.set_truncation(Some(Truncation::Threshold(threshold.parse().expect("Truncation threshold must be a number!"))));
+    }
+    if let Ok(count) = env::var("TRUNCATION_TOP_K") {
+        options.set_truncation(Some(Truncation::TopK(count.parse().expect("Truncation top K must be a number!"))));
+    }
+
     // Open RabbitMQ connection.
     let mut connection = Connection::insecure_open(&format!("amqp://{}:{}@{}:{}", rb_user, rb_pass, rb_host, rb_port))?;
 
@@ -54,22 +112,20 @@ fn main() -> Result<()> {
                     }
                 };
 
-                let mut response:String = "{".to_string();
-                let result = parser::execute_qasm(&body);
-                let regs: Vec<BTreeMap<usize, usize>> = result.values().cloned().collect();
-                let keys:Vec<char> = result.keys().cloned().collect();
-
-                let mut i = 0;
-                for reg in regs {
-                    response.push_str(&format!(" \"{}\": ", keys[i]));
-                    let bitstring:Vec<usize> = reg.values().cloned().collect();
-                    for bit in bitstring {
-                        response.push_str(bit.to_string().as_str());
-                    }
-                    response.push_str(",");
-                    i += 1;
+                let diagnostics = parser::validate_qasm(&body);
+                let response = if !diagnostics.is_empty() {
+                    println!("Invalid qasm: {} problems", diagnostics.len());
+                    error_response(&diagnostics)
                 }
-                response.push_str("}");
+                else {
+                    match parser::simulate_qasm(&body, &options) {
+                        Ok(execution) => result_response(&execution, &options),
+                        Err(e) => {
+                            println!("Error executing qasm: {}", e);
+                            error_response(&[e])
+                        }
+                    }
+                };
 
                 exchange.publish(Publish::with_properties(
                     response.as_bytes(),
@@ -86,4 +142,48 @@ fn main() -> Result<()> {
     }
 
     connection.close()
-}
\ No newline at end of file
+}
+
+/// The JSON reply to a program which was executed: the bits of each classical register, by name,
+/// as a string starting with bit 0, and whatever else the options ask to be reported, e.g.
+/// `{"registers": {"c": "01"}, "swaps": 2}`. Registers are kept apart so their names cannot clash
+/// with the other fields.
+fn result_response(execution:&ExecutionResult, options:&ExecutionOptions) -> String {
+    if let Some(report) = execution.get_optimization() {
+        println!("{}", report);
+    }
+    let mut registers = serde_json::Map::new();
+    for (name, reg) in execution.get_classical_regs() {
+        let bitstring:String = reg.values().map(|bit| bit.to_string()).collect();
+        registers.insert(name.clone(), bitstring.into());
+    }
+    let mut response = serde_json::Map::new();
+    response.insert("registers".to_string(), serde_json::Value::Object(registers));
+    if let Some(metrics) = execution.get_metrics() {
+        response.insert("metrics".to_string(), serde_json::to_value(metrics).expect("Metrics could not be serialized!"));
+    }
+    if let Some(transpiled) = execution.get_transpiled() {
+        response.insert("transpiled".to_string(), transpiled.to_string().into());
+    }
+    if let Some(routing) = execution.get_routing() {
+        response.insert("swaps".to_string(), routing.get_num_swaps().into());
+    }
+    if options.get_truncation().is_some() {
+        response.insert("fidelity".to_string(), execution.get_fidelity().into());
+    }
+    serde_json::Value::Object(response).to_string()
+}
+
+/// The JSON reply to a program which could not be executed, listing every problem found, e.g.
+/// `{"errors": [{"kind": "parse", "message": "...", "line": 3, "column": 5}]}`.
+fn error_response(errors:&[SimError]) -> String {
+    let errors:Vec<serde_json::Value> = errors.iter().map(|error| {
+        let mut details = serde_json::json!({"kind": error.get_kind(), "message": error.to_string()});
+        if let Some((line, column)) = error.get_position() {
+            details["line"] = line.into();
+            details["column"] = column.into();
+        }
+        details
+    }).collect();
+    serde_json::json!({"errors": errors}).to_string()
+}
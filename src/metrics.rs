@@ -0,0 +1,140 @@
+//! # Metrics
+//! Static measures of the cost of running a circuit: its depth and the instructions along its
+//! critical path, its gate counts, its two qubit and T gate counts, and its width. These can be
+//! computed from a parsed program before deciding whether to simulate it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use serde::Serialize;
+use crate::circuit;
+use crate::circuit::{Bit, Circuit};
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Metrics {
+    depth: usize,
+    width: usize,
+    num_clbits: usize,
+    num_gates: usize,
+    gate_counts: BTreeMap<String, usize>,
+    two_qubit_gates: usize,
+    t_count: usize,
+    critical_path: Vec<usize>
+}
+
+/// Computes the metrics of a circuit. Barriers synchronize their qubits but take no time, and an
+/// instruction conditioned on a classical register waits for every bit of that register.
+pub fn compute_metrics(circuit:&Circuit) -> Metrics {
+    // For each bit, the depth reached on it so far and the instruction which reached it.
+    let mut frontier:HashMap<Bit, (usize, Option<usize>)> = HashMap::new();
+    let mut predecessors:Vec<Option<usize>> = vec![];
+    let mut gate_counts:BTreeMap<String, usize> = BTreeMap::new();
+    let mut two_qubit_gates = 0;
+    let mut t_count = 0;
+
+    for (index, instruction) in circuit.get_instructions().iter().enumerate() {
+        let mut bits:Vec<Bit> = instruction.get_qubits().iter().chain(instruction.get_clbits()).cloned().collect();
+        if let Some(condition) = instruction.get_condition() {
+            if let Some(register) = circuit.get_cregs().iter().find(|register| register.get_name() == condition.get_register()) {
+                bits.extend((0..register.get_size()).map(|bit| circuit::create_bit(register.get_name(), bit)));
+            }
+        }
+        let (start, predecessor) = bits.iter()
+            .map(|bit| frontier.get(bit).cloned().unwrap_or((0, None)))
+            .max_by_key(|(depth, _)| *depth)
+            .unwrap_or((0, None));
+
+        if instruction.get_name() == circuit::BARRIER {
+            predecessors.push(None);
+            for bit in bits {
+                frontier.insert(bit, (start, predecessor));
+            }
+            continue;
+        }
+
+        predecessors.push(predecessor);
+        for bit in bits {
+            frontier.insert(bit, (start + 1, Some(index)));
+        }
+        *gate_counts.entry(instruction.get_name().to_string()).or_insert(0) += 1;
+        if instruction.is_gate() && instruction.get_qubits().len() == 2 {
+            two_qubit_gates += 1;
+        }
+        if instruction.get_name() == "t" || instruction.get_name() == "tdg" {
+            t_count += 1;
+        }
+    }
+
+    let (depth, mut last) = frontier.values().cloned().max_by_key(|(depth, _)| *depth).unwrap_or((0, None));
+    let mut critical_path:Vec<usize> = vec![];
+    while let Some(index) = last {
+        critical_path.push(index);
+        last = predecessors[index];
+    }
+    critical_path.reverse();
+
+    Metrics{
+        depth,
+        width: circuit.num_qubits(),
+        num_clbits: circuit.num_clbits(),
+        num_gates: circuit.get_instructions().iter().filter(|instruction| instruction.is_gate()).count(),
+        gate_counts,
+        two_qubit_gates,
+        t_count,
+        critical_path
+    }
+}
+
+impl Metrics {
+
+    /// The number of time steps needed if every instruction takes one step and instructions on
+    /// disjoint bits run in parallel.
+    pub fn get_depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The number of qubits.
+    pub fn get_width(&self) -> usize {
+        self.width
+    }
+
+    /// The number of classical bits.
+    pub fn get_num_clbits(&self) -> usize {
+        self.num_clbits
+    }
+
+    /// The number of gates, not counting measurements, resets and barriers.
+    pub fn get_num_gates(&self) -> usize {
+        self.num_gates
+    }
+
+    /// The number of each kind of instruction, by name, not counting barriers.
+    pub fn get_gate_counts(&self) -> &BTreeMap<String, usize> {
+        &self.gate_counts
+    }
+
+    /// The number of gates acting on two qubits.
+    pub fn get_two_qubit_gates(&self) -> usize {
+        self.two_qubit_gates
+    }
+
+    /// The number of `t` and `tdg` gates.
+    pub fn get_t_count(&self) -> usize {
+        self.t_count
+    }
+
+    /// The indices of a longest chain of dependent instructions, in program order. Its length is
+    /// the depth.
+    pub fn get_critical_path(&self) -> &[usize] {
+        &self.critical_path
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "depth: {}", self.depth)?;
+        writeln!(f, "width: {} qubits, {} clbits", self.width, self.num_clbits)?;
+        writeln!(f, "gates: {} ({} two qubit, {} T)", self.num_gates, self.two_qubit_gates, self.t_count)?;
+        let counts:Vec<String> = self.gate_counts.iter().map(|(name, count)| format!("{}: {}", name, count)).collect();
+        write!(f, "counts: {}", counts.join(", "))
+    }
+}
@@ -0,0 +1,301 @@
+//! # Optimization
+//! Passes which simplify a circuit without changing what it computes, and a pass manager which
+//! runs them in sequence until the circuit stops shrinking. Instructions are only ever combined
+//! with the instruction immediately before them on the same qubits, so barriers, measurements and
+//! classically conditioned instructions are never moved past.
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fmt;
+use std::sync::Arc;
+use crate::circuit;
+use crate::circuit::{Bit, Circuit, Instruction};
+
+/// Rotation angles within this distance of a multiple of 4π are treated as the identity.
+pub const ROTATION_TOLERANCE:f64 = 1e-12;
+
+/// The largest number of times the pass manager runs its passes over a circuit.
+pub const MAX_ITERATIONS:usize = 16;
+
+/// A transformation of a circuit into an equivalent one.
+pub trait Pass: Send + Sync {
+    /// The name the pass is reported under.
+    fn name(&self) -> &str;
+
+    /// Produces the transformed circuit.
+    fn run(&self, circuit:&Circuit) -> Circuit;
+}
+
+/// Cancels pairs of adjacent identical self-inverse gates, such as `x·x`, `h·h` and `cx·cx`.
+pub struct CancelInverses;
+
+/// Merges consecutive rotations about the same axis on the same qubit into one.
+pub struct MergeRotations;
+
+/// Removes single qubit gates immediately before a reset of their qubit.
+pub struct RemoveBeforeReset;
+
+/// Removes rotations by a multiple of 4π, which are exactly the identity.
+pub struct RemoveIdentityRotations;
+
+const SELF_INVERSE_GATES:[&str; 5] = ["x", "y", "z", "h", "cx"];
+const ROTATION_GATES:[&str; 3] = ["rx", "ry", "rz"];
+
+/// What to do with an instruction and the one before it on the same qubits.
+enum Combination {
+    Keep,
+    Cancel,
+    Merge(Instruction)
+}
+
+impl Pass for CancelInverses {
+    fn name(&self) -> &str {
+        "cancel inverses"
+    }
+
+    fn run(&self, circuit:&Circuit) -> Circuit {
+        combine_adjacent(circuit, |previous, next| {
+            if previous.get_name() == next.get_name() && previous.get_qubits() == next.get_qubits() && SELF_INVERSE_GATES.contains(&next.get_name()) {
+                Combination::Cancel
+            }
+            else {
+                Combination::Keep
+            }
+        })
+    }
+}
+
+impl Pass for MergeRotations {
+    fn name(&self) -> &str {
+        "merge rotations"
+    }
+
+    fn run(&self, circuit:&Circuit) -> Circuit {
+        combine_adjacent(circuit, |previous, next| {
+            if previous.get_name() == next.get_name() && previous.get_qubits() == next.get_qubits() && ROTATION_GATES.contains(&next.get_name()) {
+                let angle = previous.get_params()[0] + next.get_params()[0];
+                Combination::Merge(circuit::create_instruction(next.get_name(), next.get_qubits().to_vec(), vec![angle]))
+            }
+            else {
+                Combination::Keep
+            }
+        })
+    }
+}
+
+impl Pass for RemoveBeforeReset {
+    fn name(&self) -> &str {
+        "remove gates before reset"
+    }
+
+    fn run(&self, circuit:&Circuit) -> Circuit {
+        let mut output:Vec<Option<Instruction>> = vec![];
+        let mut history:HashMap<Bit, Vec<usize>> = HashMap::new();
+        for instruction in circuit.get_instructions() {
+            if instruction.get_name() == circuit::RESET && instruction.get_condition().is_none() {
+                let stack = history.entry(instruction.get_qubits()[0].clone()).or_default();
+                while let Some(previous) = stack.last() {
+                    match &output[*previous] {
+                        Some(gate) if is_unconditional_gate(gate) && gate.get_qubits().len() == 1 => {
+                            output[*previous] = None;
+                            stack.pop();
+                        },
+                        _ => break
+                    }
+                }
+            }
+            push(&mut output, &mut history, instruction.clone());
+        }
+        rebuild(circuit, output)
+    }
+}
+
+impl Pass for RemoveIdentityRotations {
+    fn name(&self) -> &str {
+        "remove identity rotations"
+    }
+
+    fn run(&self, circuit:&Circuit) -> Circuit {
+        let instructions = circuit.get_instructions().iter().filter(|instruction| {
+            !(ROTATION_GATES.contains(&instruction.get_name()) && is_identity_angle(instruction.get_params()[0]))
+        }).cloned().collect();
+        let mut optimized = circuit.clone();
+        optimized.set_instructions(instructions);
+        optimized
+    }
+}
+
+/// The gate counts before and after one run of a pass.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PassReport {
+    name: String,
+    gates_before: usize,
+    gates_after: usize
+}
+
+impl PassReport {
+
+    /// The name of the pass.
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of gates in the circuit before the pass ran.
+    pub fn get_gates_before(&self) -> usize {
+        self.gates_before
+    }
+
+    /// The number of gates in the circuit after the pass ran.
+    pub fn get_gates_after(&self) -> usize {
+        self.gates_after
+    }
+}
+
+/// Every pass run by the pass manager, in order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OptimizationReport {
+    passes: Vec<PassReport>
+}
+
+impl OptimizationReport {
+
+    /// The report of each pass run, in order.
+    pub fn get_passes(&self) -> &[PassReport] {
+        &self.passes
+    }
+
+    /// The number of gates in the circuit before optimization.
+    pub fn get_gates_before(&self) -> usize {
+        self.passes.first().map(|pass| pass.gates_before).unwrap_or(0)
+    }
+
+    /// The number of gates in the circuit after optimization.
+    pub fn get_gates_after(&self) -> usize {
+        self.passes.last().map(|pass| pass.gates_after).unwrap_or(0)
+    }
+}
+
+impl fmt::Display for OptimizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for pass in &self.passes {
+            writeln!(f, "{}: {} -> {} gates", pass.name, pass.gates_before, pass.gates_after)?;
+        }
+        write!(f, "total: {} -> {} gates", self.get_gates_before(), self.get_gates_after())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct PassManager {
+    passes: Vec<Arc<dyn Pass>>
+}
+
+/// Initializes a pass manager with no passes.
+pub fn create_pass_manager() -> PassManager {
+    PassManager::default()
+}
+
+/// Initializes a pass manager with every built-in pass.
+pub fn default_pass_manager() -> PassManager {
+    let mut manager = create_pass_manager();
+    manager.add_pass(Arc::new(RemoveBeforeReset));
+    manager.add_pass(Arc::new(CancelInverses));
+    manager.add_pass(Arc::new(MergeRotations));
+    manager.add_pass(Arc::new(RemoveIdentityRotations));
+    manager
+}
+
+impl PassManager {
+
+    /// Appends a pass to the sequence.
+    pub fn add_pass(&mut self, pass:Arc<dyn Pass>) {
+        self.passes.push(pass);
+    }
+
+    /// Runs the passes in sequence, repeating the sequence until it no longer reduces the number
+    /// of gates or `MAX_ITERATIONS` is reached, since one pass can expose work for another.
+    pub fn run(&self, circuit:&Circuit) -> (Circuit, OptimizationReport) {
+        let mut circuit = circuit.clone();
+        let mut report = OptimizationReport::default();
+        for _ in 0..MAX_ITERATIONS {
+            let gates_at_start = count_gates(&circuit);
+            for pass in &self.passes {
+                let gates_before = count_gates(&circuit);
+                circuit = pass.run(&circuit);
+                report.passes.push(PassReport{name: pass.name().to_string(), gates_before, gates_after: count_gates(&circuit)});
+            }
+            if count_gates(&circuit) >= gates_at_start {
+                break;
+            }
+        }
+        (circuit, report)
+    }
+}
+
+/// The number of gates in a circuit, not counting measurements, resets and barriers.
+pub fn count_gates(circuit:&Circuit) -> usize {
+    circuit.get_instructions().iter().filter(|instruction| instruction.is_gate()).count()
+}
+
+fn is_unconditional_gate(instruction:&Instruction) -> bool {
+    instruction.is_gate() && instruction.get_condition().is_none()
+}
+
+fn is_identity_angle(angle:f64) -> bool {
+    let remainder = angle.rem_euclid(4.0*PI);
+    remainder < ROTATION_TOLERANCE || 4.0*PI - remainder < ROTATION_TOLERANCE
+}
+
+/// Walks the circuit, offering each unconditional gate to `combine` together with the last
+/// instruction on its qubits, if that instruction is also an unconditional gate on exactly the
+/// same qubits.
+fn combine_adjacent<F>(circuit:&Circuit, combine:F) -> Circuit where F: Fn(&Instruction, &Instruction) -> Combination {
+    let mut output:Vec<Option<Instruction>> = vec![];
+    let mut history:HashMap<Bit, Vec<usize>> = HashMap::new();
+    for instruction in circuit.get_instructions() {
+        if let Some(previous) = get_adjacent(&output, &history, instruction) {
+            match combine(output[previous].as_ref().unwrap(), instruction) {
+                Combination::Keep => {},
+                Combination::Cancel => {
+                    output[previous] = None;
+                    for qubit in instruction.get_qubits() {
+                        history.entry(qubit.clone()).or_default().pop();
+                    }
+                    continue;
+                },
+                Combination::Merge(merged) => {
+                    output[previous] = Some(merged);
+                    continue;
+                }
+            }
+        }
+        push(&mut output, &mut history, instruction.clone());
+    }
+    rebuild(circuit, output)
+}
+
+/// The index of the instruction immediately before this one on every one of its qubits, if the
+/// two are unconditional gates on the same qubits.
+fn get_adjacent(output:&[Option<Instruction>], history:&HashMap<Bit, Vec<usize>>, instruction:&Instruction) -> Option<usize> {
+    if !is_unconditional_gate(instruction) || instruction.get_qubits().is_empty() {
+        return None;
+    }
+    let previous = *history.get(&instruction.get_qubits()[0])?.last()?;
+    let candidate = output[previous].as_ref()?;
+    let same_qubits = candidate.get_qubits().len() == instruction.get_qubits().len() && instruction.get_qubits().iter().all(|qubit| {
+        history.get(qubit).and_then(|stack| stack.last()) == Some(&previous)
+    });
+    if same_qubits && is_unconditional_gate(candidate) { Some(previous) } else { None }
+}
+
+fn push(output:&mut Vec<Option<Instruction>>, history:&mut HashMap<Bit, Vec<usize>>, instruction:Instruction) {
+    for qubit in instruction.get_qubits() {
+        history.entry(qubit.clone()).or_default().push(output.len());
+    }
+    output.push(Some(instruction));
+}
+
+fn rebuild(circuit:&Circuit, output:Vec<Option<Instruction>>) -> Circuit {
+    let mut optimized = circuit.clone();
+    optimized.set_instructions(output.into_iter().flatten().collect());
+    optimized
+}
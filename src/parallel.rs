@@ -0,0 +1,68 @@
+//! # Parallel
+//! Helpers for spreading work over the ket list of a state across a pool of threads. Kets are split
+//! into fixed-size chunks which are distributed by work-stealing, and results are always merged in
+//! ket order, so the outcome of an operation does not depend on the number of threads used.
+
+extern crate rayon;
+
+use std::sync::Arc;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+/// The number of kets handed to a thread at a time.
+pub const CHUNK_SIZE:usize = 512;
+
+#[derive(Clone)]
+pub struct Parallelism {
+    threads: usize,
+    pool: Option<Arc<ThreadPool>>
+}
+
+/// Initializes a thread pool of the given size. Zero or one threads runs everything serially on the
+/// calling thread.
+pub fn create_parallelism(threads:usize) -> Parallelism {
+    if threads <= 1 {
+        return Parallelism{threads: 1, pool: None};
+    }
+    let pool = match ThreadPoolBuilder::new().num_threads(threads).build() {
+        Ok(pool) => pool,
+        Err(e) => panic!("could not start simulation thread pool: {}", e)
+    };
+    Parallelism{threads, pool: Some(Arc::new(pool))}
+}
+
+impl Default for Parallelism {
+    fn default() -> Parallelism {
+        create_parallelism(1)
+    }
+}
+
+impl Parallelism {
+
+    /// The number of threads operations are spread across.
+    pub fn get_threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Applies the function to every item in place.
+    pub fn for_each<T, F>(&self, items:&mut [T], f:F) where T: Send, F: Fn(&mut T) + Send + Sync {
+        match &self.pool {
+            Some(pool) if items.len() > CHUNK_SIZE => pool.install(|| {
+                items.par_chunks_mut(CHUNK_SIZE).for_each(|chunk| chunk.iter_mut().for_each(&f));
+            }),
+            _ => items.iter_mut().for_each(f)
+        }
+    }
+
+    /// Applies the function to consecutive chunks of the items and returns the per-chunk results in
+    /// order. Chunk boundaries are the same regardless of thread count, so reductions over the
+    /// results are reproducible.
+    pub fn map_chunks<T, R, F>(&self, items:&[T], f:F) -> Vec<R> where T: Sync, R: Send, F: Fn(&[T]) -> R + Send + Sync {
+        match &self.pool {
+            Some(pool) if items.len() > CHUNK_SIZE => pool.install(|| {
+                items.par_chunks(CHUNK_SIZE).map(&f).collect()
+            }),
+            _ => items.chunks(CHUNK_SIZE).map(f).collect()
+        }
+    }
+}
@@ -1,222 +1,766 @@
-extern crate bit_vec;
 extern crate qasm;
 
+use crate::basis;
+use crate::circuit;
+use crate::circuit::{Bit, Circuit, Instruction, Register};
 use crate::ket;
 use crate::ket::Ket;
 use crate::state;
 use crate::state::State;
 use crate::coefficient;
+use crate::coefficient::ComplexCoefficient;
 use crate::ensemble;
 use crate::ensemble::Ensemble;
+use crate::expression;
+use crate::gate;
+use crate::metrics;
+use crate::metrics::Metrics;
+use crate::optimization;
+use crate::optimization::OptimizationReport;
+use crate::parallel;
+use crate::qasm3;
+use crate::qasm3::ErrorKind;
+use crate::routing;
+use crate::routing::{CouplingMap, Routing, RoutingError};
+use crate::tracer;
+use crate::tracer::Tracer;
+use crate::transpiler;
+use crate::transpiler::TranspileError;
+use crate::truncation::Truncation;
+use crate::unitary;
+use crate::unitary::UnitaryError;
+use crate::validation;
 
-use bit_vec::BitVec;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
 
+/// The standard gate library, which programs load with `include "qelib1.inc";`.
+const QELIB1_INC:&str = include_str!("qelib1.inc");
 
-pub fn init_ket(num_qubits:usize) -> Ket {
-    let imaginary_coeff = coefficient::create_coefficient(1.0, true);
-    let real_coeff = coefficient::create_coefficient(1.0, false);
-    let complex_coeff = coefficient::create_complex_coefficient(real_coeff, imaginary_coeff);
-    let init_state = BitVec::from_elem(num_qubits, false);
-    let ket = ket::create_ket(complex_coeff, init_state.clone(), vec![]);
-    ket
+/// How deeply gate definitions may apply one another, which bounds a definition applying itself.
+/// Shared by the OpenQASM 2 and 3 frontends.
+pub const MAX_GATE_DEPTH:usize = 64;
+
+/// How many instructions a program may expand to once its gate definitions are applied and, in
+/// OpenQASM 3, its loops unrolled. Shared by the OpenQASM 2 and 3 frontends.
+pub const MAX_INSTRUCTIONS:usize = 1_000_000;
+
+/// A gate defined by a `gate` statement, which is applied by applying the gates of its body.
+struct GateDefinition {
+    params: Vec<String>,
+    qubits: Vec<String>,
+    body: Vec<qasm::AstNode>
 }
 
-pub fn init_state(num_qubits:usize, symbol:char) -> State {
-    let mut kets = Vec::new();
+#[derive(Debug, PartialEq)]
+pub enum SimError {
+    /// A character or number which cannot be read, at a 1-based line and column.
+    Lex { line: usize, column: usize, message: String },
+    /// A statement which does not follow the grammar, at a 1-based line and column.
+    Parse { line: usize, column: usize, message: String },
+    /// The program refers to a register which was never declared.
+    UndeclaredRegister(String),
+    /// The program refers to a bit beyond the end of its register, e.g. `q[3]` of `qreg q[3];`.
+    IndexOutOfRange { register: String, index: usize, size: usize },
+    /// A register or gate is declared under a name which is already taken.
+    DuplicateDeclaration(String),
+    /// A gate is applied to the same qubit more than once, e.g. `cx q[0], q[0];`.
+    RepeatedQubit(String),
+    /// An instruction the simulator cannot apply, such as an undefined gate or a gate other than
+    /// `cx` between registers.
+    UnsupportedGate(String),
+    /// A gate applied to the wrong number of qubits or parameters, or across registers of
+    /// different sizes.
+    ArityMismatch(String),
+    /// The program is larger than the simulator will run, e.g. it expands to more than
+    /// `MAX_INSTRUCTIONS` instructions.
+    ResourceLimit(String),
+    /// The circuit could not be transpiled to the basis gates.
+    Transpile(TranspileError),
+    /// The circuit could not be routed onto the device.
+    Routing(RoutingError),
+    /// Any other mistake in the program, such as a parameter which cannot be evaluated.
+    Invalid(String)
+}
+
+impl SimError {
+
+    /// A short name for the kind of error, which the service reports to clients.
+    pub fn get_kind(&self) -> &'static str {
+        match self {
+            SimError::Lex{..} => "lex",
+            SimError::Parse{..} => "parse",
+            SimError::UndeclaredRegister(_) => "undeclared_register",
+            SimError::IndexOutOfRange{..} => "index_out_of_range",
+            SimError::DuplicateDeclaration(_) => "duplicate_declaration",
+            SimError::RepeatedQubit(_) => "repeated_qubit",
+            SimError::UnsupportedGate(_) => "unsupported_gate",
+            SimError::ArityMismatch(_) => "arity_mismatch",
+            SimError::ResourceLimit(_) => "resource_limit",
+            SimError::Transpile(_) => "transpile",
+            SimError::Routing(_) => "routing",
+            SimError::Invalid(_) => "invalid"
+        }
+    }
 
-    let mut i = 0;
-    while i < num_qubits {
-        kets.push(init_ket(num_qubits.clone()));
-        i += 1;
+    /// The line and column the error was found at, for lex and parse errors.
+    pub fn get_position(&self) -> Option<(usize, usize)> {
+        match self {
+            SimError::Lex{line, column, ..} | SimError::Parse{line, column, ..} => Some((*line, *column)),
+            _ => None
+        }
     }
+}
 
-    let state = state::create_state(kets, num_qubits, symbol);
-    state
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SimError::Lex{line, column, message} | SimError::Parse{line, column, message} => write!(f, "line {}, column {}: {}", line, column, message),
+            SimError::UndeclaredRegister(register) => write!(f, "undeclared register {}", register),
+            SimError::IndexOutOfRange{register, index, size} => write!(f, "{}[{}] is out of range of a register of {} bits", register, index, size),
+            SimError::DuplicateDeclaration(name) => write!(f, "{} is declared more than once", name),
+            SimError::RepeatedQubit(instruction) => write!(f, "{} applies a gate to the same qubit more than once", instruction),
+            SimError::UnsupportedGate(instruction) => write!(f, "unsupported gate {}", instruction),
+            SimError::ArityMismatch(message) | SimError::ResourceLimit(message) | SimError::Invalid(message) => write!(f, "{}", message),
+            SimError::Transpile(e) => write!(f, "could not transpile circuit: {}", e),
+            SimError::Routing(e) => write!(f, "could not route circuit: {}", e)
+        }
+    }
+}
+
+impl Error for SimError {}
+
+
+/// Initializes the all-zero ket of the given number of qubits with amplitude one.
+pub fn init_ket(num_qubits:usize) -> Ket {
+    let complex_coeff = coefficient::create_complex_coefficient_from_parts(1.0, 0.0);
+    ket::create_ket(complex_coeff, basis::create_basis_state(num_qubits), vec![])
+}
+
+/// Initializes a register of the given number of qubits in the all-zero state.
+pub fn init_state(num_qubits:usize, symbol:&str) -> State {
+    state::create_state(vec![init_ket(num_qubits)], num_qubits, symbol)
 }
 
 pub fn init_ensemble() -> Ensemble {
-    let mut subsystems:BTreeMap<char, State> = BTreeMap::new();
-    let ensemble = ensemble::create_ensemble(subsystems);
-    ensemble
+    let subsystems:BTreeMap<String, State> = BTreeMap::new();
+    ensemble::create_ensemble(subsystems)
 }
 
+/// Initializes a classical register of the given size with every bit zero.
 pub fn init_classical_reg(size:usize) -> BTreeMap<usize, usize> {
-    let reg:BTreeMap<usize, usize> = BTreeMap::new();
-    reg
+    (0..size).map(|index| (index, 0)).collect()
+}
+
+/// The integer a classical register holds, with bit `i` of the register as bit `i` of the integer
+/// and bits which are missing as zero, or `None` if it is too large for a `u64`.
+pub fn get_classical_reg_value(reg:&BTreeMap<usize, usize>) -> Option<u64> {
+    let mut value = 0;
+    for (index, bit) in reg {
+        match (*index, *bit) {
+            (_, 0) => {},
+            (index, _) if index < 64 => value |= 1 << index,
+            _ => return None
+        }
+    }
+    Some(value)
+}
+
+#[derive(Clone)]
+pub struct ExecutionOptions {
+    tracer: Arc<dyn Tracer>,
+    threads: usize,
+    truncation: Option<Truncation>,
+    optimize: bool,
+    metrics: bool,
+    basis: Option<Vec<String>>,
+    coupling_map: Option<CouplingMap>
+}
+
+/// Initializes execution options which trace nothing and simulate the circuit as written, exactly,
+/// on a single thread.
+pub fn create_execution_options() -> ExecutionOptions {
+    ExecutionOptions{tracer: tracer::noop_tracer(), threads: 1, truncation: None, optimize: false, metrics: false, basis: None, coupling_map: None}
+}
+
+impl ExecutionOptions {
+
+    /// Sets the tracer notified of every operation on every quantum register.
+    pub fn set_tracer(&mut self, tracer:Arc<dyn Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Sets the number of threads each quantum register's kets are spread across.
+    pub fn set_threads(&mut self, threads:usize) {
+        self.threads = threads;
+    }
+
+    /// Gets the number of threads each quantum register's kets are spread across.
+    pub fn get_threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Sets the policy used to discard kets after each gate, or `None` to simulate exactly.
+    pub fn set_truncation(&mut self, truncation:Option<Truncation>) {
+        self.truncation = truncation;
+    }
+
+    /// Gets the policy used to discard kets after each gate.
+    pub fn get_truncation(&self) -> Option<Truncation> {
+        self.truncation
+    }
+
+    /// Sets whether circuits are run through `optimization::default_pass_manager` before they are
+    /// simulated.
+    pub fn set_optimize(&mut self, optimize:bool) {
+        self.optimize = optimize;
+    }
+
+    /// Gets whether circuits are optimized before they are simulated.
+    pub fn get_optimize(&self) -> bool {
+        self.optimize
+    }
+
+    /// Sets whether the metrics of each circuit, as simulated, are reported in the result.
+    pub fn set_metrics(&mut self, metrics:bool) {
+        self.metrics = metrics;
+    }
+
+    /// Gets whether the metrics of each circuit are reported in the result.
+    pub fn get_metrics(&self) -> bool {
+        self.metrics
+    }
+
+    /// Sets the basis gates circuits are transpiled to before they are optimized and simulated,
+    /// or `None` to simulate the gates as written.
+    pub fn set_basis(&mut self, basis:Option<Vec<String>>) {
+        self.basis = basis;
+    }
+
+    /// Gets the basis gates circuits are transpiled to.
+    pub fn get_basis(&self) -> Option<&[String]> {
+        self.basis.as_deref()
+    }
+
+    /// Sets the coupling map of the device circuits are routed onto after they are transpiled, or
+    /// `None` to let any two qubits interact.
+    pub fn set_coupling_map(&mut self, coupling_map:Option<CouplingMap>) {
+        self.coupling_map = coupling_map;
+    }
+
+    /// Gets the coupling map of the device circuits are routed onto.
+    pub fn get_coupling_map(&self) -> Option<&CouplingMap> {
+        self.coupling_map.as_ref()
+    }
+}
+
+/// The outcome of executing a QASM program.
+pub struct ExecutionResult {
+    classical_regs: BTreeMap<String, BTreeMap<usize, usize>>,
+    fidelity: f64,
+    optimization: Option<OptimizationReport>,
+    metrics: Option<Metrics>,
+    transpiled: Option<Circuit>,
+    routing: Option<Routing>
+}
+
+impl ExecutionResult {
+
+    /// The measured values of each classical register, by bit index.
+    pub fn get_classical_regs(&self) -> &BTreeMap<String, BTreeMap<usize, usize>> {
+        &self.classical_regs
+    }
+
+    /// A lower bound on the fidelity of the simulated state with the exact one. This is one
+    /// unless kets were discarded by a truncation policy.
+    pub fn get_fidelity(&self) -> f64 {
+        self.fidelity
+    }
+
+    /// The gate counts before and after each optimization pass, if the circuit was optimized.
+    pub fn get_optimization(&self) -> Option<&OptimizationReport> {
+        self.optimization.as_ref()
+    }
+
+    /// The metrics of the circuit as simulated, if they were asked for.
+    pub fn get_metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref()
+    }
+
+    /// The circuit as transpiled to the basis gates, if a basis was given.
+    pub fn get_transpiled(&self) -> Option<&Circuit> {
+        self.transpiled.as_ref()
+    }
+
+    /// The circuit as routed onto the device, with its layouts and swap count, if a coupling map
+    /// was given.
+    pub fn get_routing(&self) -> Option<&Routing> {
+        self.routing.as_ref()
+    }
+}
+
+/// Builds the unitary implemented by a measurement-free QASM program, indexed `[row][column]`.
+/// Qubits are numbered across registers in declaration order, and bit `i` of a row or column
+/// index is the value of qubit `i`.
+pub fn unitary_qasm(source:&str) -> Result<Vec<Vec<ComplexCoefficient>>, UnitaryError> {
+    let circuit = parse_qasm(source).map_err(|e| match e {
+        SimError::UndeclaredRegister(register) => UnitaryError::UndeclaredRegister(register),
+        SimError::IndexOutOfRange{register, index, ..} => UnitaryError::QubitOutOfRange(format!("{}[{}]", register, index)),
+        e => UnitaryError::Parse(e.to_string())
+    })?;
+    unitary::build_circuit_unitary(&circuit)
+}
+
+/// Computes the depth, gate counts and width of a QASM program without simulating it.
+pub fn metrics_qasm(source:&str) -> Result<Metrics, SimError> {
+    parse_qasm(source).map(|circuit| metrics::compute_metrics(&circuit))
+}
+
+/// Parses a QASM program into a circuit without simulating it. Programs headed `OPENQASM 3` are
+/// read by the OpenQASM 3 frontend, and all others as OpenQASM 2.0.
+pub fn parse_qasm(source:&str) -> Result<Circuit, SimError> {
+    if qasm3::is_qasm3(source) {
+        return qasm3::parse_qasm3(source).map_err(from_qasm3_error);
+    }
+    translate(source, &mut Err)
+}
+
+/// Reports an OpenQASM 3 error as the same `SimError` variant as the problem in OpenQASM 2.0 gets.
+fn from_qasm3_error(e:qasm3::Qasm3Error) -> SimError {
+    let (line, column, message) = (e.get_line(), e.get_column(), e.get_message().to_string());
+    match e.get_kind().clone() {
+        ErrorKind::Lex => SimError::Lex{line, column, message},
+        ErrorKind::Parse => SimError::Parse{line, column, message},
+        ErrorKind::UndeclaredRegister(register) => SimError::UndeclaredRegister(register),
+        ErrorKind::IndexOutOfRange{register, index, size} => SimError::IndexOutOfRange{register, index, size},
+        ErrorKind::DuplicateDeclaration(name) => SimError::DuplicateDeclaration(name),
+        ErrorKind::RepeatedQubit(gate) => SimError::RepeatedQubit(gate),
+        ErrorKind::UnsupportedGate(gate) => SimError::UnsupportedGate(gate),
+        ErrorKind::ArityMismatch => SimError::ArityMismatch(message),
+        ErrorKind::ResourceLimit => SimError::ResourceLimit(message)
+    }
+}
+
+/// Every problem which would stop a QASM program from being simulated, or none if it can be.
+/// Statements of an OpenQASM 2.0 program which cannot be translated are reported and skipped, and
+/// the circuit of the rest is checked by `validation::validate_circuit`. An OpenQASM 3 program, or
+/// one which cannot be lexed or parsed, has at most one problem reported.
+pub fn validate_qasm(source:&str) -> Vec<SimError> {
+    let mut diagnostics:Vec<SimError> = vec![];
+    let circuit = if qasm3::is_qasm3(source) {
+        parse_qasm(source)
+    }
+    else {
+        translate(source, &mut |e| {
+            diagnostics.push(e);
+            Ok(())
+        })
+    };
+    match circuit {
+        Ok(circuit) => diagnostics.extend(validation::validate_circuit(&circuit)),
+        Err(e) => diagnostics.push(e)
+    }
+    diagnostics
+}
+
+/// Translates an OpenQASM 2.0 program into a circuit, handing the error of each statement which
+/// cannot be translated to `on_error`, which either skips the statement or stops translation.
+fn translate(source:&str, on_error:&mut dyn FnMut(SimError) -> Result<(), SimError>) -> Result<Circuit, SimError> {
+    let (source, includes_qelib1) = preprocess(source)?;
+    let mut circuit = circuit::create_circuit();
+    let mut gates:HashMap<String, GateDefinition> = HashMap::new();
+    let mut nodes = if includes_qelib1 { parse_nodes(&format!("OPENQASM 2.0;\n{}", strip_comments(QELIB1_INC)))? } else { vec![] };
+    nodes.extend(parse_nodes(&source)?);
+    for node in nodes {
+        match parse_node(node, &mut circuit, &mut gates) {
+            Ok(instructions) => {
+                for instruction in instructions {
+                    circuit.push(instruction);
+                }
+            },
+            Err(e) => on_error(e)?
+        }
+        if circuit.get_instructions().len() > MAX_INSTRUCTIONS {
+            return Err(SimError::ResourceLimit(format!("program expands to more than {} instructions", MAX_INSTRUCTIONS)));
+        }
+    }
+    Ok(circuit)
+}
+
+/// Removes comments and includes, reporting whether `qelib1.inc` is included so that the built-in
+/// copy of the library can be read first. Other includes are rejected, since programs are not read
+/// from the filesystem. Lines are kept in place so that errors point into the original program.
+fn preprocess(source:&str) -> Result<(String, bool), SimError> {
+    let mut lines:Vec<&str> = vec![];
+    let mut includes_qelib1 = false;
+    let source = strip_comments(source);
+    for (number, line) in source.lines().enumerate() {
+        match line.trim().strip_prefix("include") {
+            Some(include) => {
+                let file = include.trim().trim_end_matches(';').trim().trim_matches('"');
+                if file != "qelib1.inc" {
+                    let column = line.chars().take_while(|c| c.is_whitespace()).count() + 1;
+                    return Err(SimError::Parse{line: number + 1, column, message: format!("cannot include {}", file)});
+                }
+                includes_qelib1 = true;
+                lines.push("");
+            },
+            None => lines.push(line)
+        }
+    }
+    Ok((lines.join("\n"), includes_qelib1))
 }
 
-pub fn execute_qasm(source:&str) -> BTreeMap<char, BTreeMap<usize, usize>> {
+fn strip_comments(source:&str) -> String {
+    source.lines().map(|line| line.split("//").next().unwrap_or_default()).collect::<Vec<&str>>().join("\n")
+}
 
+/// Lexes and parses a program into statements. The `qasm` crate neither reports where an error is
+/// nor survives every malformed program, so the program is first checked against the crate's own
+/// rules for tokens, and errors are placed at the token the crate failed on.
+fn parse_nodes(source:&str) -> Result<Vec<qasm::AstNode>, SimError> {
+    let positions = token_positions(source)?;
     let mut tokens = qasm::lex(source);
-    let mut ensemble:Ensemble = init_ensemble();
-    let mut classical_regs:BTreeMap<char, BTreeMap<usize, usize>> = BTreeMap::new();
-
-    println!("QASM received...");
-
-    match qasm::parse(&mut tokens) {
-        Ok(nodes) => {
-            let mut ast_nodes = nodes.clone();
-
-            for mut ast_node in ast_nodes {
-                match ast_node {
-                    qasm::AstNode::QReg(ref mut identifier, ref mut size) => {
-                        let id:Vec<char> = identifier.chars().collect();
-                        let state = init_state(*size as usize, id[0]);
-                        ensemble.subsystems.insert(id[0], state);
-                    },
-                    qasm::AstNode::CReg(ref mut identifier, ref mut size) => {
-                        let id:Vec<char> = identifier.chars().collect();
-                        let reg = init_classical_reg(*size as usize);
-                        classical_regs.insert(id[0], reg);
-                    },
-                    qasm::AstNode::Measure(ref mut source, ref mut dest) => {
-                        let mut classical_index:usize = 0;
-                        let mut classical_reg:char = 'c';
-                        let mut quantum_index:usize = 0;
-                        let mut quantum_reg:char = 'q';
-                        let mut proceed = true;
-
-                        match source {
-                            qasm::Argument::Qubit(ref mut identifier, ref mut index) => {
-                                let id:Vec<char> = identifier.chars().collect();
-                                quantum_reg = id[0];
-                                quantum_index = *index as usize;
-                            }
-                            qasm::Argument::Register(ref mut reg) => {
-                                println!("Unsupported full register gate on {}... skipping", *reg);
-                                proceed = false;
-                            }
-                            _ => {
-                                println!("Parser error in measurement!");
-                                proceed = false;
-                            }
-                        }
-                        match dest {
-                            qasm::Argument::Qubit(ref mut identifier, ref mut index) => {
-                                let id:Vec<char> = identifier.chars().collect();
-                                classical_reg = id[0];
-                                classical_index = *index as usize;
-                            }
-                            qasm::Argument::Register(ref mut reg) => {
-                                println!("Unsupported full register gate on {}... skipping", *reg);
-                                proceed = false;
-                            }
-                            _ => {
-                                println!("Parser error in measurement!");
-                                proceed = false;
-                            }
-                        }
-                        if proceed {
-                            let mut creg = classical_reg.clone();
-                            let result = ensemble.m(quantum_reg, quantum_index);
-
-                            let mut old_val = classical_regs.get(&creg);
-                            match old_val {
-                                Some(val) => {
-                                    let mut new_val = BTreeMap::new();
-                                    new_val = val.clone();
-                                    new_val.insert(classical_index, result as usize);
-                                    classical_regs.insert(creg, new_val.clone());
-                                }
-                                _ => {}
-                            }
-                        }
-                    },
-                    qasm::AstNode::ApplyGate(ref mut name, ref mut qubits, ref mut params) => {
-
-                        if name != "cx" {
-                            let mut i:usize = 0;
-                            let mut reg = 'q';
-                            let mut proceed = true;
-
-                            match qubits[0] {
-                                qasm::Argument::Qubit(ref mut identifier, ref mut index) => {
-                                    let id:Vec<char> = identifier.chars().collect();
-                                    reg = id[0];
-                                    i = *index as usize;
-                                }
-                                qasm::Argument::Register(ref mut reg) => {
-                                    println!("Unsupported full register gate on {}... skipping", *reg);
-                                    proceed = false;
-                                }
-                                _ => {
-                                    println!("Parser error in measurement!");
-                                    proceed = false;
-                                }
-                            }
-                            if proceed {
-                                let mut state = ensemble.subsystems.get(&reg);
-
-                                match state {
-                                    Some(q) => {
-                                        let mut new_state = q.clone();
-                                        if name == "h" {
-                                            new_state.h(i);
-                                        }
-                                        if name == "z" {
-                                            new_state.z(i);
-                                        }
-                                        if name == "y" {
-                                            new_state.y(i);
-                                        }
-                                        if name == "x" {
-                                            new_state.x(i);
-                                        }
-                                        ensemble.subsystems.insert(reg, new_state);
-                                    }
-                                    _ => {}
-                                }
-                            }
-                        }
-
-                        else {
-                            let mut first_i:usize = 0;
-                            let mut first_reg = 'q';
-                            let mut second_i:usize = 0;
-                            let mut second_reg = 'q';
-                            let mut proceed = true;
-
-                            match qubits[0] {
-                                qasm::Argument::Qubit(ref mut identifier, ref mut index) => {
-                                    let id:Vec<char> = identifier.chars().collect();
-                                    first_reg = id[0];
-                                    first_i = *index as usize;
-                                }
-                                qasm::Argument::Register(ref mut reg) => {
-                                    println!("Unsupported full register gate on {}... skipping", *reg);
-                                    proceed = false;
-                                }
-                                _ => {
-                                    println!("Parser error in measurement!");
-                                    proceed = false;
-                                }
-                            }
-                            match qubits[1] {
-                                qasm::Argument::Qubit(ref mut identifier, ref mut index) => {
-                                    let id:Vec<char> = identifier.chars().collect();
-                                    second_reg = id[0];
-                                    second_i = *index as usize;
-                                }
-                                qasm::Argument::Register(ref mut reg) => {
-                                    println!("Unsupported full register gate on {}... skipping", *reg);
-                                    proceed = false;
-                                }
-                                _ => {
-                                    println!("Parser error in measurement!");
-                                    proceed = false;
-                                }
-                            }
-                            if proceed {
-                                ensemble.cx(first_reg, first_i, second_reg, second_i);
-                            }
-                        }
-                    },
-                    _ => println!("Skipping unsupported operation"),
+    let end = source.lines().enumerate().last().map(|(number, line)| (number + 1, line.chars().count() + 1)).unwrap_or((1, 1));
+    let parse_error = |(line, column):(usize, usize), message:&str| SimError::Parse{line, column, message: message.to_string()};
+
+    // The crate reads past the end of programs which stop inside a statement or gate body.
+    let mut open:Vec<usize> = vec![];
+    for (index, token) in tokens.iter().enumerate() {
+        match token {
+            qasm::Token::LCParen => open.push(index),
+            qasm::Token::RCParen if open.pop().is_none() => return Err(parse_error(positions[index], "unmatched }")),
+            _ => {}
+        }
+    }
+    if let Some(index) = open.pop() {
+        return Err(parse_error(positions[index], "unclosed {"));
+    }
+    match tokens.last() {
+        None => return Err(parse_error(end, &qasm::Error::MissingVersion.to_string())),
+        Some(qasm::Token::Semicolon) | Some(qasm::Token::RCParen) => {},
+        Some(_) => return Err(parse_error(end, "unexpected end of program"))
+    }
+
+    // The crate removes each token as it reads it, so the error is at the last token it removed.
+    let total = tokens.len();
+    qasm::parse(&mut tokens).map_err(|e| parse_error(positions[(total - tokens.len()).saturating_sub(1)], &e.to_string()))
+}
+
+/// The line and column of each token of a program, following the rules of the `qasm` crate's
+/// lexer, which panics on a lone `=` or a malformed number rather than failing.
+fn token_positions(source:&str) -> Result<Vec<(usize, usize)>, SimError> {
+    let mut positions:Vec<(usize, usize)> = vec![];
+    for (number, line) in source.lines().enumerate() {
+        let chars:Vec<char> = line.chars().collect();
+        let mut index = 0;
+        while index < chars.len() {
+            let c = chars[index];
+            let position = (number + 1, index + 1);
+            let lex_error = |message:String| SimError::Lex{line: position.0, column: position.1, message};
+            let length = if c.is_whitespace() {
+                index += 1;
+                continue;
+            }
+            else if c == '=' {
+                if chars.get(index + 1) != Some(&'=') {
+                    return Err(lex_error("expected ==".to_string()));
                 }
+                2
+            }
+            else if c == '-' && chars.get(index + 1) == Some(&'>') {
+                2
+            }
+            else if "+-*/^;,()[]{}".contains(c) {
+                1
             }
+            else if c.is_alphabetic() || c == '_' {
+                chars[index..].iter().position(|c| !(c.is_alphanumeric() || *c == '_')).unwrap_or(chars.len() - index)
+            }
+            else if c.is_numeric() {
+                let length = chars[index..].iter().position(|c| !(c.is_numeric() || *c == '.')).unwrap_or(chars.len() - index);
+                let text:String = chars[index..index + length].iter().collect();
+                let valid = if text.contains('.') { text.parse::<f32>().is_ok() } else { text.parse::<i32>().is_ok() };
+                if !valid {
+                    return Err(lex_error(format!("invalid number {}", text)));
+                }
+                length
+            }
+            else {
+                return Err(lex_error(format!("unexpected character {}", c)));
+            };
+            positions.push(position);
+            index += length;
+        }
+    }
+    Ok(positions)
+}
+
+/// Adds a register declaration or gate definition, or translates a statement into instructions.
+fn parse_node(node:qasm::AstNode, circuit:&mut Circuit, gates:&mut HashMap<String, GateDefinition>) -> Result<Vec<Instruction>, SimError> {
+    match node {
+        qasm::AstNode::QReg(identifier, size) => {
+            check_undeclared(&identifier, circuit)?;
+            circuit.add_qreg(&identifier, size as usize);
+            Ok(vec![])
+        },
+        qasm::AstNode::CReg(identifier, size) => {
+            check_undeclared(&identifier, circuit)?;
+            circuit.add_creg(&identifier, size as usize);
+            Ok(vec![])
+        },
+        qasm::AstNode::Gate(name, qubits, params, body) => {
+            if gates.contains_key(&name) {
+                return Err(SimError::DuplicateDeclaration(name));
+            }
+            gates.insert(name, GateDefinition{params, qubits, body});
+            Ok(vec![])
+        },
+        qasm::AstNode::Measure(source, dest) => {
+            let qubits = parse_bits(source, circuit.get_qregs())?;
+            let clbits = parse_bits(dest, circuit.get_cregs())?;
+            if qubits.len() != clbits.len() {
+                return Err(SimError::ArityMismatch(format!("cannot measure {} qubits into {} bits", qubits.len(), clbits.len())));
+            }
+            Ok(qubits.into_iter().zip(clbits).map(|(qubit, clbit)| circuit::create_measurement(qubit, clbit)).collect())
+        },
+        qasm::AstNode::Reset(argument) => Ok(parse_bits(argument, circuit.get_qregs())?.into_iter().map(circuit::create_reset).collect()),
+        qasm::AstNode::Barrier(argument) => Ok(vec![circuit::create_barrier(parse_bits(argument, circuit.get_qregs())?)]),
+        qasm::AstNode::ApplyGate(name, arguments, params) => {
+            let mut qubits:Vec<Vec<Bit>> = vec![];
+            for argument in arguments {
+                qubits.push(parse_bits(argument, circuit.get_qregs())?);
+            }
+            let angles = evaluate_params(&name, &params, &HashMap::new())?;
+            let mut instructions:Vec<Instruction> = vec![];
+            for qubits in broadcast(&name, qubits)? {
+                apply_gate(&name, qubits, angles.clone(), gates, 0, &mut instructions)?;
+            }
+            Ok(instructions)
+        },
+        qasm::AstNode::If(register, value, node) => {
+            if !circuit.get_cregs().iter().any(|creg| creg.get_name() == register) {
+                return Err(SimError::UndeclaredRegister(register));
+            }
+            let mut instructions = parse_node(*node, circuit, gates)?;
+            for instruction in &mut instructions {
+                instruction.set_condition(Some(circuit::create_condition(&register, value as u64)));
+            }
+            Ok(instructions)
+        },
+        // Opaque gates have no definition, so applying one fails once the circuit is simulated.
+        _ => Ok(vec![])
+    }
+}
+
+/// Fails if a register of the name is already declared. Quantum and classical registers share
+/// one namespace.
+fn check_undeclared(name:&str, circuit:&Circuit) -> Result<(), SimError> {
+    if circuit.get_qregs().iter().chain(circuit.get_cregs()).any(|register| register.get_name() == name) {
+        return Err(SimError::DuplicateDeclaration(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Adds the instructions applying a gate. Gates the simulator implements are applied as they are,
+/// so a definition is only expanded, with its parameters and qubits bound to the arguments, for
+/// others.
+fn apply_gate(name:&str, qubits:Vec<Bit>, params:Vec<f64>, gates:&HashMap<String, GateDefinition>, depth:usize, instructions:&mut Vec<Instruction>) -> Result<(), SimError> {
+    // The built-in gates of OpenQASM 2.0.
+    let name = match name {
+        "U" => "u",
+        "CX" => "cx",
+        name => name
+    };
+    let definition = match gates.get(name) {
+        Some(definition) if gate::get_signature(name) != Some((qubits.len(), params.len())) => definition,
+        _ => {
+            if instructions.len() == MAX_INSTRUCTIONS {
+                return Err(SimError::ResourceLimit(format!("program expands to more than {} instructions", MAX_INSTRUCTIONS)));
+            }
+            instructions.push(circuit::create_instruction(name, qubits, params));
+            return Ok(());
         }
-        Err(e) => println!("Error parsing qasm: {}", e)
     };
-    classical_regs
+    if definition.params.len() != params.len() || definition.qubits.len() != qubits.len() {
+        return Err(SimError::ArityMismatch(format!("gate {} takes {} parameters and {} qubits", name, definition.params.len(), definition.qubits.len())));
+    }
+    if depth == MAX_GATE_DEPTH {
+        return Err(SimError::ResourceLimit(format!("gate definitions are nested more than {} deep", MAX_GATE_DEPTH)));
+    }
+
+    let bindings:HashMap<&str, f64> = definition.params.iter().map(|param| param.as_str()).zip(params).collect();
+    let aliases:HashMap<&str, &Bit> = definition.qubits.iter().map(|qubit| qubit.as_str()).zip(qubits.iter()).collect();
+    for node in &definition.body {
+        let (applied, arguments, applied_params) = match node {
+            qasm::AstNode::ApplyGate(applied, arguments, applied_params) => (applied, arguments, applied_params),
+            _ => return Err(SimError::Invalid(format!("gate {} can only apply gates", name)))
+        };
+        let mut applied_qubits:Vec<Bit> = vec![];
+        for argument in arguments {
+            match argument {
+                qasm::Argument::Register(identifier) if aliases.contains_key(identifier.as_str()) => applied_qubits.push(aliases[identifier.as_str()].clone()),
+                _ => return Err(SimError::Invalid(format!("gate {} applies {} to a qubit it does not declare", name, applied)))
+            }
+        }
+        let angles = evaluate_params(applied, applied_params, &bindings)?;
+        apply_gate(applied, applied_qubits, angles, gates, depth + 1, instructions)?;
+    }
+    Ok(())
+}
+
+fn evaluate_params(name:&str, params:&[String], bindings:&HashMap<&str, f64>) -> Result<Vec<f64>, SimError> {
+    let mut angles:Vec<f64> = vec![];
+    for param in params {
+        match expression::evaluate(param, bindings) {
+            Ok(angle) => angles.push(angle),
+            // The qasm crate separates the tokens of a parameter with spaces, as in `- pi / 2`.
+            Err(e) => return Err(SimError::Invalid(format!("could not evaluate parameter {} of {}: {}", param.split_whitespace().collect::<String>(), name, e)))
+        }
+    }
+    Ok(angles)
+}
+
+/// The bits an argument refers to: a single bit, or every bit of a register.
+fn parse_bits(argument:qasm::Argument, registers:&[Register]) -> Result<Vec<Bit>, SimError> {
+    match argument {
+        qasm::Argument::Qubit(identifier, index) => {
+            let bit = circuit::create_bit(&identifier, index as usize);
+            validation::check_bit(&bit, registers)?;
+            Ok(vec![bit])
+        },
+        qasm::Argument::Register(identifier) => match registers.iter().find(|register| register.get_name() == identifier) {
+            Some(register) => Ok((0..register.get_size()).map(|index| circuit::create_bit(&identifier, index)).collect()),
+            None => Err(SimError::UndeclaredRegister(identifier))
+        }
+    }
+}
+
+/// The qubits of each application of a gate to its arguments. A gate applied to whole registers is
+/// applied once for each index of the registers, which must be the same size, alongside any single
+/// qubit arguments.
+fn broadcast(name:&str, arguments:Vec<Vec<Bit>>) -> Result<Vec<Vec<Bit>>, SimError> {
+    let size = arguments.iter().map(|qubits| qubits.len()).find(|size| *size != 1).unwrap_or(1);
+    if let Some(qubits) = arguments.iter().find(|qubits| qubits.len() != 1 && qubits.len() != size) {
+        return Err(SimError::ArityMismatch(format!("cannot apply {} to registers of {} and {} qubits", name, size, qubits.len())));
+    }
+    Ok((0..size).map(|index| {
+        arguments.iter().map(|qubits| qubits[if qubits.len() == 1 { 0 } else { index }].clone()).collect()
+    }).collect())
+}
+
+/// Executes a QASM program, returning the measured values of each classical register.
+pub fn execute_qasm(source:&str) -> Result<BTreeMap<String, BTreeMap<usize, usize>>, SimError> {
+    execute_qasm_with_options(source, &create_execution_options())
+}
+
+/// Executes a QASM program, reporting every operation on every quantum register to the tracer.
+pub fn execute_qasm_with_tracer(source:&str, tracer:Arc<dyn Tracer>) -> Result<BTreeMap<String, BTreeMap<usize, usize>>, SimError> {
+    let mut options = create_execution_options();
+    options.set_tracer(tracer);
+    execute_qasm_with_options(source, &options)
+}
+
+/// Executes a QASM program with the given tracing, threading and truncation options.
+pub fn execute_qasm_with_options(source:&str, options:&ExecutionOptions) -> Result<BTreeMap<String, BTreeMap<usize, usize>>, SimError> {
+    simulate_qasm(source, options).map(|result| result.classical_regs)
+}
+
+/// Executes a QASM program with the given options, reporting the fidelity bound of the simulation
+/// alongside the classical registers.
+pub fn simulate_qasm(source:&str, options:&ExecutionOptions) -> Result<ExecutionResult, SimError> {
+    execute_circuit(&parse_qasm(source)?, options)
+}
+
+/// Simulates a circuit from the all-zero state with the given options, transpiling, routing and
+/// then optimizing it first if they ask for it. Each quantum register is simulated as its own
+/// subsystem of an ensemble.
+pub fn execute_circuit(circuit:&Circuit, options:&ExecutionOptions) -> Result<ExecutionResult, SimError> {
+    let transpiled = match &options.basis {
+        Some(basis) => {
+            let basis:Vec<&str> = basis.iter().map(|gate| gate.as_str()).collect();
+            Some(transpiler::transpile(circuit, &basis).map_err(SimError::Transpile)?)
+        },
+        None => None
+    };
+    let circuit = transpiled.as_ref().unwrap_or(circuit);
+    let routing = match &options.coupling_map {
+        Some(coupling_map) => Some(routing::route(circuit, coupling_map).map_err(SimError::Routing)?),
+        None => None
+    };
+    let circuit = routing.as_ref().map(|routing| routing.get_circuit()).unwrap_or(circuit);
+    let mut result = if options.optimize {
+        let (optimized, report) = optimization::default_pass_manager().run(circuit);
+        let mut result = simulate_circuit(&optimized, options)?;
+        result.optimization = Some(report);
+        result
+    }
+    else {
+        simulate_circuit(circuit, options)?
+    };
+    result.transpiled = transpiled;
+    result.routing = routing;
+    Ok(result)
+}
+
+fn simulate_circuit(circuit:&Circuit, options:&ExecutionOptions) -> Result<ExecutionResult, SimError> {
+    if let Some(e) = validation::validate_circuit(circuit).into_iter().next() {
+        return Err(e);
+    }
+    let parallelism = parallel::create_parallelism(options.threads);
+    let mut ensemble:Ensemble = init_ensemble();
+    let mut classical_regs:BTreeMap<String, BTreeMap<usize, usize>> = BTreeMap::new();
+
+    for register in circuit.get_qregs() {
+        let mut state = init_state(register.get_size(), register.get_name());
+        state.set_tracer(options.tracer.clone());
+        state.set_parallelism(parallelism.clone());
+        state.set_truncation(options.truncation);
+        ensemble.subsystems.insert(register.get_name().to_string(), state);
+    }
+    for register in circuit.get_cregs() {
+        classical_regs.insert(register.get_name().to_string(), init_classical_reg(register.get_size()));
+    }
+
+    for instruction in circuit.get_instructions() {
+        let qubits = instruction.get_qubits();
+        if let Some(condition) = instruction.get_condition() {
+            let value = classical_regs.get(condition.get_register()).and_then(get_classical_reg_value);
+            if !value.is_some_and(|value| condition.is_satisfied(value)) {
+                continue;
+            }
+        }
+        match instruction.get_name() {
+            circuit::BARRIER => {},
+            circuit::RESET => {
+                // Flipping the qubit if it is measured as one leaves it in |0> and the rest of the
+                // ensemble as a reset would.
+                if ensemble.m(qubits[0].get_register(), qubits[0].get_index()) {
+                    if let Some(state) = ensemble.subsystems.get_mut(qubits[0].get_register()) {
+                        state.x(qubits[0].get_index());
+                    }
+                }
+            },
+            circuit::MEASURE => {
+                let result = ensemble.m(qubits[0].get_register(), qubits[0].get_index());
+                let clbit = &instruction.get_clbits()[0];
+                if let Some(reg) = classical_regs.get_mut(clbit.get_register()) {
+                    reg.insert(clbit.get_index(), result as usize);
+                }
+            },
+            "cx" if qubits.len() == 2 => {
+                ensemble.cx(qubits[0].get_register(), qubits[0].get_index(), qubits[1].get_register(), qubits[1].get_index());
+            },
+            name => {
+                if let Some(state) = ensemble.subsystems.get_mut(qubits[0].get_register()) {
+                    let indices = qubits.iter().map(|qubit| qubit.get_index()).collect();
+                    state.apply_gate(&gate::create_gate(name, indices, instruction.get_params().to_vec()));
+                }
+            }
+        }
+    }
+    let metrics = if options.metrics { Some(metrics::compute_metrics(circuit)) } else { None };
+    Ok(ExecutionResult{classical_regs, fidelity: ensemble.get_fidelity(), optimization: None, metrics, transpiled: None, routing: None})
 }
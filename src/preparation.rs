@@ -0,0 +1,89 @@
+//! # Preparation
+//! Synthesis of gate sequences which prepare an arbitrary state from |0...0>, following Möttönen et
+//! al., "Transformation of quantum states using uniformly controlled rotations" (2004).
+//!
+//! The magnitudes of the amplitudes are set by a cascade of uniformly controlled Y rotations, from
+//! the most significant qubit down, and their phases by a cascade of uniformly controlled Z
+//! rotations, from the least significant qubit up. Each uniformly controlled rotation over `k`
+//! controls is decomposed into 2^k single qubit rotations interleaved with 2^k CNOTs. The prepared
+//! state matches the target up to a global phase.
+
+use crate::coefficient::ComplexCoefficient;
+use crate::gate;
+use crate::gate::Gate;
+use crate::state::AmplitudeError;
+
+/// Rotations with angles smaller than this are left out of the synthesized sequence.
+pub const ANGLE_TOLERANCE:f64 = 1e-12;
+
+/// Synthesizes the gates which prepare the state with the given 2^n amplitudes from |0...0>,
+/// where bit `i` of an amplitude's index is the value of qubit `i`. The amplitudes are normalized
+/// before synthesis. Fails if the length is not a power of two or every amplitude is zero.
+pub fn prepare(amplitudes:&[ComplexCoefficient]) -> Result<Vec<Gate>, AmplitudeError> {
+    if !amplitudes.len().is_power_of_two() {
+        return Err(AmplitudeError::NotPowerOfTwo(amplitudes.len()));
+    }
+    let num_qubits = amplitudes.len().trailing_zeros() as usize;
+    let probabilities:Vec<f64> = amplitudes.iter().map(|amplitude| amplitude.to_probability()).collect();
+    let phases:Vec<f64> = amplitudes.iter().map(|amplitude| {
+        if amplitude.to_probability() == 0.0 { 0.0 } else { amplitude.argument() }
+    }).collect();
+
+    if probabilities.iter().sum::<f64>() == 0.0 {
+        return Err(AmplitudeError::ZeroNorm);
+    }
+
+    let mut gates:Vec<Gate> = vec![];
+
+    // Magnitudes: the target qubit's split is controlled on every more significant qubit.
+    for target in (0..num_qubits).rev() {
+        let angles:Vec<f64> = (0..1usize << (num_qubits - target - 1)).map(|controls| {
+            let mut weights = [0.0, 0.0];
+            for (index, probability) in probabilities.iter().enumerate() {
+                if index >> (target + 1) == controls {
+                    weights[(index >> target) & 1] += probability;
+                }
+            }
+            2.0*weights[1].sqrt().atan2(weights[0].sqrt())
+        }).collect();
+        uniformly_controlled_rotation("ry", target, &angles, &mut gates);
+    }
+
+    // Phases: each step fixes the relative phase on the target qubit and hands the mean phase of
+    // each pair down to the remaining, more significant qubits.
+    let mut phases = phases;
+    for target in 0..num_qubits {
+        let angles:Vec<f64> = phases.chunks(2).map(|pair| pair[1] - pair[0]).collect();
+        uniformly_controlled_rotation("rz", target, &angles, &mut gates);
+        phases = phases.chunks(2).map(|pair| (pair[0] + pair[1])/2.0).collect();
+    }
+
+    Ok(gates)
+}
+
+/// Appends the decomposition of a rotation on the target qubit whose angle depends on the value of
+/// every more significant qubit. `angles[c]` is the angle applied when those qubits, read as an
+/// integer with the qubit after the target least significant, equal `c`.
+fn uniformly_controlled_rotation(name:&str, target:usize, angles:&[f64], gates:&mut Vec<Gate>) {
+    if angles.iter().all(|angle| angle.abs() < ANGLE_TOLERANCE) {
+        return;
+    }
+    let num_controls = angles.len().trailing_zeros() as usize;
+    let size = angles.len();
+
+    for step in 0..size {
+        let gray = step ^ (step >> 1);
+        let theta = angles.iter().enumerate().map(|(controls, angle)| {
+            if (controls & gray).count_ones() % 2 == 0 { *angle } else { -*angle }
+        }).sum::<f64>() / size as f64;
+
+        if theta.abs() >= ANGLE_TOLERANCE {
+            gates.push(gate::create_gate(name, vec![target], vec![theta]));
+        }
+        if num_controls > 0 {
+            let next_gray = ((step + 1) % size) ^ (((step + 1) % size) >> 1);
+            let control = (gray ^ next_gray).trailing_zeros() as usize;
+            gates.push(gate::create_gate("cx", vec![target + 1 + control, target], vec![]));
+        }
+    }
+}
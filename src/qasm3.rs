@@ -0,0 +1,1731 @@
+//! # QASM 3
+//! A frontend for a subset of OpenQASM 3 which produces the same circuits as OpenQASM 2 programs,
+//! so that they are optimized, simulated and measured along the same path.
+//!
+//! Supported are `qubit[n]`/`bit[n]` declarations (and `qreg`/`creg`), `include "stdgates.inc"`,
+//! gate definitions, the gate modifiers `ctrl @`, `negctrl @`, `inv @` and `pow(k) @`, measurement
+//! in both its assignment and arrow forms, `reset`, `barrier`, `if`/`else`, `for` loops over ranges
+//! and sets, and `int`, `uint`, `float`, `angle` and `bool` variables with the usual arithmetic,
+//! bitwise, comparison and logical operators.
+//!
+//! The program is unrolled as it is read: loops are expanded and classical variables evaluated,
+//! so they can only depend on constants and other variables, not on measurement results. An `if`
+//! whose condition reads a `bit` register is instead kept for the simulator: its condition is
+//! evaluated for every value the register could hold, and each instruction of its branches is
+//! conditioned on each value for which the branch is taken.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::f64::consts::{E, PI};
+use std::fmt;
+use std::rc::Rc;
+use crate::circuit;
+use crate::circuit::{Bit, Circuit, Comparison, Condition, Instruction};
+use crate::expression;
+use crate::expression::ExpressionError;
+use crate::gate;
+use crate::parser::{MAX_GATE_DEPTH, MAX_INSTRUCTIONS};
+use crate::transpiler;
+
+/// The gates defined by `stdgates.inc`.
+pub const STDGATES:[&str; 32] = [
+    "p", "x", "y", "z", "h", "s", "sdg", "t", "tdg", "sx", "rx", "ry", "rz", "cx", "cy", "cz", "cp", "crx", "cry",
+    "crz", "ch", "swap", "ccx", "cswap", "cu", "CX", "phase", "cphase", "id", "u1", "u2", "u3"
+];
+
+/// The widest `bit` register a condition can read unless it compares the register with an integer,
+/// since any other condition is evaluated for every value of its register.
+pub const MAX_CONDITION_BITS:usize = 8;
+
+/// How many loop iterations, gate applications and emitted instructions a program may take to
+/// elaborate in total, which bounds loops nested inside one another. A loop emitting one
+/// instruction per iteration can still reach `MAX_INSTRUCTIONS`.
+pub const MAX_STEPS:usize = 2*MAX_INSTRUCTIONS;
+
+/// The gates which modifiers are applied to exactly: every gate the simulator implements, so that
+/// only multi-qubit gates without a controlled form, such as `swap`, are decomposed.
+const EXACT_BASIS:[&str; 20] = [
+    "cx", "id", "x", "y", "z", "h", "s", "sdg", "t", "tdg", "sx", "sxdg", "rx", "ry", "rz", "p", "u1", "u2", "u3", "u"
+];
+
+const SYMBOLS:[&str; 45] = [
+    "<<=", ">>=", "**", "==", "!=", "<=", ">=", "&&", "||", "<<", ">>", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=",
+    "->", ";", ",", "(", ")", "[", "]", "{", "}", ":", "@", "=", "<", ">", "+", "-", "*", "/", "%", "!", "~", "&",
+    "|", "^", ".", "#"
+];
+
+const UNSUPPORTED_KEYWORDS:[&str; 20] = [
+    "def", "while", "switch", "box", "let", "input", "output", "defcal", "cal", "defcalgrammar", "break", "continue",
+    "return", "delay", "duration", "stretch", "complex", "array", "extern", "gphase"
+];
+
+/// An error in a program, at the position of the statement or token it was found in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Qasm3Error {
+    kind: ErrorKind,
+    line: usize,
+    column: usize,
+    message: String
+}
+
+/// The kinds of error `parser::parse_qasm` reports as their own `SimError` variants, as it does
+/// for OpenQASM 2.0 programs.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// A character or number which cannot be read.
+    Lex,
+    /// A statement which does not follow the grammar, or any other mistake in the program.
+    Parse,
+    /// A qubit or bit register which was never declared.
+    UndeclaredRegister(String),
+    /// A bit beyond the end of its register.
+    IndexOutOfRange { register: String, index: usize, size: usize },
+    /// A register, variable or gate declared under a name which is already taken.
+    DuplicateDeclaration(String),
+    /// The gate applied to the same qubit more than once.
+    RepeatedQubit(String),
+    /// A gate which is not defined.
+    UnsupportedGate(String),
+    /// A gate or measurement applied to the wrong number of qubits, bits or parameters.
+    ArityMismatch,
+    /// A program which unrolls to more than the simulator will run.
+    ResourceLimit
+}
+
+impl Qasm3Error {
+
+    /// What kind of error it is.
+    pub fn get_kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// The line of the error, counting from one.
+    pub fn get_line(&self) -> usize {
+        self.line
+    }
+
+    /// The column of the error, counting from one.
+    pub fn get_column(&self) -> usize {
+        self.column
+    }
+
+    /// What is wrong.
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Qasm3Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for Qasm3Error {}
+
+fn error<T>(position:Position, message:String) -> Result<T, Qasm3Error> {
+    error_of(ErrorKind::Parse, position, message)
+}
+
+fn error_of<T>(kind:ErrorKind, position:Position, message:String) -> Result<T, Qasm3Error> {
+    Err(Qasm3Error{kind, line: position.0, column: position.1, message})
+}
+
+/// Whether a program declares itself to be OpenQASM 3.
+pub fn is_qasm3(source:&str) -> bool {
+    let tokens = match lex(source) {
+        Ok(tokens) => tokens,
+        // Let the frontend report where the program cannot be read.
+        Err(_) => return source.trim_start().starts_with("OPENQASM 3")
+    };
+    matches!(
+        &tokens[..],
+        [Spanned{token: Token::Identifier(keyword), ..}, version, ..] if keyword == "OPENQASM" && (version.text == "3" || version.text.starts_with("3."))
+    )
+}
+
+/// Parses an OpenQASM 3 program into a circuit.
+pub fn parse_qasm3(source:&str) -> Result<Circuit, Qasm3Error> {
+    let tokens = lex(source)?;
+    let statements = Parser{tokens, position: 0, nesting: 0, depth: 0}.parse_program()?;
+    let mut elaborator = Elaborator{
+        circuit: circuit::create_circuit(),
+        output: vec![],
+        scopes: vec![HashMap::new()],
+        gates: HashMap::new(),
+        stdgates: false,
+        aliases: HashMap::new(),
+        depth: 0,
+        branch: None,
+        register_value: None,
+        steps: MAX_STEPS
+    };
+    elaborator.elaborate_all(&statements)?;
+    let mut circuit = elaborator.circuit;
+    circuit.set_instructions(elaborator.output);
+    Ok(circuit)
+}
+
+/// A line and column, counting from one.
+type Position = (usize, usize);
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Token {
+    Identifier(String),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+    Symbol(&'static str),
+    End
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Spanned {
+    pub(crate) token: Token,
+    pub(crate) text: String,
+    pub(crate) position: Position
+}
+
+pub(crate) fn lex(source:&str) -> Result<Vec<Spanned>, Qasm3Error> {
+    let chars:Vec<char> = source.chars().collect();
+    let mut tokens:Vec<Spanned> = vec![];
+    let (mut index, mut line, mut column) = (0, 1, 1);
+    let advance = |index:&mut usize, line:&mut usize, column:&mut usize, count:usize| {
+        for _ in 0..count {
+            if chars[*index] == '\n' {
+                *line += 1;
+                *column = 1;
+            }
+            else {
+                *column += 1;
+            }
+            *index += 1;
+        }
+    };
+
+    while index < chars.len() {
+        let c = chars[index];
+        let position = (line, column);
+        let rest:String = chars[index..chars.len().min(index + 3)].iter().collect();
+        if c.is_whitespace() {
+            advance(&mut index, &mut line, &mut column, 1);
+        }
+        else if rest.starts_with("//") {
+            let length = chars[index..].iter().position(|c| *c == '\n').unwrap_or(chars.len() - index);
+            advance(&mut index, &mut line, &mut column, length);
+        }
+        else if rest.starts_with("/*") {
+            let text:String = chars[index..].iter().collect();
+            match text.find("*/") {
+                Some(end) => advance(&mut index, &mut line, &mut column, text[..end + 2].chars().count()),
+                None => return error_of(ErrorKind::Lex, position, "unterminated comment".to_string())
+            }
+        }
+        else if c.is_alphabetic() || c == '_' {
+            let length = chars[index..].iter().position(|c| !(c.is_alphanumeric() || *c == '_')).unwrap_or(chars.len() - index);
+            let text:String = chars[index..index + length].iter().collect();
+            tokens.push(Spanned{token: Token::Identifier(text.clone()), text, position});
+            advance(&mut index, &mut line, &mut column, length);
+        }
+        else if c.is_ascii_digit() || (c == '.' && chars.get(index + 1).map(|c| c.is_ascii_digit()).unwrap_or(false)) {
+            let mut length = 0;
+            let mut is_float = false;
+            while let Some(c) = chars.get(index + length) {
+                if c.is_ascii_digit() || *c == '_' {
+                    length += 1;
+                }
+                else if *c == '.' && !is_float {
+                    is_float = true;
+                    length += 1;
+                }
+                else if (*c == 'e' || *c == 'E') && chars.get(index + length + 1).map(|c| c.is_ascii_digit() || *c == '+' || *c == '-').unwrap_or(false) {
+                    is_float = true;
+                    length += 2;
+                }
+                else {
+                    break;
+                }
+            }
+            let text:String = chars[index..index + length].iter().filter(|c| **c != '_').collect();
+            let token = if is_float {
+                text.parse().map(Token::Float).ok()
+            }
+            else {
+                text.parse().map(Token::Integer).ok()
+            };
+            match token {
+                Some(token) => tokens.push(Spanned{token, text, position}),
+                None => return error_of(ErrorKind::Lex, position, format!("invalid number {}", text))
+            }
+            advance(&mut index, &mut line, &mut column, length);
+        }
+        else if c == '"' {
+            match chars[index + 1..].iter().position(|c| *c == '"') {
+                Some(length) => {
+                    let text:String = chars[index + 1..index + 1 + length].iter().collect();
+                    tokens.push(Spanned{token: Token::Text(text.clone()), text, position});
+                    advance(&mut index, &mut line, &mut column, length + 2);
+                },
+                None => return error_of(ErrorKind::Lex, position, "unterminated string".to_string())
+            }
+        }
+        else {
+            match SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+                Some(symbol) => {
+                    tokens.push(Spanned{token: Token::Symbol(symbol), text: symbol.to_string(), position});
+                    advance(&mut index, &mut line, &mut column, symbol.len());
+                },
+                None => return error_of(ErrorKind::Lex, position, format!("unexpected character {}", c))
+            }
+        }
+    }
+    tokens.push(Spanned{token: Token::End, text: String::new(), position: (line, column)});
+    Ok(tokens)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Type {
+    Int(Option<u32>),
+    Uint(Option<u32>),
+    Float,
+    Angle,
+    Bool
+}
+
+#[derive(Clone, Debug)]
+enum Expression {
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    Identifier(String),
+    Index(String, Box<Expression>),
+    Unary(&'static str, Box<Expression>),
+    Binary(&'static str, Box<Expression>, Box<Expression>),
+    Call(String, Vec<Expression>)
+}
+
+/// A reference to a register or to one of its bits.
+#[derive(Clone, Debug)]
+struct Operand {
+    name: String,
+    index: Option<Expression>
+}
+
+#[derive(Clone, Debug)]
+enum Modifier {
+    Inv,
+    Pow(Expression),
+    Ctrl(Option<Expression>),
+    NegCtrl(Option<Expression>)
+}
+
+#[derive(Clone, Debug)]
+enum Iteration {
+    Range(Expression, Option<Expression>, Expression),
+    Set(Vec<Expression>)
+}
+
+#[derive(Clone, Debug)]
+struct GateDefinition {
+    params: Vec<String>,
+    qubits: Vec<String>,
+    body: Vec<Statement>
+}
+
+#[derive(Clone, Debug)]
+enum Kind {
+    Include(String),
+    Qubits(String, Option<Expression>),
+    Bits(String, Option<Expression>, Option<Operand>),
+    Variable(Type, bool, String, Option<Expression>),
+    Assign(String, Option<Expression>, &'static str, Expression),
+    Measure(Operand, Operand),
+    Reset(Operand),
+    Barrier(Vec<Operand>),
+    Gate(Vec<Modifier>, String, Vec<Expression>, Vec<Operand>),
+    Definition(String, Rc<GateDefinition>),
+    If(Expression, Vec<Statement>, Vec<Statement>),
+    For(String, Iteration, Vec<Statement>)
+}
+
+#[derive(Clone, Debug)]
+struct Statement {
+    kind: Kind,
+    position: Position
+}
+
+struct Parser {
+    tokens: Vec<Spanned>,
+    position: usize,
+    /// How many operands being parsed the current one is nested in.
+    nesting: usize,
+    /// The depth of the expression parsed last, which bounds the recursion evaluating it.
+    depth: usize
+}
+
+impl Parser {
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position].token
+    }
+
+    fn peek_at(&self, offset:usize) -> &Token {
+        &self.tokens[(self.position + offset).min(self.tokens.len() - 1)].token
+    }
+
+    fn here(&self) -> Position {
+        self.tokens[self.position].position
+    }
+
+    fn next(&mut self) -> Spanned {
+        let token = self.tokens[self.position].clone();
+        if self.position + 1 < self.tokens.len() {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn is_symbol(&self, symbol:&str) -> bool {
+        matches!(self.peek(), Token::Symbol(s) if *s == symbol)
+    }
+
+    fn is_keyword(&self, keyword:&str) -> bool {
+        matches!(self.peek(), Token::Identifier(name) if name == keyword)
+    }
+
+    fn unexpected<T>(&self, expected:&str) -> Result<T, Qasm3Error> {
+        let found = match self.peek() {
+            Token::End => "end of program".to_string(),
+            _ => self.tokens[self.position].text.clone()
+        };
+        error(self.here(), format!("expected {}, found {}", expected, found))
+    }
+
+    fn expect_symbol(&mut self, symbol:&str) -> Result<(), Qasm3Error> {
+        if self.is_symbol(symbol) {
+            self.next();
+            Ok(())
+        }
+        else {
+            self.unexpected(&format!("`{}`", symbol))
+        }
+    }
+
+    fn expect_identifier(&mut self) -> Result<String, Qasm3Error> {
+        match self.peek().clone() {
+            Token::Identifier(name) => {
+                self.next();
+                Ok(name)
+            },
+            _ => self.unexpected("an identifier")
+        }
+    }
+
+    fn parse_program(&mut self) -> Result<Vec<Statement>, Qasm3Error> {
+        if self.is_keyword("OPENQASM") {
+            self.next();
+            match self.next().token {
+                Token::Integer(3) => {},
+                Token::Float(version) if version.floor() == 3.0 => {},
+                _ => return error(self.here(), "only OpenQASM 3 is supported".to_string())
+            }
+            self.expect_symbol(";")?;
+        }
+        let mut statements:Vec<Statement> = vec![];
+        while *self.peek() != Token::End {
+            statements.push(self.parse_statement()?);
+        }
+        Ok(statements)
+    }
+
+    /// A block in braces, or a single statement.
+    fn parse_body(&mut self) -> Result<Vec<Statement>, Qasm3Error> {
+        if !self.is_symbol("{") {
+            return Ok(vec![self.parse_statement()?]);
+        }
+        self.next();
+        let mut statements:Vec<Statement> = vec![];
+        while !self.is_symbol("}") {
+            if *self.peek() == Token::End {
+                return self.unexpected("`}`");
+            }
+            statements.push(self.parse_statement()?);
+        }
+        self.next();
+        Ok(statements)
+    }
+
+    fn parse_statement(&mut self) -> Result<Statement, Qasm3Error> {
+        let position = self.here();
+        let keyword = match self.peek().clone() {
+            Token::Identifier(keyword) => keyword,
+            _ => return self.unexpected("a statement")
+        };
+        let kind = match keyword.as_str() {
+            "include" => {
+                self.next();
+                let file = match self.next().token {
+                    Token::Text(file) => file,
+                    _ => return error(position, "expected a file name in quotes".to_string())
+                };
+                self.expect_symbol(";")?;
+                Kind::Include(file)
+            },
+            "qubit" | "qreg" | "bit" | "creg" => {
+                self.next();
+                let mut size = if keyword == "qubit" || keyword == "bit" { self.parse_designator()? } else { None };
+                let name = self.expect_identifier()?;
+                if keyword == "qreg" || keyword == "creg" {
+                    size = self.parse_designator()?;
+                }
+                let kind = if keyword == "qubit" || keyword == "qreg" {
+                    Kind::Qubits(name, size)
+                }
+                else if self.is_symbol("=") {
+                    self.next();
+                    if !self.is_keyword("measure") {
+                        return self.unexpected("`measure`");
+                    }
+                    self.next();
+                    Kind::Bits(name, size, Some(self.parse_operand()?))
+                }
+                else {
+                    Kind::Bits(name, size, None)
+                };
+                self.expect_symbol(";")?;
+                kind
+            },
+            "const" | "int" | "uint" | "float" | "angle" | "bool" => {
+                let constant = keyword == "const";
+                if constant {
+                    self.next();
+                }
+                let ty = self.parse_type()?;
+                let name = self.expect_identifier()?;
+                let value = if self.is_symbol("=") {
+                    self.next();
+                    Some(self.parse_expression()?)
+                }
+                else {
+                    None
+                };
+                if constant && value.is_none() {
+                    return error(position, format!("constant {} has no value", name));
+                }
+                self.expect_symbol(";")?;
+                Kind::Variable(ty, constant, name, value)
+            },
+            "gate" => {
+                self.next();
+                let name = self.expect_identifier()?;
+                let mut params:Vec<String> = vec![];
+                if self.is_symbol("(") {
+                    self.next();
+                    while !self.is_symbol(")") {
+                        params.push(self.expect_identifier()?);
+                        if !self.is_symbol(")") {
+                            self.expect_symbol(",")?;
+                        }
+                    }
+                    self.next();
+                }
+                let mut qubits = vec![self.expect_identifier()?];
+                while self.is_symbol(",") {
+                    self.next();
+                    qubits.push(self.expect_identifier()?);
+                }
+                if !self.is_symbol("{") {
+                    return self.unexpected("`{`");
+                }
+                let body = self.parse_body()?;
+                Kind::Definition(name, Rc::new(GateDefinition{params, qubits, body}))
+            },
+            "if" => {
+                self.next();
+                self.expect_symbol("(")?;
+                let condition = self.parse_expression()?;
+                self.expect_symbol(")")?;
+                let then = self.parse_body()?;
+                let otherwise = if self.is_keyword("else") {
+                    self.next();
+                    self.parse_body()?
+                }
+                else {
+                    vec![]
+                };
+                Kind::If(condition, then, otherwise)
+            },
+            "for" => {
+                self.next();
+                if self.is_keyword("int") || self.is_keyword("uint") {
+                    self.parse_type()?;
+                }
+                let variable = self.expect_identifier()?;
+                if !self.is_keyword("in") {
+                    return self.unexpected("`in`");
+                }
+                self.next();
+                let iteration = if self.is_symbol("[") {
+                    self.next();
+                    let start = self.parse_expression()?;
+                    self.expect_symbol(":")?;
+                    let mut end = self.parse_expression()?;
+                    let mut step = None;
+                    if self.is_symbol(":") {
+                        self.next();
+                        step = Some(end);
+                        end = self.parse_expression()?;
+                    }
+                    self.expect_symbol("]")?;
+                    Iteration::Range(start, step, end)
+                }
+                else if self.is_symbol("{") {
+                    self.next();
+                    let mut values = vec![self.parse_expression()?];
+                    while self.is_symbol(",") {
+                        self.next();
+                        values.push(self.parse_expression()?);
+                    }
+                    self.expect_symbol("}")?;
+                    Iteration::Set(values)
+                }
+                else {
+                    return self.unexpected("a range or set");
+                };
+                Kind::For(variable, iteration, self.parse_body()?)
+            },
+            "measure" => {
+                self.next();
+                let qubits = self.parse_operand()?;
+                self.expect_symbol("->")?;
+                let clbits = self.parse_operand()?;
+                self.expect_symbol(";")?;
+                Kind::Measure(qubits, clbits)
+            },
+            "reset" => {
+                self.next();
+                let qubits = self.parse_operand()?;
+                self.expect_symbol(";")?;
+                Kind::Reset(qubits)
+            },
+            "barrier" => {
+                self.next();
+                let mut operands:Vec<Operand> = vec![];
+                while !self.is_symbol(";") {
+                    operands.push(self.parse_operand()?);
+                    if !self.is_symbol(";") {
+                        self.expect_symbol(",")?;
+                    }
+                }
+                self.next();
+                Kind::Barrier(operands)
+            },
+            "inv" | "pow" | "ctrl" | "negctrl" => self.parse_gate_call()?,
+            _ if UNSUPPORTED_KEYWORDS.contains(&keyword.as_str()) => return error(position, format!("unsupported statement {}", keyword)),
+            _ => match self.peek_at(1) {
+                Token::Identifier(_) | Token::Symbol("(") => self.parse_gate_call()?,
+                _ => self.parse_assignment()?
+            }
+        };
+        Ok(Statement{kind, position})
+    }
+
+    /// An optional size in square brackets.
+    fn parse_designator(&mut self) -> Result<Option<Expression>, Qasm3Error> {
+        if !self.is_symbol("[") {
+            return Ok(None);
+        }
+        self.next();
+        let size = self.parse_expression()?;
+        self.expect_symbol("]")?;
+        Ok(Some(size))
+    }
+
+    fn parse_type(&mut self) -> Result<Type, Qasm3Error> {
+        let position = self.here();
+        let name = self.expect_identifier()?;
+        let width = match self.parse_designator()? {
+            Some(Expression::Integer(width)) if width > 0 && width <= 64 => Some(width as u32),
+            Some(_) => return error(position, "type widths must be integers from 1 to 64".to_string()),
+            None => None
+        };
+        match name.as_str() {
+            "int" => Ok(Type::Int(width)),
+            "uint" => Ok(Type::Uint(width)),
+            "float" => Ok(Type::Float),
+            "angle" => Ok(Type::Angle),
+            "bool" => Ok(Type::Bool),
+            _ => error(position, format!("unsupported type {}", name))
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, Qasm3Error> {
+        let name = self.expect_identifier()?;
+        let index = if self.is_symbol("[") {
+            self.next();
+            let index = self.parse_expression()?;
+            self.expect_symbol("]")?;
+            Some(index)
+        }
+        else {
+            None
+        };
+        Ok(Operand{name, index})
+    }
+
+    fn parse_gate_call(&mut self) -> Result<Kind, Qasm3Error> {
+        let mut modifiers:Vec<Modifier> = vec![];
+        loop {
+            let modifier = if self.is_keyword("inv") {
+                self.next();
+                Modifier::Inv
+            }
+            else if self.is_keyword("pow") {
+                self.next();
+                self.expect_symbol("(")?;
+                let exponent = self.parse_expression()?;
+                self.expect_symbol(")")?;
+                Modifier::Pow(exponent)
+            }
+            else if self.is_keyword("ctrl") || self.is_keyword("negctrl") {
+                let negated = self.is_keyword("negctrl");
+                self.next();
+                let count = if self.is_symbol("(") {
+                    self.next();
+                    let count = self.parse_expression()?;
+                    self.expect_symbol(")")?;
+                    Some(count)
+                }
+                else {
+                    None
+                };
+                if negated { Modifier::NegCtrl(count) } else { Modifier::Ctrl(count) }
+            }
+            else {
+                break;
+            };
+            self.expect_symbol("@")?;
+            modifiers.push(modifier);
+        }
+
+        let name = self.expect_identifier()?;
+        let mut params:Vec<Expression> = vec![];
+        if self.is_symbol("(") {
+            self.next();
+            while !self.is_symbol(")") {
+                params.push(self.parse_expression()?);
+                if !self.is_symbol(")") {
+                    self.expect_symbol(",")?;
+                }
+            }
+            self.next();
+        }
+        let mut operands = vec![self.parse_operand()?];
+        while self.is_symbol(",") {
+            self.next();
+            operands.push(self.parse_operand()?);
+        }
+        self.expect_symbol(";")?;
+        Ok(Kind::Gate(modifiers, name, params, operands))
+    }
+
+    fn parse_assignment(&mut self) -> Result<Kind, Qasm3Error> {
+        let target = self.parse_operand()?;
+        let operator = match self.peek() {
+            Token::Symbol(symbol) if ["=", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "<<=", ">>="].contains(symbol) => *symbol,
+            _ => return self.unexpected("a gate, declaration or assignment")
+        };
+        self.next();
+        if self.is_keyword("measure") {
+            if operator != "=" {
+                return self.unexpected("`=`");
+            }
+            self.next();
+            let qubits = self.parse_operand()?;
+            self.expect_symbol(";")?;
+            return Ok(Kind::Measure(qubits, target));
+        }
+        let value = self.parse_expression()?;
+        self.expect_symbol(";")?;
+        Ok(Kind::Assign(target.name, target.index, operator, value))
+    }
+
+    fn parse_expression(&mut self) -> Result<Expression, Qasm3Error> {
+        self.parse_binary(0)
+    }
+
+    /// The depth of an expression whose deepest operand has the given depth, if it is within
+    /// `expression::MAX_DEPTH`.
+    fn nest(&self, depth:usize) -> Result<usize, Qasm3Error> {
+        if depth >= expression::MAX_DEPTH {
+            return error_of(ErrorKind::ResourceLimit, self.here(), ExpressionError::TooDeep.to_string());
+        }
+        Ok(depth + 1)
+    }
+
+    /// Parses binary operators by precedence climbing, from `||` binding least tightly to `*`
+    /// binding most. Only operators at the given level or above are read, and the right operand
+    /// of each is parsed at the level above it, so operators of one level associate to the left.
+    fn parse_binary(&mut self, level:usize) -> Result<Expression, Qasm3Error> {
+        const LEVELS:[&[&str]; 9] = [
+            &["||"], &["&&"], &["|"], &["^"], &["&"], &["==", "!="], &["<", "<=", ">", ">="], &["<<", ">>"], &["+", "-"]
+        ];
+        let mut left = self.parse_product()?;
+        let mut depth = self.depth;
+        while let Token::Symbol(symbol) = self.peek() {
+            let symbol = *symbol;
+            let operator_level = match LEVELS.iter().position(|operators| operators.contains(&symbol)) {
+                Some(operator_level) if operator_level >= level => operator_level,
+                _ => break
+            };
+            self.next();
+            let right = self.parse_binary(operator_level + 1)?;
+            depth = self.nest(depth.max(self.depth))?;
+            left = Expression::Binary(symbol, Box::new(left), Box::new(right));
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn parse_product(&mut self) -> Result<Expression, Qasm3Error> {
+        let mut left = self.parse_unary()?;
+        let mut depth = self.depth;
+        while let Token::Symbol(symbol) = self.peek() {
+            let symbol = *symbol;
+            if !["*", "/", "%"].contains(&symbol) {
+                break;
+            }
+            self.next();
+            let right = self.parse_unary()?;
+            depth = self.nest(depth.max(self.depth))?;
+            left = Expression::Binary(symbol, Box::new(left), Box::new(right));
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    /// Every nested operand is parsed here, which bounds how deeply the parser recurses.
+    fn parse_unary(&mut self) -> Result<Expression, Qasm3Error> {
+        self.nesting = self.nest(self.nesting)?;
+        let result = self.parse_signed();
+        self.nesting -= 1;
+        result
+    }
+
+    fn parse_signed(&mut self) -> Result<Expression, Qasm3Error> {
+        match self.peek() {
+            Token::Symbol(symbol) if ["-", "!", "~", "+"].contains(symbol) => {
+                let symbol = *symbol;
+                self.next();
+                let operand = self.parse_unary()?;
+                if symbol == "+" {
+                    return Ok(operand);
+                }
+                self.depth = self.nest(self.depth)?;
+                Ok(Expression::Unary(symbol, Box::new(operand)))
+            },
+            _ => self.parse_power()
+        }
+    }
+
+    /// Exponentiation, which is right associative and binds more tightly than unary minus.
+    fn parse_power(&mut self) -> Result<Expression, Qasm3Error> {
+        let base = self.parse_primary()?;
+        if self.is_symbol("**") {
+            let depth = self.depth;
+            self.next();
+            let exponent = self.parse_unary()?;
+            self.depth = self.nest(depth.max(self.depth))?;
+            return Ok(Expression::Binary("**", Box::new(base), Box::new(exponent)));
+        }
+        Ok(base)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, Qasm3Error> {
+        self.depth = 0;
+        match self.peek().clone() {
+            Token::Integer(value) => {
+                self.next();
+                Ok(Expression::Integer(value))
+            },
+            Token::Float(value) => {
+                self.next();
+                Ok(Expression::Float(value))
+            },
+            Token::Symbol("(") => {
+                self.next();
+                let expression = self.parse_expression()?;
+                self.expect_symbol(")")?;
+                Ok(expression)
+            },
+            Token::Identifier(name) => {
+                self.next();
+                if name == "true" || name == "false" {
+                    return Ok(Expression::Bool(name == "true"));
+                }
+                if self.is_symbol("(") {
+                    self.next();
+                    let mut arguments:Vec<Expression> = vec![];
+                    let mut depth = 0;
+                    while !self.is_symbol(")") {
+                        arguments.push(self.parse_expression()?);
+                        depth = depth.max(self.depth);
+                        if !self.is_symbol(")") {
+                            self.expect_symbol(",")?;
+                        }
+                    }
+                    self.next();
+                    self.depth = self.nest(depth)?;
+                    return Ok(Expression::Call(name, arguments));
+                }
+                if self.is_symbol("[") {
+                    self.next();
+                    let index = self.parse_expression()?;
+                    self.expect_symbol("]")?;
+                    self.depth = self.nest(self.depth)?;
+                    return Ok(Expression::Index(name, Box::new(index)));
+                }
+                Ok(Expression::Identifier(name))
+            },
+            _ => self.unexpected("an expression")
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool)
+}
+
+impl Value {
+
+    fn to_float(self) -> f64 {
+        match self {
+            Value::Int(value) => value as f64,
+            Value::Float(value) => value,
+            Value::Bool(value) => value as u8 as f64
+        }
+    }
+
+    fn to_int(self) -> Option<i64> {
+        match self {
+            Value::Int(value) => Some(value),
+            Value::Float(value) if value.fract() == 0.0 => Some(value as i64),
+            Value::Float(_) => None,
+            Value::Bool(value) => Some(value as i64)
+        }
+    }
+
+    fn to_bool(self) -> bool {
+        match self {
+            Value::Int(value) => value != 0,
+            Value::Float(value) => value != 0.0,
+            Value::Bool(value) => value
+        }
+    }
+}
+
+struct Variable {
+    ty: Type,
+    constant: bool,
+    value: Value
+}
+
+struct Elaborator {
+    circuit: Circuit,
+    output: Vec<Instruction>,
+    scopes: Vec<HashMap<String, Variable>>,
+    gates: HashMap<String, Rc<GateDefinition>>,
+    stdgates: bool,
+    /// The qubit arguments of the gate definition being expanded.
+    aliases: HashMap<String, Bit>,
+    depth: usize,
+    /// The register a branch being elaborated is conditioned on, the number of scopes outside
+    /// it, whose variables it may not assign, and the values of the register it runs for.
+    branch: Option<(String, usize, Vec<u64>)>,
+    /// The value a condition is being evaluated for.
+    register_value: Option<(String, u64)>,
+    /// How many more steps elaboration may take.
+    steps: usize
+}
+
+impl Elaborator {
+
+    fn elaborate_all(&mut self, statements:&[Statement]) -> Result<(), Qasm3Error> {
+        for statement in statements {
+            self.elaborate(statement)?;
+        }
+        Ok(())
+    }
+
+    fn elaborate_block(&mut self, statements:&[Statement]) -> Result<(), Qasm3Error> {
+        self.scopes.push(HashMap::new());
+        let result = self.elaborate_all(statements);
+        self.scopes.pop();
+        result
+    }
+
+    fn step(&mut self, position:Position) -> Result<(), Qasm3Error> {
+        if self.steps == 0 {
+            return error_of(ErrorKind::ResourceLimit, position, format!("program takes more than {} steps to unroll", MAX_STEPS));
+        }
+        self.steps -= 1;
+        Ok(())
+    }
+
+    fn emit(&mut self, instruction:Instruction, position:Position) -> Result<(), Qasm3Error> {
+        self.step(position)?;
+        if self.output.len() >= MAX_INSTRUCTIONS {
+            return error_of(ErrorKind::ResourceLimit, position, format!("program unrolls to more than {} instructions", MAX_INSTRUCTIONS));
+        }
+        self.output.push(instruction);
+        Ok(())
+    }
+
+    fn elaborate(&mut self, statement:&Statement) -> Result<(), Qasm3Error> {
+        let position = statement.position;
+        match &statement.kind {
+            Kind::Include(file) => {
+                if file != "stdgates.inc" {
+                    return error(position, format!("cannot include {}", file));
+                }
+                self.stdgates = true;
+            },
+            Kind::Qubits(name, size) => {
+                self.check_global(position)?;
+                let size = self.evaluate_size(size, position)?;
+                self.check_undeclared(name, position)?;
+                self.circuit.add_qreg(name, size);
+            },
+            Kind::Bits(name, size, measured) => {
+                self.check_global(position)?;
+                let size = self.evaluate_size(size, position)?;
+                self.check_undeclared(name, position)?;
+                self.circuit.add_creg(name, size);
+                if let Some(qubits) = measured {
+                    self.measure(qubits, &Operand{name: name.clone(), index: None}, position)?;
+                }
+            },
+            Kind::Variable(ty, constant, name, value) => {
+                self.check_undeclared(name, position)?;
+                let value = match value {
+                    Some(expression) => {
+                        let value = self.evaluate(expression, position)?;
+                        convert(*ty, value, position)?
+                    },
+                    None => convert(*ty, Value::Int(0), position)?
+                };
+                self.scopes.last_mut().unwrap().insert(name.clone(), Variable{ty: *ty, constant: *constant, value});
+            },
+            Kind::Assign(name, index, operator, expression) => self.assign(name, index, operator, expression, position)?,
+            Kind::Measure(qubits, clbits) => self.measure(qubits, clbits, position)?,
+            Kind::Reset(qubits) => {
+                for qubit in self.resolve_qubits(qubits, position)? {
+                    self.emit(circuit::create_reset(qubit), position)?;
+                }
+            },
+            Kind::Barrier(operands) => {
+                let mut qubits:Vec<Bit> = vec![];
+                for operand in operands {
+                    qubits.extend(self.resolve_qubits(operand, position)?);
+                }
+                if operands.is_empty() {
+                    qubits = self.circuit.get_qubits();
+                }
+                self.emit(circuit::create_barrier(qubits), position)?;
+            },
+            Kind::Gate(modifiers, name, params, operands) => self.call(modifiers, name, params, operands, position)?,
+            Kind::Definition(name, definition) => {
+                self.check_global(position)?;
+                if self.gates.contains_key(name) {
+                    return error_of(ErrorKind::DuplicateDeclaration(name.clone()), position, format!("gate {} is already defined", name));
+                }
+                self.gates.insert(name.clone(), definition.clone());
+            },
+            Kind::If(condition, then, otherwise) => self.branch(condition, then, otherwise, position)?,
+            Kind::For(variable, iteration, body) => {
+                let values:Vec<i64> = match iteration {
+                    Iteration::Range(start, step, end) => {
+                        let start = self.evaluate_int(start, position)?;
+                        let step = match step {
+                            Some(step) => self.evaluate_int(step, position)?,
+                            None => 1
+                        };
+                        let end = self.evaluate_int(end, position)?;
+                        if step == 0 {
+                            return error(position, "loop step cannot be zero".to_string());
+                        }
+                        // Ranges include their end. A range too long to count has too many iterations.
+                        let count = end.checked_sub(start).and_then(|distance| {
+                            if distance.signum() == -step.signum() { Some(0) } else { distance.checked_div(step)?.checked_add(1) }
+                        });
+                        match count {
+                            Some(count) if count as usize <= MAX_INSTRUCTIONS => (0..count).map(|iteration| start + iteration*step).collect(),
+                            _ => return error_of(ErrorKind::ResourceLimit, position, format!("loop has more than {} iterations", MAX_INSTRUCTIONS))
+                        }
+                    },
+                    Iteration::Set(values) => {
+                        let mut evaluated:Vec<i64> = vec![];
+                        for value in values {
+                            evaluated.push(self.evaluate_int(value, position)?);
+                        }
+                        evaluated
+                    }
+                };
+                for value in values {
+                    self.step(position)?;
+                    let mut scope = HashMap::new();
+                    scope.insert(variable.clone(), Variable{ty: Type::Int(None), constant: false, value: Value::Int(value)});
+                    self.scopes.push(scope);
+                    let result = self.elaborate_block(body);
+                    self.scopes.pop();
+                    result?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_global(&self, position:Position) -> Result<(), Qasm3Error> {
+        if self.scopes.len() > 1 || self.depth > 0 {
+            return error(position, "registers and gates can only be declared globally".to_string());
+        }
+        Ok(())
+    }
+
+    fn check_undeclared(&self, name:&str, position:Position) -> Result<(), Qasm3Error> {
+        let register = self.circuit.get_qregs().iter().chain(self.circuit.get_cregs()).any(|register| register.get_name() == name);
+        if register || self.scopes.last().unwrap().contains_key(name) || self.aliases.contains_key(name) {
+            return error_of(ErrorKind::DuplicateDeclaration(name.to_string()), position, format!("{} is already declared", name));
+        }
+        Ok(())
+    }
+
+    fn evaluate_size(&mut self, size:&Option<Expression>, position:Position) -> Result<usize, Qasm3Error> {
+        match size {
+            Some(size) => match self.evaluate_int(size, position)? {
+                size if size > 0 => Ok(size as usize),
+                size => error(position, format!("register size {} is not positive", size))
+            },
+            None => Ok(1)
+        }
+    }
+
+    fn assign(&mut self, name:&str, index:&Option<Expression>, operator:&str, expression:&Expression, position:Position) -> Result<(), Qasm3Error> {
+        if self.circuit.get_cregs().iter().any(|register| register.get_name() == name) {
+            return error(position, format!("bit register {} can only be assigned measurement results", name));
+        }
+        if index.is_some() {
+            return error(position, format!("{} is not a register", name));
+        }
+        let depth = match self.scopes.iter().rposition(|scope| scope.contains_key(name)) {
+            Some(depth) => depth,
+            None => return error(position, format!("undeclared variable {}", name))
+        };
+        if let Some((register, outer_scopes, _)) = &self.branch {
+            if depth < *outer_scopes {
+                return error(position, format!("cannot assign {} in a branch conditioned on measurements of {}", name, register));
+            }
+        }
+        let value = self.evaluate(expression, position)?;
+        let variable = &self.scopes[depth][name];
+        if variable.constant {
+            return error(position, format!("cannot assign constant {}", name));
+        }
+        let value = if operator == "=" {
+            value
+        }
+        else {
+            apply_binary(&operator[..operator.len() - 1], variable.value, value, position)?
+        };
+        let value = convert(variable.ty, value, position)?;
+        self.scopes[depth].get_mut(name).unwrap().value = value;
+        Ok(())
+    }
+
+    fn measure(&mut self, qubits:&Operand, clbits:&Operand, position:Position) -> Result<(), Qasm3Error> {
+        let qubits = self.resolve_qubits(qubits, position)?;
+        let clbits = self.resolve_clbits(clbits, position)?;
+        if qubits.len() != clbits.len() {
+            return error_of(ErrorKind::ArityMismatch, position, format!("cannot measure {} qubits into {} bits", qubits.len(), clbits.len()));
+        }
+        for (qubit, clbit) in qubits.into_iter().zip(clbits) {
+            if let Some((register, _, _)) = &self.branch {
+                if clbit.get_register() == register {
+                    return error(position, format!("cannot measure into {} in a branch conditioned on it", register));
+                }
+            }
+            self.emit(circuit::create_measurement(qubit, clbit), position)?;
+        }
+        Ok(())
+    }
+
+    /// Elaborates an `if`, either choosing a branch now or, if its condition reads a `bit`
+    /// register, conditioning each branch's instructions on the values which select it. A
+    /// comparison of the register with an integer, e.g. `c < 3`, becomes a single condition;
+    /// any other condition is evaluated for every value of the register. Within such a branch,
+    /// further conditions may only read the same register.
+    fn branch(&mut self, condition:&Expression, then:&[Statement], otherwise:&[Statement], position:Position) -> Result<(), Qasm3Error> {
+        let register = match self.get_condition_register(condition, position)? {
+            Some(register) => register,
+            None => {
+                let taken = if self.evaluate(condition, position)?.to_bool() { then } else { otherwise };
+                return self.elaborate_block(taken);
+            }
+        };
+        let size = self.circuit.get_cregs().iter().find(|creg| creg.get_name() == register).unwrap().get_size();
+        if self.branch.is_none() {
+            if let Some((comparison, value)) = self.get_comparison(condition, &register, position)? {
+                let outer_scopes = self.scopes.len();
+                for (statements, comparison) in [(then, comparison), (otherwise, comparison.negate())] {
+                    // Conditions nested in this one are evaluated for the values it runs for, and
+                    // are rejected before reading them if the register is too wide to list them.
+                    let values:Vec<u64> = match size {
+                        size if size <= MAX_CONDITION_BITS => (0..1u64 << size).filter(|register_value| comparison.compare(*register_value, value)).collect(),
+                        _ => vec![]
+                    };
+                    let conditions = vec![Some(circuit::create_comparison_condition(&register, comparison, value))];
+                    self.elaborate_conditioned(statements, &register, outer_scopes, values, &conditions, position)?;
+                }
+                return Ok(());
+            }
+        }
+        if size > MAX_CONDITION_BITS {
+            return error_of(ErrorKind::ResourceLimit, position, format!("conditions can only read bit registers of up to {} bits", MAX_CONDITION_BITS));
+        }
+        let (outer_scopes, possible) = match &self.branch {
+            Some((outer, _, _)) if *outer != register => {
+                return error(position, format!("a condition on {} cannot be nested in one on {}", register, outer));
+            },
+            Some((_, outer_scopes, possible)) => (*outer_scopes, possible.clone()),
+            None => (self.scopes.len(), (0..1u64 << size).collect())
+        };
+        let mut values:Vec<u64> = vec![];
+        for value in &possible {
+            self.register_value = Some((register.clone(), *value));
+            let result = self.evaluate(condition, position);
+            self.register_value = None;
+            if result?.to_bool() {
+                values.push(*value);
+            }
+        }
+        let others:Vec<u64> = possible.iter().cloned().filter(|value| !values.contains(value)).collect();
+
+        for (statements, values) in [(then, values), (otherwise, others)] {
+            let conditions:Vec<Option<Condition>> = match values.len() == 1usize << size {
+                true => vec![None],
+                false => values.iter().map(|value| Some(circuit::create_condition(&register, *value))).collect()
+            };
+            self.elaborate_conditioned(statements, &register, outer_scopes, values, &conditions, position)?;
+        }
+        Ok(())
+    }
+
+    /// Elaborates one side of a branch on a `bit` register, which runs for the given values of it,
+    /// and emits each of its instructions once under each of the conditions. Instructions of
+    /// nested branches are already conditioned on values within these, and are emitted as they are.
+    fn elaborate_conditioned(&mut self, statements:&[Statement], register:&str, outer_scopes:usize, values:Vec<u64>, conditions:&[Option<Condition>], position:Position) -> Result<(), Qasm3Error> {
+        let outer_output = std::mem::take(&mut self.output);
+        let outer_branch = self.branch.replace((register.to_string(), outer_scopes, values));
+        let result = self.elaborate_block(statements);
+        let instructions = std::mem::replace(&mut self.output, outer_output);
+        self.branch = outer_branch;
+        result?;
+        for instruction in instructions {
+            if instruction.get_condition().is_some() {
+                self.emit(instruction, position)?;
+                continue;
+            }
+            for condition in conditions {
+                let mut conditioned = instruction.clone();
+                conditioned.set_condition(condition.clone());
+                self.emit(conditioned, position)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The comparison and integer a condition compares its `bit` register with, if it is a
+    /// comparison of the register with a non-negative integer which does not read it, e.g. `c < 3`
+    /// or `2 != c`.
+    fn get_comparison(&self, condition:&Expression, register:&str, position:Position) -> Result<Option<(Comparison, u64)>, Qasm3Error> {
+        let (operator, left, right) = match condition {
+            Expression::Binary(operator, left, right) => (*operator, left, right),
+            _ => return Ok(None)
+        };
+        let comparison = match operator {
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            "<=" => Comparison::LessEqual,
+            ">" => Comparison::Greater,
+            ">=" => Comparison::GreaterEqual,
+            _ => return Ok(None)
+        };
+        let is_register = |expression:&Expression| matches!(expression, Expression::Identifier(name) if name == register);
+        let (comparison, value) = if is_register(left) {
+            (comparison, right)
+        }
+        else if is_register(right) {
+            // Written the other way round, e.g. `2 < c` is `c > 2`.
+            let comparison = match comparison {
+                Comparison::Less => Comparison::Greater,
+                Comparison::LessEqual => Comparison::GreaterEqual,
+                Comparison::Greater => Comparison::Less,
+                Comparison::GreaterEqual => Comparison::LessEqual,
+                comparison => comparison
+            };
+            (comparison, left)
+        }
+        else {
+            return Ok(None);
+        };
+        let mut reads_register = false;
+        collect_registers(value, &mut |name| reads_register |= name == register);
+        if reads_register {
+            return Ok(None);
+        }
+        Ok(match self.evaluate(value, position)? {
+            Value::Int(value) if value >= 0 => Some((comparison, value as u64)),
+            _ => None
+        })
+    }
+
+    /// The `bit` register a condition reads, if any. A condition may only read one.
+    fn get_condition_register(&self, expression:&Expression, position:Position) -> Result<Option<String>, Qasm3Error> {
+        let mut registers:Vec<String> = vec![];
+        collect_registers(expression, &mut |name| {
+            if self.find_variable(name).is_none() && self.circuit.get_cregs().iter().any(|register| register.get_name() == name) && !registers.iter().any(|register| register == name) {
+                registers.push(name.to_string());
+            }
+        });
+        match registers.len() {
+            0 => Ok(None),
+            1 => Ok(registers.pop()),
+            _ => error(position, format!("a condition can only read one bit register, not {}", registers.join(" and ")))
+        }
+    }
+
+    fn find_variable(&self, name:&str) -> Option<&Variable> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn evaluate_int(&mut self, expression:&Expression, position:Position) -> Result<i64, Qasm3Error> {
+        match self.evaluate(expression, position)?.to_int() {
+            Some(value) => Ok(value),
+            None => error(position, "expected an integer".to_string())
+        }
+    }
+
+    fn evaluate(&self, expression:&Expression, position:Position) -> Result<Value, Qasm3Error> {
+        match expression {
+            Expression::Integer(value) => Ok(Value::Int(*value)),
+            Expression::Float(value) => Ok(Value::Float(*value)),
+            Expression::Bool(value) => Ok(Value::Bool(*value)),
+            Expression::Identifier(name) => {
+                if let Some(variable) = self.find_variable(name) {
+                    return Ok(variable.value);
+                }
+                match (name.as_str(), &self.register_value) {
+                    ("pi" | "π", _) => Ok(Value::Float(PI)),
+                    ("tau" | "τ", _) => Ok(Value::Float(2.0*PI)),
+                    ("euler" | "ℇ", _) => Ok(Value::Float(E)),
+                    (_, Some((register, value))) if register == name => Ok(Value::Int(*value as i64)),
+                    _ if self.circuit.get_cregs().iter().any(|register| register.get_name() == name) => {
+                        error(position, format!("measurement results in {} can only be read by conditions", name))
+                    },
+                    _ => error(position, format!("undeclared identifier {}", name))
+                }
+            },
+            Expression::Index(name, index) => {
+                let index = match self.evaluate(index, position)?.to_int() {
+                    Some(index) => index,
+                    None => return error(position, "expected an integer index".to_string())
+                };
+                match (&self.register_value, self.find_variable(name)) {
+                    (_, Some(variable)) => match variable.value {
+                        Value::Int(value) if (0..64).contains(&index) => Ok(Value::Bool((value >> index) & 1 == 1)),
+                        _ => error(position, format!("cannot index {} with {}", name, index))
+                    },
+                    (Some((register, value)), None) if register == name => {
+                        let size = self.circuit.get_cregs().iter().find(|creg| creg.get_name() == register).unwrap().get_size() as i64;
+                        let index = if index < 0 { index + size } else { index };
+                        if index >= size {
+                            let kind = ErrorKind::IndexOutOfRange{register: name.clone(), index: index as usize, size: size as usize};
+                            return error_of(kind, position, format!("index {} is out of range for {}", index, name));
+                        }
+                        if index < 0 {
+                            return error(position, format!("index {} is out of range for {}", index, name));
+                        }
+                        Ok(Value::Bool((value >> index) & 1 == 1))
+                    },
+                    _ => error(position, format!("cannot read {}[{}] here", name, index))
+                }
+            },
+            Expression::Unary(operator, operand) => {
+                let value = self.evaluate(operand, position)?;
+                match (*operator, value) {
+                    ("-", Value::Float(value)) => Ok(Value::Float(-value)),
+                    ("-", value) => {
+                        let value = value.to_int().unwrap_or(0);
+                        match value.checked_neg() {
+                            Some(negated) => Ok(Value::Int(negated)),
+                            None => error(position, format!("-({}) overflows", value))
+                        }
+                    },
+                    ("!", value) => Ok(Value::Bool(!value.to_bool())),
+                    ("~", Value::Int(value)) => Ok(Value::Int(!value)),
+                    _ => error(position, format!("cannot apply {} to {:?}", operator, value))
+                }
+            },
+            Expression::Binary(operator, left, right) => {
+                let left = self.evaluate(left, position)?;
+                let right = self.evaluate(right, position)?;
+                apply_binary(operator, left, right, position)
+            },
+            Expression::Call(function, arguments) => {
+                if arguments.len() != 1 {
+                    return error(position, format!("{} takes one argument", function));
+                }
+                let argument = self.evaluate(&arguments[0], position)?.to_float();
+                match expression::apply_function(function, argument) {
+                    Some(value) => Ok(Value::Float(value)),
+                    None => error(position, format!("unknown function {}", function))
+                }
+            }
+        }
+    }
+
+    fn resolve_qubits(&self, operand:&Operand, position:Position) -> Result<Vec<Bit>, Qasm3Error> {
+        if let Some(qubit) = self.aliases.get(&operand.name) {
+            if operand.index.is_some() {
+                return error(position, format!("cannot index gate argument {}", operand.name));
+            }
+            return Ok(vec![qubit.clone()]);
+        }
+        if self.depth > 0 {
+            return error(position, format!("gate bodies can only use their arguments, not {}", operand.name));
+        }
+        match self.circuit.get_qregs().iter().find(|register| register.get_name() == operand.name) {
+            Some(register) => self.resolve(&operand.name, register.get_size(), &operand.index, position),
+            None => error_of(ErrorKind::UndeclaredRegister(operand.name.clone()), position, format!("undeclared qubit register {}", operand.name))
+        }
+    }
+
+    fn resolve_clbits(&self, operand:&Operand, position:Position) -> Result<Vec<Bit>, Qasm3Error> {
+        match self.circuit.get_cregs().iter().find(|register| register.get_name() == operand.name) {
+            Some(register) => self.resolve(&operand.name, register.get_size(), &operand.index, position),
+            None => error_of(ErrorKind::UndeclaredRegister(operand.name.clone()), position, format!("undeclared bit register {}", operand.name))
+        }
+    }
+
+    /// The bits of a register, or the one bit at the index. Negative indices count from the end.
+    fn resolve(&self, name:&str, size:usize, index:&Option<Expression>, position:Position) -> Result<Vec<Bit>, Qasm3Error> {
+        match index {
+            Some(index) => {
+                let index = match self.evaluate(index, position)?.to_int() {
+                    Some(index) => index,
+                    None => return error(position, "expected an integer index".to_string())
+                };
+                let resolved = if index < 0 { index + size as i64 } else { index };
+                if resolved < 0 || resolved >= size as i64 {
+                    let message = format!("index {} is out of range for {}[{}]", index, name, size);
+                    if index < 0 {
+                        return error(position, message);
+                    }
+                    return error_of(ErrorKind::IndexOutOfRange{register: name.to_string(), index: index as usize, size}, position, message);
+                }
+                Ok(vec![circuit::create_bit(name, resolved as usize)])
+            },
+            None => Ok((0..size).map(|index| circuit::create_bit(name, index)).collect())
+        }
+    }
+
+    /// Applies a gate to its operands, once for each qubit of any whole registers among them.
+    fn call(&mut self, modifiers:&[Modifier], name:&str, params:&[Expression], operands:&[Operand], position:Position) -> Result<(), Qasm3Error> {
+        let mut angles:Vec<f64> = vec![];
+        for param in params {
+            match expression::check_finite(self.evaluate(param, position)?.to_float()) {
+                Ok(angle) => angles.push(angle),
+                Err(e) => return error(position, format!("parameter of {}: {}", name, e))
+            }
+        }
+        let mut qubits:Vec<Vec<Bit>> = vec![];
+        for operand in operands {
+            qubits.push(self.resolve_qubits(operand, position)?);
+        }
+        let width = qubits.iter().map(|bits| bits.len()).max().unwrap_or(1);
+        if let Some(bits) = qubits.iter().find(|bits| bits.len() != 1 && bits.len() != width) {
+            return error_of(ErrorKind::ArityMismatch, position, format!("cannot apply {} to registers of {} and {} qubits", name, bits.len(), width));
+        }
+        for index in 0..width {
+            let arguments:Vec<Bit> = qubits.iter().map(|bits| bits[if bits.len() == 1 { 0 } else { index }].clone()).collect();
+            if arguments.iter().enumerate().any(|(position, qubit)| arguments[..position].contains(qubit)) {
+                return error_of(ErrorKind::RepeatedQubit(name.to_string()), position, format!("{} is applied to the same qubit more than once", name));
+            }
+            for instruction in self.apply(modifiers, name, &angles, &arguments, position)? {
+                self.emit(instruction, position)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The instructions of a modified gate. Each `ctrl` modifier takes its controls from the
+    /// front of the qubits, outermost modifier first, and the gate acts on the remainder.
+    fn apply(&mut self, modifiers:&[Modifier], name:&str, params:&[f64], qubits:&[Bit], position:Position) -> Result<Vec<Instruction>, Qasm3Error> {
+        let mut counts:Vec<usize> = vec![];
+        for modifier in modifiers {
+            counts.push(match modifier {
+                Modifier::Ctrl(count) | Modifier::NegCtrl(count) => match count {
+                    Some(count) => match self.evaluate_int(count, position)? {
+                        count if count > 0 => count as usize,
+                        count => return error(position, format!("cannot control a gate on {} qubits", count))
+                    },
+                    None => 1
+                },
+                _ => 0
+            });
+        }
+        let num_controls:usize = counts.iter().sum();
+        if qubits.len() < num_controls {
+            return error_of(ErrorKind::ArityMismatch, position, format!("{} has {} control qubits but only {} qubits", name, num_controls, qubits.len()));
+        }
+        let mut instructions = self.expand(name, params, &qubits[num_controls..], position)?;
+        let mut start = num_controls;
+        for (modifier, count) in modifiers.iter().zip(counts).rev() {
+            start -= count;
+            let controls = &qubits[start..start + count];
+            instructions = match modifier {
+                Modifier::Inv => invert(instructions, position)?,
+                Modifier::Pow(exponent) => {
+                    let exponent = self.evaluate(exponent, position)?;
+                    power(instructions, exponent, MAX_INSTRUCTIONS - self.output.len(), position)?
+                },
+                Modifier::Ctrl(_) => control(instructions, controls, position)?,
+                Modifier::NegCtrl(_) => {
+                    let flips:Vec<Instruction> = controls.iter().map(|control| circuit::create_instruction("x", vec![control.clone()], vec![])).collect();
+                    let mut negated = flips.clone();
+                    negated.extend(control(instructions, controls, position)?);
+                    negated.extend(flips);
+                    negated
+                }
+            };
+        }
+        Ok(instructions)
+    }
+
+    /// The instructions of an unmodified gate: a user-defined gate's body, or a built-in gate.
+    fn expand(&mut self, name:&str, params:&[f64], qubits:&[Bit], position:Position) -> Result<Vec<Instruction>, Qasm3Error> {
+        let instruction = |name:&str, qubits:&[Bit], params:Vec<f64>| circuit::create_instruction(name, qubits.to_vec(), params);
+        if let Some(definition) = self.gates.get(name).cloned() {
+            if definition.params.len() != params.len() || definition.qubits.len() != qubits.len() {
+                return error_of(ErrorKind::ArityMismatch, position, format!("gate {} takes {} parameters and {} qubits", name, definition.params.len(), definition.qubits.len()));
+            }
+            if self.depth >= MAX_GATE_DEPTH {
+                return error_of(ErrorKind::ResourceLimit, position, format!("gate definitions are nested more than {} deep", MAX_GATE_DEPTH));
+            }
+            self.step(position)?;
+            let scope:HashMap<String, Variable> = definition.params.iter().zip(params)
+                .map(|(param, value)| (param.clone(), Variable{ty: Type::Float, constant: true, value: Value::Float(*value)}))
+                .collect();
+            let aliases:HashMap<String, Bit> = definition.qubits.iter().cloned().zip(qubits.iter().cloned()).collect();
+            let outer_output = std::mem::take(&mut self.output);
+            let outer_aliases = std::mem::replace(&mut self.aliases, aliases);
+            let outer_scopes = std::mem::replace(&mut self.scopes, vec![scope]);
+            let outer_branch = self.branch.take();
+            self.depth += 1;
+            let result = self.elaborate_all(&definition.body);
+            self.depth -= 1;
+            self.branch = outer_branch;
+            self.scopes = outer_scopes;
+            self.aliases = outer_aliases;
+            let body = std::mem::replace(&mut self.output, outer_output);
+            result?;
+            return Ok(body);
+        }
+
+        let signature = match name {
+            "U" => Some((1, 3)),
+            "CX" => Some((2, 0)),
+            "phase" => Some((1, 1)),
+            "cphase" => Some((2, 1)),
+            "cu" => Some((2, 4)),
+            "cswap" => Some((3, 0)),
+            _ => gate::get_signature(name)
+        };
+        if name != "U" && !(self.stdgates && STDGATES.contains(&name)) {
+            let hint = if STDGATES.contains(&name) { " (is stdgates.inc included?)" } else { "" };
+            return error_of(ErrorKind::UnsupportedGate(format!("{}{}", name, hint)), position, format!("unknown gate {}{}", name, hint));
+        }
+        if signature != Some((qubits.len(), params.len())) {
+            let (num_qubits, num_params) = signature.unwrap_or((0, 0));
+            return error_of(ErrorKind::ArityMismatch, position, format!("gate {} takes {} parameters and {} qubits", name, num_params, num_qubits));
+        }
+        Ok(match name {
+            "U" => vec![instruction("u", qubits, params.to_vec())],
+            "CX" => vec![instruction("cx", qubits, vec![])],
+            "phase" => vec![instruction("p", qubits, params.to_vec())],
+            "cphase" => vec![instruction("cp", qubits, params.to_vec())],
+            // The phase of a controlled unitary is a phase on its control.
+            "cu" => vec![instruction("p", &qubits[..1], vec![params[3]]), instruction("cu3", qubits, params[..3].to_vec())],
+            "cswap" => vec![
+                instruction("cx", &[qubits[2].clone(), qubits[1].clone()], vec![]),
+                instruction("ccx", qubits, vec![]),
+                instruction("cx", &[qubits[2].clone(), qubits[1].clone()], vec![])
+            ],
+            _ => vec![instruction(name, qubits, params.to_vec())]
+        })
+    }
+}
+
+/// Converts a value to a variable's type, wrapping integers to the type's width.
+fn convert(ty:Type, value:Value, position:Position) -> Result<Value, Qasm3Error> {
+    match ty {
+        Type::Int(width) | Type::Uint(width) => {
+            let value = match value {
+                Value::Float(_) => return error(position, format!("cannot assign {:?} to an integer", value)),
+                value => value.to_int().unwrap()
+            };
+            Ok(Value::Int(match (ty, width) {
+                (_, None) | (_, Some(64)) => value,
+                (Type::Uint(_), Some(width)) => value & ((1i64 << width) - 1),
+                (_, Some(width)) => (value << (64 - width)) >> (64 - width)
+            }))
+        },
+        Type::Float | Type::Angle => Ok(Value::Float(value.to_float())),
+        Type::Bool => Ok(Value::Bool(value.to_bool()))
+    }
+}
+
+fn apply_binary(operator:&str, left:Value, right:Value, position:Position) -> Result<Value, Qasm3Error> {
+    let integers = match (left, right) {
+        (Value::Float(_), _) | (_, Value::Float(_)) => None,
+        _ => Some((left.to_int().unwrap(), right.to_int().unwrap()))
+    };
+    let (x, y) = (left.to_float(), right.to_float());
+    let value = match (operator, integers) {
+        ("+", Some((a, b))) => Value::Int(a.wrapping_add(b)),
+        ("-", Some((a, b))) => Value::Int(a.wrapping_sub(b)),
+        ("*", Some((a, b))) => Value::Int(a.wrapping_mul(b)),
+        ("/", Some((_, 0))) | ("%", Some((_, 0))) => return error(position, "division by zero".to_string()),
+        ("/", Some((a, b))) | ("%", Some((a, b))) => {
+            let value = if operator == "/" { a.checked_div(b) } else { a.checked_rem(b) };
+            match value {
+                Some(value) => Value::Int(value),
+                None => return error(position, format!("{} {} {} overflows", a, operator, b))
+            }
+        },
+        ("**", Some((a, b))) if b >= 0 => Value::Int(a.wrapping_pow(b.min(u32::MAX as i64) as u32)),
+        ("+", None) => Value::Float(x + y),
+        ("-", None) => Value::Float(x - y),
+        ("*", None) => Value::Float(x*y),
+        ("/", None) => Value::Float(x/y),
+        ("**", _) => Value::Float(x.powf(y)),
+        ("==", _) => Value::Bool(x == y),
+        ("!=", _) => Value::Bool(x != y),
+        ("<", _) => Value::Bool(x < y),
+        ("<=", _) => Value::Bool(x <= y),
+        (">", _) => Value::Bool(x > y),
+        (">=", _) => Value::Bool(x >= y),
+        ("&&", _) => Value::Bool(left.to_bool() && right.to_bool()),
+        ("||", _) => Value::Bool(left.to_bool() || right.to_bool()),
+        ("&", Some((a, b))) => Value::Int(a & b),
+        ("|", Some((a, b))) => Value::Int(a | b),
+        ("^", Some((a, b))) => Value::Int(a ^ b),
+        ("<<", Some((a, b))) => Value::Int(a.wrapping_shl(b as u32)),
+        (">>", Some((a, b))) => Value::Int(a.wrapping_shr(b as u32)),
+        _ => return error(position, format!("cannot apply {} to {:?} and {:?}", operator, left, right))
+    };
+    Ok(value)
+}
+
+/// Calls the function with the name of every identifier the expression reads.
+fn collect_registers<F>(expression:&Expression, found:&mut F) where F: FnMut(&str) {
+    match expression {
+        Expression::Identifier(name) => found(name),
+        Expression::Index(name, index) => {
+            found(name);
+            collect_registers(index, found);
+        },
+        Expression::Unary(_, operand) => collect_registers(operand, found),
+        Expression::Binary(_, left, right) => {
+            collect_registers(left, found);
+            collect_registers(right, found);
+        },
+        Expression::Call(_, arguments) => arguments.iter().for_each(|argument| collect_registers(argument, found)),
+        _ => {}
+    }
+}
+
+fn invert(instructions:Vec<Instruction>, position:Position) -> Result<Vec<Instruction>, Qasm3Error> {
+    let mut inverted:Vec<Instruction> = vec![];
+    for instruction in instructions.into_iter().rev() {
+        if !instruction.is_gate() {
+            inverted.push(instruction);
+            continue;
+        }
+        match gate::get_inverse(instruction.get_name(), instruction.get_params()) {
+            Some((name, params)) => inverted.push(circuit::create_instruction(&name, instruction.get_qubits().to_vec(), params)),
+            None => return error(position, format!("the inverse of {} is not known", instruction.get_name()))
+        }
+    }
+    Ok(inverted)
+}
+
+/// Repeats a gate a whole number of times, inverting it for negative exponents, or raises a
+/// rotation to any power by scaling its angle. Fails without repeating the gate if the repetitions
+/// would not fit in the number of instructions the program has left.
+fn power(instructions:Vec<Instruction>, exponent:Value, remaining:usize, position:Position) -> Result<Vec<Instruction>, Qasm3Error> {
+    if let Some(exponent) = exponent.to_int() {
+        if exponent.unsigned_abs().saturating_mul(instructions.len() as u64) > remaining as u64 {
+            return error_of(ErrorKind::ResourceLimit, position, format!("program unrolls to more than {} instructions", MAX_INSTRUCTIONS));
+        }
+        let instructions = if exponent < 0 { invert(instructions, position)? } else { instructions };
+        let repeated:Vec<Instruction> = (0..exponent.unsigned_abs()).flat_map(|_| instructions.iter().cloned()).collect();
+        return Ok(repeated);
+    }
+    let rotation = instructions.len() == 1 && match gate::split_controls(instructions[0].get_name()) {
+        Some((_, base)) => ["rx", "ry", "rz", "p", "u1"].contains(&base),
+        None => false
+    };
+    if !rotation {
+        return error(position, "only rotations can be raised to fractional powers".to_string());
+    }
+    let instruction = &instructions[0];
+    let angle = match expression::check_finite(instruction.get_params()[0]*exponent.to_float()) {
+        Ok(angle) => angle,
+        Err(e) => return error(position, format!("power of {}: {}", instruction.get_name(), e))
+    };
+    Ok(vec![circuit::create_instruction(instruction.get_name(), instruction.get_qubits().to_vec(), vec![angle])])
+}
+
+/// Adds the controls to every gate. Gates with no controlled form in the simulator, such as
+/// `swap`, are first decomposed exactly into ones which have.
+fn control(instructions:Vec<Instruction>, controls:&[Bit], position:Position) -> Result<Vec<Instruction>, Qasm3Error> {
+    let mut controlled:Vec<Instruction> = vec![];
+    for instruction in instructions {
+        if !instruction.is_gate() {
+            return error(position, format!("cannot control {}", instruction.get_name()));
+        }
+        if controls.iter().any(|control| instruction.get_qubits().contains(control)) {
+            return error(position, format!("control qubit is also a target of {}", instruction));
+        }
+        let name = format!("{}{}", "c".repeat(controls.len()), instruction.get_name());
+        let qubits:Vec<Bit> = controls.iter().cloned().chain(instruction.get_qubits().iter().cloned()).collect();
+        if gate::get_signature(&name) == Some((qubits.len(), instruction.get_params().len())) {
+            controlled.push(circuit::create_instruction(&name, qubits, instruction.get_params().to_vec()));
+            continue;
+        }
+        let mut single = circuit::create_circuit();
+        single.push(instruction.clone());
+        match transpiler::transpile(&single, &EXACT_BASIS) {
+            Ok(decomposed) if decomposed.get_instructions().len() > 1 || decomposed.get_instructions()[0] != instruction => {
+                controlled.extend(control(decomposed.get_instructions().to_vec(), controls, position)?);
+            },
+            _ => return error(position, format!("cannot control {}", instruction))
+        }
+    }
+    Ok(controlled)
+}
@@ -0,0 +1,469 @@
+//! # Routing
+//! Mapping of circuits onto devices whose qubits can only interact with their neighbours. A
+//! device is described by a coupling map, and two qubit gates between qubits which are not coupled
+//! are made possible by inserting SWAP gates, chosen with the SABRE heuristic: of the swaps
+//! touching the qubits of gates waiting to run, the one which most reduces the distance between
+//! the qubits of those gates, and to a lesser extent of the gates after them, is applied next.
+//! Swaps permute the qubits, so the routed circuit is equivalent to the original only up to the
+//! permutation between its initial and final layouts, which `verify_routing` checks.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use crate::circuit;
+use crate::circuit::{Bit, Circuit, Instruction};
+use crate::coefficient;
+use crate::coefficient::ComplexCoefficient;
+use crate::unitary;
+use crate::unitary::UnitaryError;
+
+/// The name of the single register of physical qubits in a routed circuit, followed by a number
+/// if the circuit has a classical register of that name, e.g. `q1`.
+pub const PHYSICAL_REGISTER:&str = "q";
+
+/// How many of the two qubit gates after the front layer are considered when scoring swaps.
+pub const EXTENDED_SET_SIZE:usize = 20;
+
+/// The weight of the extended set relative to the front layer when scoring swaps.
+pub const EXTENDED_SET_WEIGHT:f64 = 0.5;
+
+/// How much the score of swaps on a qubit is penalized each time it is swapped, which encourages
+/// swaps on different qubits to run in parallel.
+pub const DECAY_RATE:f64 = 0.001;
+
+/// Entries of the unitaries compared by `verify_routing` may differ by this much.
+pub const VERIFICATION_TOLERANCE:f64 = 1e-9;
+
+#[derive(Debug, PartialEq)]
+pub enum RoutingError {
+    /// The circuit has more qubits than the device.
+    TooFewPhysicalQubits(usize, usize),
+    /// The circuit has a gate on more than two qubits, which must be transpiled away first.
+    UnsupportedGate(String),
+    /// An instruction acts on a qubit which is not declared, e.g. `q[3]` of a two qubit register.
+    UndeclaredQubit(String),
+    /// Two qubits which the circuit makes interact have no path between them on the device.
+    Disconnected(usize, usize),
+    /// The initial layout does not assign each qubit its own physical qubit.
+    InvalidLayout(Vec<usize>),
+    /// A coupling map edge joins a qubit to itself or to a qubit the device does not have.
+    InvalidEdge(usize, usize)
+}
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoutingError::TooFewPhysicalQubits(needed, available) => write!(f, "circuit has {} qubits but the device only has {}", needed, available),
+            RoutingError::UnsupportedGate(gate) => write!(f, "cannot route gate {} on more than two qubits", gate),
+            RoutingError::UndeclaredQubit(qubit) => write!(f, "undeclared qubit {}", qubit),
+            RoutingError::Disconnected(first, second) => write!(f, "physical qubits {} and {} are not connected", first, second),
+            RoutingError::InvalidLayout(layout) => write!(f, "invalid initial layout {:?}", layout),
+            RoutingError::InvalidEdge(first, second) => write!(f, "cannot couple physical qubits {} and {}", first, second)
+        }
+    }
+}
+
+impl Error for RoutingError {}
+
+/// The pairs of physical qubits of a device on which two qubit gates can be applied, in either
+/// direction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CouplingMap {
+    num_qubits: usize,
+    edges: Vec<(usize, usize)>,
+    neighbours: Vec<Vec<usize>>,
+    distances: Vec<Vec<Option<usize>>>
+}
+
+/// Initializes a coupling map of the given number of physical qubits from its edges. Fails if an
+/// edge joins a qubit to itself or to a qubit beyond `num_qubits`.
+pub fn create_coupling_map(num_qubits:usize, edges:&[(usize, usize)]) -> Result<CouplingMap, RoutingError> {
+    let mut neighbours = vec![vec![]; num_qubits];
+    for (first, second) in edges {
+        if *first >= num_qubits || *second >= num_qubits || first == second {
+            return Err(RoutingError::InvalidEdge(*first, *second));
+        }
+        neighbours[*first].push(*second);
+        neighbours[*second].push(*first);
+    }
+    for adjacent in &mut neighbours {
+        adjacent.sort_unstable();
+        adjacent.dedup();
+    }
+
+    // Breadth first search from every qubit.
+    let mut distances = vec![vec![None; num_qubits]; num_qubits];
+    for (source, row) in distances.iter_mut().enumerate() {
+        row[source] = Some(0);
+        let mut queue = VecDeque::from(vec![source]);
+        while let Some(qubit) = queue.pop_front() {
+            let distance = row[qubit].unwrap();
+            for neighbour in &neighbours[qubit] {
+                if row[*neighbour].is_none() {
+                    row[*neighbour] = Some(distance + 1);
+                    queue.push_back(*neighbour);
+                }
+            }
+        }
+    }
+    Ok(CouplingMap{num_qubits, edges: edges.to_vec(), neighbours, distances})
+}
+
+/// Initializes the coupling map of qubits in a line, each coupled to the next.
+pub fn create_line_coupling_map(num_qubits:usize) -> CouplingMap {
+    let edges:Vec<(usize, usize)> = (1..num_qubits).map(|qubit| (qubit - 1, qubit)).collect();
+    create_coupling_map(num_qubits, &edges).expect("line edges join distinct qubits")
+}
+
+/// Initializes the coupling map of qubits in a ring, each coupled to the next and the last to
+/// the first.
+pub fn create_ring_coupling_map(num_qubits:usize) -> CouplingMap {
+    let mut edges:Vec<(usize, usize)> = (1..num_qubits).map(|qubit| (qubit - 1, qubit)).collect();
+    if num_qubits > 2 {
+        edges.push((num_qubits - 1, 0));
+    }
+    create_coupling_map(num_qubits, &edges).expect("ring edges join distinct qubits")
+}
+
+/// Initializes the coupling map of qubits in a grid, numbered row by row, each coupled to the
+/// qubits beside, above and below it.
+pub fn create_grid_coupling_map(rows:usize, columns:usize) -> CouplingMap {
+    let mut edges:Vec<(usize, usize)> = vec![];
+    for row in 0..rows {
+        for column in 0..columns {
+            let qubit = row*columns + column;
+            if column + 1 < columns {
+                edges.push((qubit, qubit + 1));
+            }
+            if row + 1 < rows {
+                edges.push((qubit, qubit + columns));
+            }
+        }
+    }
+    create_coupling_map(rows*columns, &edges).expect("grid edges join distinct qubits")
+}
+
+impl CouplingMap {
+
+    /// The number of physical qubits.
+    pub fn get_num_qubits(&self) -> usize {
+        self.num_qubits
+    }
+
+    /// The coupled pairs of physical qubits, as given.
+    pub fn get_edges(&self) -> &[(usize, usize)] {
+        &self.edges
+    }
+
+    /// Whether a two qubit gate can be applied to the physical qubits.
+    pub fn are_coupled(&self, first:usize, second:usize) -> bool {
+        self.neighbours[first].contains(&second)
+    }
+
+    /// The number of edges on a shortest path between two physical qubits, or `None` if there is
+    /// no path.
+    pub fn get_distance(&self, first:usize, second:usize) -> Option<usize> {
+        self.distances[first][second]
+    }
+}
+
+/// A circuit routed onto a device, with the physical qubit each of its qubits starts and ends on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Routing {
+    circuit: Circuit,
+    initial_layout: Vec<usize>,
+    final_layout: Vec<usize>,
+    num_swaps: usize
+}
+
+impl Routing {
+
+    /// The routed circuit, which has a single register of the device's physical qubits, named
+    /// after `PHYSICAL_REGISTER`, and the classical registers of the original.
+    pub fn get_circuit(&self) -> &Circuit {
+        &self.circuit
+    }
+
+    /// The physical qubit each qubit of the original circuit starts on, numbered across its
+    /// registers in declaration order. Entries beyond the circuit's qubits place the device's
+    /// unused qubits.
+    pub fn get_initial_layout(&self) -> &[usize] {
+        &self.initial_layout
+    }
+
+    /// The physical qubit each qubit of the original circuit ends on, after the swaps.
+    pub fn get_final_layout(&self) -> &[usize] {
+        &self.final_layout
+    }
+
+    /// The number of swaps inserted.
+    pub fn get_num_swaps(&self) -> usize {
+        self.num_swaps
+    }
+}
+
+/// Routes a circuit onto a device, choosing the initial layout by routing the circuit forwards
+/// and then backwards from the trivial layout and starting from wherever the qubits end up, so
+/// that the qubits of its first gates start close together.
+pub fn route(circuit:&Circuit, coupling_map:&CouplingMap) -> Result<Routing, RoutingError> {
+    let num_physical = coupling_map.get_num_qubits();
+    if circuit.num_qubits() > num_physical {
+        return Err(RoutingError::TooFewPhysicalQubits(circuit.num_qubits(), num_physical));
+    }
+    let instructions = get_logical_instructions(circuit)?;
+    let trivial:Vec<usize> = (0..num_physical).collect();
+    let (_, forward, _) = sabre(&instructions, coupling_map, &trivial, PHYSICAL_REGISTER)?;
+    let reversed:Vec<LogicalInstruction> = instructions.iter().rev().cloned().collect();
+    let (_, initial_layout, _) = sabre(&reversed, coupling_map, &forward, PHYSICAL_REGISTER)?;
+    route_instructions(circuit, &instructions, coupling_map, initial_layout)
+}
+
+/// Routes a circuit onto a device starting from the given layout, which assigns each qubit of
+/// the circuit, numbered across its registers in declaration order, a distinct physical qubit.
+pub fn route_with_layout(circuit:&Circuit, coupling_map:&CouplingMap, layout:&[usize]) -> Result<Routing, RoutingError> {
+    let num_physical = coupling_map.get_num_qubits();
+    if circuit.num_qubits() > num_physical {
+        return Err(RoutingError::TooFewPhysicalQubits(circuit.num_qubits(), num_physical));
+    }
+    if layout.len() != circuit.num_qubits() {
+        return Err(RoutingError::InvalidLayout(layout.to_vec()));
+    }
+    let mut used = vec![false; num_physical];
+    for physical in layout {
+        if *physical >= num_physical || used[*physical] {
+            return Err(RoutingError::InvalidLayout(layout.to_vec()));
+        }
+        used[*physical] = true;
+    }
+    // The device's remaining qubits are laid out after the circuit's, in order.
+    let mut initial_layout = layout.to_vec();
+    initial_layout.extend((0..num_physical).filter(|physical| !used[*physical]));
+    let instructions = get_logical_instructions(circuit)?;
+    route_instructions(circuit, &instructions, coupling_map, initial_layout)
+}
+
+/// Checks with the simulator that a routed circuit implements the same unitary as the original
+/// once its qubits are permuted by the initial and final layouts, with the device's unused qubits
+/// left untouched. Both circuits must be unitary and no wider than `unitary::MAX_UNITARY_QUBITS`.
+pub fn verify_routing(original:&Circuit, routing:&Routing) -> Result<bool, UnitaryError> {
+    let expected = unitary::build_circuit_unitary(original)?;
+    let actual = unitary::build_circuit_unitary(&routing.circuit)?;
+    let num_logical = original.num_qubits();
+    let logical_mask = (1usize << num_logical) - 1;
+    let permute = |index:usize, layout:&[usize]| -> usize {
+        layout.iter().enumerate().filter(|(logical, _)| (index >> logical) & 1 == 1).map(|(_, physical)| 1 << physical).sum()
+    };
+
+    for input in 0..actual.len() {
+        let column = permute(input, &routing.initial_layout);
+        for output in 0..actual.len() {
+            let entry = if input & !logical_mask == output & !logical_mask {
+                expected[output & logical_mask][input & logical_mask]
+            }
+            else {
+                coefficient::create_complex_coefficient_from_parts(0.0, 0.0)
+            };
+            if !is_close(entry, actual[permute(output, &routing.final_layout)][column]) {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+fn is_close(first:ComplexCoefficient, second:ComplexCoefficient) -> bool {
+    let mut difference = second;
+    difference.negate_magnitude();
+    first.add_to_complex_coefficient(difference).modulus() < VERIFICATION_TOLERANCE
+}
+
+/// An instruction with its qubits numbered across the circuit's registers.
+#[derive(Clone)]
+struct LogicalInstruction {
+    instruction: Instruction,
+    qubits: Vec<usize>,
+    /// The classical bits the instruction writes or is conditioned on, which order it against
+    /// other instructions on the same bits.
+    clbits: Vec<Bit>
+}
+
+fn get_logical_instructions(circuit:&Circuit) -> Result<Vec<LogicalInstruction>, RoutingError> {
+    let mut instructions:Vec<LogicalInstruction> = vec![];
+    for instruction in circuit.get_instructions() {
+        if instruction.is_gate() && instruction.get_qubits().len() > 2 {
+            return Err(RoutingError::UnsupportedGate(instruction.to_string()));
+        }
+        let mut qubits:Vec<usize> = vec![];
+        for qubit in instruction.get_qubits() {
+            match circuit.get_qubit_index(qubit) {
+                Some(index) => qubits.push(index),
+                None => return Err(RoutingError::UndeclaredQubit(qubit.to_string()))
+            }
+        }
+        let mut clbits = instruction.get_clbits().to_vec();
+        if let Some(condition) = instruction.get_condition() {
+            if let Some(register) = circuit.get_cregs().iter().find(|register| register.get_name() == condition.get_register()) {
+                clbits.extend((0..register.get_size()).map(|index| circuit::create_bit(register.get_name(), index)));
+            }
+        }
+        instructions.push(LogicalInstruction{instruction: instruction.clone(), qubits, clbits});
+    }
+    Ok(instructions)
+}
+
+fn route_instructions(circuit:&Circuit, instructions:&[LogicalInstruction], coupling_map:&CouplingMap, initial_layout:Vec<usize>) -> Result<Routing, RoutingError> {
+    let is_taken = |name:&str| circuit.get_cregs().iter().any(|register| register.get_name() == name);
+    let physical_register = (0..).map(|suffix| match suffix {
+        0 => PHYSICAL_REGISTER.to_string(),
+        _ => format!("{}{}", PHYSICAL_REGISTER, suffix)
+    }).find(|name| !is_taken(name)).unwrap();
+    let (routed, final_layout, num_swaps) = sabre(instructions, coupling_map, &initial_layout, &physical_register)?;
+    let mut routed_circuit = circuit::create_circuit();
+    routed_circuit.add_qreg(&physical_register, coupling_map.get_num_qubits());
+    for register in circuit.get_cregs() {
+        routed_circuit.add_creg(register.get_name(), register.get_size());
+    }
+    routed_circuit.set_instructions(routed);
+    Ok(Routing{circuit: routed_circuit, initial_layout, final_layout, num_swaps})
+}
+
+/// Routes the instructions from the given layout onto the named register of physical qubits,
+/// returning the routed instructions, the final layout and the number of swaps inserted.
+fn sabre(instructions:&[LogicalInstruction], coupling_map:&CouplingMap, initial_layout:&[usize], physical_register:&str) -> Result<(Vec<Instruction>, Vec<usize>, usize), RoutingError> {
+    let num_physical = coupling_map.get_num_qubits();
+    let mut layout = initial_layout.to_vec();
+    let physical_bit = |physical:usize| circuit::create_bit(physical_register, physical);
+
+    // Each instruction depends on the last earlier instruction on each of its qubits and bits.
+    let mut successors:Vec<Vec<usize>> = vec![vec![]; instructions.len()];
+    let mut remaining:Vec<usize> = vec![0; instructions.len()];
+    let mut last_on_qubit:HashMap<usize, usize> = HashMap::new();
+    let mut last_on_clbit:HashMap<Bit, usize> = HashMap::new();
+    for (index, instruction) in instructions.iter().enumerate() {
+        let mut predecessors:HashSet<usize> = HashSet::new();
+        for qubit in &instruction.qubits {
+            predecessors.extend(last_on_qubit.insert(*qubit, index));
+        }
+        for clbit in &instruction.clbits {
+            predecessors.extend(last_on_clbit.insert(clbit.clone(), index));
+        }
+        remaining[index] = predecessors.len();
+        for predecessor in predecessors {
+            successors[predecessor].push(index);
+        }
+    }
+
+    let mut front:Vec<usize> = (0..instructions.len()).filter(|index| remaining[*index] == 0).collect();
+    let mut routed:Vec<Instruction> = vec![];
+    let mut num_swaps = 0;
+    let mut decay:Vec<f64> = vec![1.0; num_physical];
+    let mut swaps_without_progress = 0;
+
+    while !front.is_empty() {
+        let is_two_qubit = |index:usize| instructions[index].instruction.is_gate() && instructions[index].qubits.len() == 2;
+        let physical = |layout:&[usize], index:usize| -> (usize, usize) {
+            let qubits = &instructions[index].qubits;
+            (layout[qubits[0]], layout[qubits[1]])
+        };
+
+        let (executable, blocked):(Vec<usize>, Vec<usize>) = front.iter().partition(|index| {
+            if !is_two_qubit(**index) {
+                return true;
+            }
+            let (first, second) = physical(&layout, **index);
+            coupling_map.are_coupled(first, second)
+        });
+        if !executable.is_empty() {
+            for index in &executable {
+                let mut instruction = instructions[*index].instruction.clone();
+                instruction.set_qubits(instructions[*index].qubits.iter().map(|qubit| physical_bit(layout[*qubit])).collect());
+                routed.push(instruction);
+                for successor in &successors[*index] {
+                    remaining[*successor] -= 1;
+                    if remaining[*successor] == 0 {
+                        front.push(*successor);
+                    }
+                }
+            }
+            front.retain(|index| !executable.contains(index));
+            front.sort_unstable();
+            decay.iter_mut().for_each(|value| *value = 1.0);
+            swaps_without_progress = 0;
+            continue;
+        }
+
+        for index in &blocked {
+            let (first, second) = physical(&layout, *index);
+            if coupling_map.get_distance(first, second).is_none() {
+                return Err(RoutingError::Disconnected(first, second));
+            }
+        }
+
+        // The two qubit gates which will follow the front layer.
+        let mut extended:Vec<usize> = vec![];
+        let mut visited:HashSet<usize> = HashSet::new();
+        let mut queue:Vec<usize> = blocked.clone();
+        while let Some(index) = queue.pop() {
+            for successor in &successors[index] {
+                if extended.len() < EXTENDED_SET_SIZE && visited.insert(*successor) {
+                    if is_two_qubit(*successor) {
+                        extended.push(*successor);
+                    }
+                    queue.push(*successor);
+                }
+            }
+        }
+
+        let swap = if swaps_without_progress > 2*num_physical {
+            // The heuristic is going in circles, so bring the first blocked gate's qubits together
+            // along a shortest path.
+            let (first, second) = physical(&layout, blocked[0]);
+            let distance = coupling_map.get_distance(first, second).unwrap();
+            let next = *coupling_map.neighbours[first].iter().find(|neighbour| coupling_map.get_distance(**neighbour, second) == Some(distance - 1)).unwrap();
+            (first, next)
+        }
+        else {
+            let mut candidates:Vec<(usize, usize)> = blocked.iter().flat_map(|index| {
+                let (first, second) = physical(&layout, *index);
+                vec![first, second].into_iter().flat_map(|qubit| coupling_map.neighbours[qubit].iter().map(move |neighbour| (qubit.min(*neighbour), qubit.max(*neighbour))))
+            }).collect();
+            candidates.sort_unstable();
+            candidates.dedup();
+
+            let score = |layout:&[usize], gates:&[usize]| -> f64 {
+                let total:usize = gates.iter().map(|index| {
+                    let (first, second) = physical(layout, *index);
+                    coupling_map.get_distance(first, second).unwrap_or(num_physical)
+                }).sum();
+                total as f64/gates.len().max(1) as f64
+            };
+            let mut best:Option<((usize, usize), f64)> = None;
+            for (first, second) in candidates {
+                let swapped:Vec<usize> = layout.iter().map(|physical| {
+                    if *physical == first { second } else if *physical == second { first } else { *physical }
+                }).collect();
+                let cost = decay[first].max(decay[second])*(score(&swapped, &blocked) + EXTENDED_SET_WEIGHT*score(&swapped, &extended));
+                if best.map(|(_, best_cost)| cost < best_cost).unwrap_or(true) {
+                    best = Some(((first, second), cost));
+                }
+            }
+            best.unwrap().0
+        };
+
+        let (first, second) = swap;
+        for physical in &mut layout {
+            if *physical == first {
+                *physical = second;
+            }
+            else if *physical == second {
+                *physical = first;
+            }
+        }
+        routed.push(circuit::create_instruction("swap", vec![physical_bit(first), physical_bit(second)], vec![]));
+        num_swaps += 1;
+        swaps_without_progress += 1;
+        decay[first] += DECAY_RATE;
+        decay[second] += DECAY_RATE;
+    }
+    Ok((routed, layout, num_swaps))
+}
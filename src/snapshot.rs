@@ -0,0 +1,152 @@
+//! # Snapshot
+//! Serialization of states, ensembles and classical registers so that simulations can be
+//! checkpointed and shipped between services. Snapshots are written as JSON or in a compact binary
+//! format, and are validated as they are loaded.
+
+extern crate bincode;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use crate::ensemble::Ensemble;
+use crate::state::State;
+
+/// How far the total probability of a loaded state may stray from one.
+pub const NORMALIZATION_TOLERANCE:f64 = 1e-6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Binary
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SnapshotError {
+    /// The snapshot could not be encoded or decoded.
+    Encoding(String),
+    /// A ket's qubit string does not match the number of qubits of its state.
+    QubitCount { system: String, expected: usize, found: usize },
+    /// The total probability of a state is not one.
+    NotNormalized { system: String, total_probability: f64 },
+    /// A subsystem is stored under a different name than its own symbol.
+    SymbolMismatch { key: String, symbol: String },
+    /// A ket depends on a qubit which is not part of the ensemble.
+    DanglingEntanglement { system: String, qubit: usize }
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Encoding(message) => write!(f, "could not encode or decode snapshot: {}", message),
+            SnapshotError::QubitCount{system, expected, found} => write!(f, "state {} has {} qubits but contains a ket of {} qubits", system, expected, found),
+            SnapshotError::NotNormalized{system, total_probability} => write!(f, "state {} is not normalized (total probability {})", system, total_probability),
+            SnapshotError::SymbolMismatch{key, symbol} => write!(f, "subsystem {} holds a state with symbol {}", key, symbol),
+            SnapshotError::DanglingEntanglement{system, qubit} => write!(f, "ket is entangled with non-existent qubit {}[{}]", system, qubit)
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+/// The full state of a running program: its quantum subsystems and classical registers.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub ensemble: Ensemble,
+    pub classical_regs: BTreeMap<String, BTreeMap<usize, usize>>
+}
+
+/// Initializes a snapshot of an ensemble and its classical registers.
+pub fn create_snapshot(ensemble:Ensemble, classical_regs:BTreeMap<String, BTreeMap<usize, usize>>) -> Snapshot {
+    Snapshot{ensemble, classical_regs}
+}
+
+/// Serializes a state.
+pub fn save_state(state:&State, format:Format) -> Result<Vec<u8>, SnapshotError> {
+    encode(state, format)
+}
+
+/// Deserializes a state, checking that every ket has the state's number of qubits and that the
+/// state is normalized.
+pub fn load_state(bytes:&[u8], format:Format) -> Result<State, SnapshotError> {
+    let state:State = decode(bytes, format)?;
+    validate_state(&state)?;
+    Ok(state)
+}
+
+/// Serializes an ensemble.
+pub fn save_ensemble(ensemble:&Ensemble, format:Format) -> Result<Vec<u8>, SnapshotError> {
+    encode(ensemble, format)
+}
+
+/// Deserializes an ensemble, validating each subsystem and every entanglement between them.
+pub fn load_ensemble(bytes:&[u8], format:Format) -> Result<Ensemble, SnapshotError> {
+    let ensemble:Ensemble = decode(bytes, format)?;
+    validate_ensemble(&ensemble)?;
+    Ok(ensemble)
+}
+
+/// Serializes a snapshot of an ensemble and its classical registers.
+pub fn save_snapshot(snapshot:&Snapshot, format:Format) -> Result<Vec<u8>, SnapshotError> {
+    encode(snapshot, format)
+}
+
+/// Deserializes a snapshot, validating its ensemble.
+pub fn load_snapshot(bytes:&[u8], format:Format) -> Result<Snapshot, SnapshotError> {
+    let snapshot:Snapshot = decode(bytes, format)?;
+    validate_ensemble(&snapshot.ensemble)?;
+    Ok(snapshot)
+}
+
+/// Checks that every ket has the state's number of qubits and that the state is normalized.
+pub fn validate_state(state:&State) -> Result<(), SnapshotError> {
+    let mut total_probability = 0.0;
+    for ket in &state.kets {
+        if ket.get_val().len() != state.num_qubits {
+            return Err(SnapshotError::QubitCount{system: state.symbol.clone(), expected: state.num_qubits, found: ket.get_val().len()});
+        }
+        total_probability += ket.get_probability();
+    }
+    if (total_probability - 1.0).abs() > NORMALIZATION_TOLERANCE {
+        return Err(SnapshotError::NotNormalized{system: state.symbol.clone(), total_probability});
+    }
+    Ok(())
+}
+
+/// Validates each subsystem of the ensemble and checks that every entanglement refers to a qubit
+/// within the ensemble.
+pub fn validate_ensemble(ensemble:&Ensemble) -> Result<(), SnapshotError> {
+    for (key, subsystem) in &ensemble.subsystems {
+        if *key != subsystem.symbol {
+            return Err(SnapshotError::SymbolMismatch{key: key.clone(), symbol: subsystem.symbol.clone()});
+        }
+        validate_state(subsystem)?;
+        for ket in &subsystem.kets {
+            for entanglement in ket.get_entanglements() {
+                let system = entanglement.get_system();
+                let qubit = entanglement.get_qubit();
+                match ensemble.subsystems.get(system) {
+                    Some(other) if qubit < other.num_qubits => {},
+                    _ => return Err(SnapshotError::DanglingEntanglement{system: system.to_string(), qubit})
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn encode<T: Serialize>(value:&T, format:Format) -> Result<Vec<u8>, SnapshotError> {
+    match format {
+        Format::Json => serde_json::to_vec(value).map_err(|e| SnapshotError::Encoding(e.to_string())),
+        Format::Binary => bincode::serialize(value).map_err(|e| SnapshotError::Encoding(e.to_string()))
+    }
+}
+
+fn decode<T: DeserializeOwned>(bytes:&[u8], format:Format) -> Result<T, SnapshotError> {
+    match format {
+        Format::Json => serde_json::from_slice(bytes).map_err(|e| SnapshotError::Encoding(e.to_string())),
+        Format::Binary => bincode::deserialize(bytes).map_err(|e| SnapshotError::Encoding(e.to_string()))
+    }
+}
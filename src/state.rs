@@ -2,25 +2,135 @@
 //! A data structure that represents a full quantum state and maintains a set of underlying kets.
 
 extern crate rand;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
 use rand::Rng;
-use crate::ket::Ket;
+use serde::{Deserialize, Serialize};
+use crate::basis;
+use crate::basis::BasisState;
+use crate::ket::{Entanglement, Ket};
+use crate::parallel;
+use crate::parallel::Parallelism;
+use crate::tracer;
+use crate::tracer::Tracer;
 use crate::coefficient;
 use crate::coefficient::ComplexCoefficient;
+use crate::gate;
+use crate::gate::Gate;
+use crate::ket;
+use crate::truncation;
+use crate::truncation::Truncation;
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct State {
     pub kets: Vec<Ket>,
     pub num_qubits: usize,
-    pub symbol: char
+    pub symbol: String,
+    #[serde(skip, default = "tracer::noop_tracer")]
+    tracer: Arc<dyn Tracer>,
+    #[serde(skip)]
+    parallelism: Parallelism,
+    #[serde(skip)]
+    truncation: Option<Truncation>,
+    #[serde(default = "exact_fidelity")]
+    fidelity: f64
 }
 
 /// Initializes a quantum state with a given set of kets and number of qubits.
-pub fn create_state(kets:Vec<Ket>, num_qubits:usize, symbol:char) -> State {
-    State{kets: kets, num_qubits: num_qubits, symbol: symbol}
+/// The state is observed by a no-op tracer and simulated on a single thread until
+/// configured otherwise, and is simulated exactly unless given a truncation policy.
+pub fn create_state(kets:Vec<Ket>, num_qubits:usize, symbol:&str) -> State {
+    State{kets, num_qubits, symbol: symbol.to_string(), tracer: tracer::noop_tracer(), parallelism: parallel::create_parallelism(1), truncation: None, fidelity: exact_fidelity()}
 }
 
+fn exact_fidelity() -> f64 {
+    1.0
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PostselectionError {
+    /// The outcome cannot occur, so the projected state would be empty.
+    ZeroProbability { system: String, qubit: usize, outcome: bool },
+    /// The qubit is not part of the state.
+    NonExistentQubit { system: String, qubit: usize },
+    /// The system is not part of the ensemble.
+    NonExistentSystem { system: String }
+}
+
+impl fmt::Display for PostselectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PostselectionError::ZeroProbability{system, qubit, outcome} => write!(f, "outcome {} of {}[{}] has zero probability", *outcome as u8, system, qubit),
+            PostselectionError::NonExistentQubit{system, qubit} => write!(f, "attempt to postselect non-existent qubit {}[{}]", system, qubit),
+            PostselectionError::NonExistentSystem{system} => write!(f, "attempt to postselect on non-existent system {}", system)
+        }
+    }
+}
+
+impl Error for PostselectionError {}
+
+#[derive(Debug, PartialEq)]
+pub enum AmplitudeError {
+    /// A dense vector of amplitudes must have one entry for each of the 2^n basis states.
+    NotPowerOfTwo(usize),
+    /// A basis state has a different number of qubits from the state being created.
+    WidthMismatch { expected: usize, found: usize },
+    /// Every amplitude is zero, so there is no state to normalize.
+    ZeroNorm
+}
+
+impl fmt::Display for AmplitudeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AmplitudeError::NotPowerOfTwo(length) => write!(f, "{} amplitudes is not a power of two", length),
+            AmplitudeError::WidthMismatch{expected, found} => write!(f, "expected a {} qubit basis state but found {} qubits", expected, found),
+            AmplitudeError::ZeroNorm => write!(f, "every amplitude is zero")
+        }
+    }
+}
+
+impl Error for AmplitudeError {}
+
+/// Kets whose amplitude falls below this modulus after a gate are discarded.
+pub const AMPLITUDE_TOLERANCE:f64 = 1e-12;
+
 impl State {
 
+    /// Initializes a state from a dense vector of 2^n amplitudes, where bit `i` of an amplitude's
+    /// index is the value of qubit `i`. Zero amplitudes do not produce kets. Fails if the length is
+    /// not a power of two or every amplitude is zero.
+    pub fn from_amplitudes(amplitudes:&[ComplexCoefficient], symbol:&str) -> Result<State, AmplitudeError> {
+        if !amplitudes.len().is_power_of_two() {
+            return Err(AmplitudeError::NotPowerOfTwo(amplitudes.len()));
+        }
+        let num_qubits = amplitudes.len().trailing_zeros() as usize;
+        let entries = amplitudes.iter().enumerate()
+            .map(|(index, amplitude)| (basis::create_basis_state_from_int(index as u128, num_qubits), *amplitude))
+            .collect();
+        State::from_sparse(entries, num_qubits, symbol)
+    }
+
+    /// Initializes a state from (basis state, amplitude) pairs. Amplitudes of repeated basis
+    /// states are summed and zero amplitudes do not produce kets. Fails if a basis state is not
+    /// `num_qubits` wide or every amplitude is zero.
+    pub fn from_sparse(entries:Vec<(BasisState, ComplexCoefficient)>, num_qubits:usize, symbol:&str) -> Result<State, AmplitudeError> {
+        let mut kets:Vec<Ket> = vec![];
+        for (val, amplitude) in entries {
+            if val.len() != num_qubits {
+                return Err(AmplitudeError::WidthMismatch{expected: num_qubits, found: val.len()});
+            }
+            kets.push(ket::create_ket(amplitude, val, vec![]));
+        }
+        let mut kets = merge_duplicate_kets(&kets);
+        kets.retain(|ket| ket.get_probability() != 0.0);
+        if kets.is_empty() {
+            return Err(AmplitudeError::ZeroNorm);
+        }
+        Ok(create_state(kets, num_qubits, symbol))
+    }
+
     /// Adds a ket to the overall quantum state.        
     pub fn add_ket(&mut self, ket:Ket) {
         self.kets.push(ket);
@@ -36,215 +146,349 @@ impl State {
 
     /// Determines the components of the state vector for the given target qubit.    
     pub fn get_components(&self, qubit:usize) -> [ComplexCoefficient; 2] {
-        let empty_coefficient = coefficient::create_coefficient(0.0, false);
-        let empty_imaginary_coefficient = coefficient::create_coefficient(0.0, true);
-        let mut beta = coefficient::create_complex_coefficient(empty_coefficient, empty_imaginary_coefficient);
-        let mut alpha = coefficient::create_complex_coefficient(empty_coefficient, empty_imaginary_coefficient);
-        for ket in &self.kets {
-            if Some(true) == ket.get_val().get(qubit) {
-                beta = beta.add_to_complex_coefficient(ket.get_coefficient());
-            }
-            else {
-                alpha = alpha.add_to_complex_coefficient(ket.get_coefficient());
+        let partial_sums = self.parallelism.map_chunks(&self.kets, |kets| {
+            let mut components = [empty_complex_coefficient(), empty_complex_coefficient()];
+            for ket in kets {
+                let index = if ket.get_bit(qubit) == Some(true) { 1 } else { 0 };
+                components[index] = components[index].add_to_complex_coefficient(ket.get_coefficient());
             }
+            components
+        });
+        let mut alpha = empty_complex_coefficient();
+        let mut beta = empty_complex_coefficient();
+        for [partial_alpha, partial_beta] in partial_sums {
+            alpha = alpha.add_to_complex_coefficient(partial_alpha);
+            beta = beta.add_to_complex_coefficient(partial_beta);
         }
         [alpha, beta]
     }
 
+    /// Sets the number of threads that gates, normalization and probability sums are spread
+    /// across. One thread applies everything serially.
+    pub fn set_threads(&mut self, threads:usize) {
+        self.parallelism = parallel::create_parallelism(threads);
+    }
+
+    /// Shares an existing thread pool with this state.
+    pub(crate) fn set_parallelism(&mut self, parallelism:Parallelism) {
+        self.parallelism = parallelism;
+    }
+
+    /// The number of threads operations on this state are spread across.
+    pub fn get_threads(&self) -> usize {
+        self.parallelism.get_threads()
+    }
+
+    /// Sets the tracer which is notified of every operation on this state.
+    pub fn set_tracer(&mut self, tracer:Arc<dyn Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Returns a handle to the tracer observing this state.
+    pub fn get_tracer(&self) -> Arc<dyn Tracer> {
+        self.tracer.clone()
+    }
+
+    /// Sets the policy used to discard kets after each gate, or `None` to simulate exactly.
+    pub fn set_truncation(&mut self, truncation:Option<Truncation>) {
+        self.truncation = truncation;
+    }
+
+    /// The policy used to discard kets after each gate, if any.
+    pub fn get_truncation(&self) -> Option<Truncation> {
+        self.truncation
+    }
+
+    /// A lower bound on the fidelity of this state with the one exact simulation would have
+    /// produced: the product of the probability kept by every truncation so far.
+    pub fn get_fidelity(&self) -> f64 {
+        self.fidelity
+    }
+
+    /// Applies a gate as a traced operation, then truncates and renormalizes the state if it has a
+    /// truncation policy. The trace reports the state after truncation.
+    fn traced_gate<F>(&mut self, operation:&str, qubits:&[usize], apply:F) where F: FnOnce(&mut State) -> Vec<f64> {
+        self.traced(operation, qubits, |state| {
+            let params = apply(state);
+            if let Some(policy) = state.truncation {
+                let (kets, kept_probability) = truncation::truncate(std::mem::take(&mut state.kets), policy);
+                state.kets = kets;
+                if kept_probability < 1.0 {
+                    state.fidelity *= kept_probability;
+                    state._normalize();
+                }
+            }
+            params
+        });
+    }
+
+    /// Applies an operation to the state and reports it to the tracer, capturing snapshots of
+    /// the kets before and after only if the tracer asks for them. The operation returns any
+    /// numeric parameters that should be reported alongside it.
+    fn traced<F>(&mut self, operation:&str, qubits:&[usize], apply:F) where F: FnOnce(&mut State) -> Vec<f64> {
+        let snapshots = self.tracer.wants_snapshots();
+        let before = if snapshots { Some(self.kets.clone()) } else { None };
+        let params = apply(self);
+        let after = if snapshots { Some(self.kets.clone()) } else { None };
+        let qubits = qubits.iter().map(|qubit| (self.symbol.clone(), *qubit)).collect();
+        self.tracer.on_operation(&tracer::create_trace_event(operation, qubits, params, before, after));
+    }
+
     /// Performs a Pauli X gate on the target qubit.
     pub fn x(&mut self, qubit:usize) {
-        for ket in &mut self.kets {
-            print!("x ({})", qubit);
-            ket.print();
-            print!(" =");
-            ket.x(qubit);
-            ket.print();
-            println!();
-        }
+        self.traced_gate("x", &[qubit], |state| {
+            state.parallelism.for_each(&mut state.kets, |ket| ket.x(qubit));
+            vec![]
+        });
     }
          
     /// Performs a Controlled X gate on the target qubit with the 
     /// source qubit as controller.
     pub fn cx(&mut self, source:usize, target:usize) {
-        for ket in &mut self.kets {
-            print!("cx ({} -> {})",source, target);
-            ket.print();
-            print!(" =");
-            ket.cx(source, target);
-            ket.print();
-            println!();
-        }
+        self.traced_gate("cx", &[source, target], |state| {
+            state.parallelism.for_each(&mut state.kets, |ket| ket.cx(source, target));
+            vec![]
+        });
+    }
+
+    /// Exchanges the values of two qubits.
+    pub fn swap(&mut self, first:usize, second:usize) {
+        self.traced_gate("swap", &[first, second], |state| {
+            state.parallelism.for_each(&mut state.kets, |ket| {
+                ket.cx(first, second);
+                ket.cx(second, first);
+                ket.cx(first, second);
+            });
+            vec![]
+        });
     }
 
     /// Performs a Pauli Y gate on the target qubit.       
     pub fn y(&mut self, qubit:usize) {
-        for ket in &mut self.kets {
-            print!("y ({})", qubit);
-            ket.print();
-            print!(" =");
-            ket.y(qubit);
-            ket.print();
-            println!();
-        }
+        self.traced_gate("y", &[qubit], |state| {
+            state.parallelism.for_each(&mut state.kets, |ket| ket.y(qubit));
+            vec![]
+        });
     }
 
     /// Performs a Pauli Z gate on the target qubit.            
     pub fn z(&mut self, qubit:usize) {
-        for ket in &mut self.kets {
-            print!("z ({})", qubit);
-            ket.print();
-            print!(" =");
-            ket.z(qubit);
-            ket.print();
-            println!();
-        }
+        self.traced_gate("z", &[qubit], |state| {
+            state.parallelism.for_each(&mut state.kets, |ket| ket.z(qubit));
+            vec![]
+        });
     }
 
-    /// Performs a Hadamard gate on the target qubit.
-    pub fn h(&mut self, qubit:usize) {
-        let empty_coefficient = coefficient::create_coefficient(0.0, false);
-        let empty_imaginary_coefficient = coefficient::create_coefficient(0.0, true);
-        let mut beta = coefficient::create_complex_coefficient(empty_coefficient, empty_imaginary_coefficient);
-        let mut alpha = coefficient::create_complex_coefficient(empty_coefficient, empty_imaginary_coefficient);
-        let mut one_kets:Vec<Ket> = vec![];
-        let mut zero_kets:Vec<Ket> = vec![];
+    /// Performs a rotation about the X axis by the given angle on the target qubit.
+    pub fn rx(&mut self, qubit:usize, theta:f64) {
+        self.traced_gate("rx", &[qubit], |state| {
+            state.apply_matrix(&[], qubit, gate::get_matrix("rx", &[theta]).unwrap());
+            vec![theta]
+        });
+    }
 
-        for ket in &self.kets {
-            match ket.get_val().get(qubit) {
-                Some(true) => {
-                    one_kets.push(ket.clone());
-                    beta = beta.add_to_complex_coefficient(ket.get_coefficient()); 
-                },
-                _ => {
-                    zero_kets.push(ket.clone());
-                    alpha = alpha.add_to_complex_coefficient(ket.get_coefficient());
-                }
-            };
-        }
+    /// Performs a rotation about the Y axis by the given angle on the target qubit.
+    pub fn ry(&mut self, qubit:usize, theta:f64) {
+        self.traced_gate("ry", &[qubit], |state| {
+            state.apply_matrix(&[], qubit, gate::get_matrix("ry", &[theta]).unwrap());
+            vec![theta]
+        });
+    }
 
-        let mut negative_beta = beta.clone();
-        negative_beta.negate_magnitude();
+    /// Performs a rotation about the Z axis by the given angle on the target qubit.
+    pub fn rz(&mut self, qubit:usize, theta:f64) {
+        self.traced_gate("rz", &[qubit], |state| {
+            state.apply_matrix(&[], qubit, gate::get_matrix("rz", &[theta]).unwrap());
+            vec![theta]
+        });
+    }
 
-        if alpha.equals_complex_coefficient(beta) {
-            print!("h ({})", qubit);
-            for ket in &self.kets {
-                ket.print();
-            }
-            print!(" =");
-            self.kets = zero_kets;
-            for ket in &self.kets {
-                ket.print();
-            }
-            println!();
-        }
+    /// Performs a single qubit gate, named as by `gate::split_controls`, on the target qubit of
+    /// those kets in which every control qubit is set.
+    pub fn controlled(&mut self, controls:&[usize], target:usize, name:&str, params:&[f64]) {
+        let mut qubits = controls.to_vec();
+        qubits.push(target);
+        let operation = format!("{}{}", "c".repeat(controls.len()), name);
+        self.traced_gate(&operation, &qubits, |state| {
+            let matrix = gate::get_matrix(name, params).unwrap_or_else(|| panic!("attempt to apply unsupported gate {}", name));
+            state.apply_matrix(controls, target, matrix);
+            params.to_vec()
+        });
+    }
 
-        else if alpha.equals_complex_coefficient(negative_beta) {
-            print!("h ({})", qubit);
-            for ket in &self.kets {
-                ket.print();
+    /// Applies a single qubit unitary, given as a 2x2 matrix indexed by [output][input] value of
+    /// the target qubit, to the kets in which every control qubit is set. Each such ket is split
+    /// into a ket for each non-zero entry in its column, and the results are merged so that each
+    /// qubit string appears once.
+    fn apply_matrix(&mut self, controls:&[usize], qubit:usize, matrix:[[ComplexCoefficient; 2]; 2]) {
+        let new_kets = self.parallelism.map_chunks(&self.kets, |kets| {
+            let mut new_kets:Vec<Ket> = Vec::with_capacity(2*kets.len());
+            for ket in kets {
+                if !controls.iter().all(|control| ket.get_bit(*control) == Some(true)) {
+                    new_kets.push(ket.clone());
+                    continue;
+                }
+                let input = if ket.get_bit(qubit) == Some(true) { 1 } else { 0 };
+                for (output, row) in matrix.iter().enumerate() {
+                    if row[input].to_probability() == 0.0 {
+                        continue;
+                    }
+                    let mut new_ket = ket.clone();
+                    if output != input {
+                        new_ket.x(qubit);
+                    }
+                    new_ket.set_coefficient(ket.get_coefficient().multiply_by_complex_coefficient(row[input]));
+                    new_kets.push(new_ket);
+                }
             }
-            print!(" =");
-            self.kets = one_kets;
-            for ket in &self.kets {
-                ket.print();
+            merge_duplicate_kets(&new_kets)
+        });
+        let mut kets = merge_duplicate_kets(&new_kets.concat());
+        kets.retain(|ket| ket.get_coefficient().modulus() >= AMPLITUDE_TOLERANCE);
+        self.kets = kets;
+    }
+
+    /// Applies a gate by name. Supports the gates listed by `gate::get_signature`.
+    pub fn apply_gate(&mut self, gate:&Gate) {
+        let qubits = gate.get_qubits();
+        let params = gate.get_params();
+        match (gate.get_name(), qubits.len(), params.len()) {
+            ("x", 1, 0) => self.x(qubits[0]),
+            ("y", 1, 0) => self.y(qubits[0]),
+            ("z", 1, 0) => self.z(qubits[0]),
+            ("h", 1, 0) => self.h(qubits[0]),
+            ("cx", 2, 0) => self.cx(qubits[0], qubits[1]),
+            ("swap", 2, 0) => self.swap(qubits[0], qubits[1]),
+            ("rx", 1, 1) => self.rx(qubits[0], params[0]),
+            ("ry", 1, 1) => self.ry(qubits[0], params[0]),
+            ("rz", 1, 1) => self.rz(qubits[0], params[0]),
+            (name, _, _) => match gate::split_controls(name) {
+                Some((controls, base)) if gate::get_signature(name) == Some((qubits.len(), params.len())) => {
+                    self.controlled(&qubits[..controls], qubits[controls], base, params);
+                },
+                _ => panic!("attempt to apply unsupported gate {}", gate)
             }
-            println!();
         }
+    }
 
-        else {
-            let mut new_kets:Vec<Ket> = vec![];
-            for ket in &mut self.kets {
-                print!("h ({})", qubit);
-                ket.print();
-                let hadamard_result = ket.h(qubit);
-                for result in &hadamard_result {
-                    new_kets.push(result.clone());
-                }
-                print!(" =");
-                for result in &hadamard_result {
-                    result.print();
-                }
-                println!();
-            }
-            self.kets = new_kets;
+    /// Applies a sequence of gates in order.
+    pub fn apply_gates(&mut self, gates:&[Gate]) {
+        for gate in gates {
+            self.apply_gate(gate);
         }
     }
 
+    /// Performs a Hadamard gate on the target qubit.
+    pub fn h(&mut self, qubit:usize) {
+        self.traced_gate("h", &[qubit], |state| {
+            state.apply_matrix(&[], qubit, gate::get_matrix("h", &[]).unwrap());
+            vec![]
+        });
+    }
+
     /// Measures the target qubit.    
     pub fn m(&mut self, qubit:usize) -> bool {
-        let empty_coefficient = coefficient::create_coefficient(0.0, false);
-        let empty_imaginary_coefficient = coefficient::create_coefficient(0.0, true);
-        let mut beta = coefficient::create_complex_coefficient(empty_coefficient, empty_imaginary_coefficient);
-        let mut alpha = coefficient::create_complex_coefficient(empty_coefficient, empty_imaginary_coefficient);
+        let mut result = false;
+        self.traced("measure", &[qubit], |state| {
+            result = state._m(qubit);
+            vec![if result { 1.0 } else { 0.0 }]
+        });
+        result
+    }
 
-        let mut one_kets:Vec<Ket> = vec![];
-        let mut zero_kets:Vec<Ket> = vec![];
+    fn _m(&mut self, qubit:usize) -> bool {
+        let [alpha, beta] = self.get_probabilities(qubit);
+        let result = self._measure(alpha, beta);
+        self.kets.retain(|ket| (ket.get_bit(qubit) == Some(true)) == result);
+        result
+    }
 
-        for ket in &self.kets {
-            match ket.get_val().get(qubit) {
-                Some(true) => { 
-                    one_kets.push(ket.clone());
-                    beta = beta.add_to_complex_coefficient(ket.get_coefficient()); 
-                },
-                _ => {
-                    zero_kets.push(ket.clone());
-                    alpha = alpha.add_to_complex_coefficient(ket.get_coefficient());
-                }
-            };
+    /// Determines the probabilities of measuring the target qubit as zero and as one. The
+    /// probabilities are relative to the total weight of the state, so they sum to one even if
+    /// the state has not been normalized.
+    pub fn get_probabilities(&self, qubit:usize) -> [f64; 2] {
+        let partial_sums = self.parallelism.map_chunks(&self.kets, |kets| {
+            let mut weights = [0.0, 0.0];
+            for ket in kets {
+                let index = if ket.get_bit(qubit) == Some(true) { 1 } else { 0 };
+                weights[index] += ket.get_probability();
+            }
+            weights
+        });
+        let mut weights = [0.0, 0.0];
+        for [zero, one] in partial_sums {
+            weights[0] += zero;
+            weights[1] += one;
         }
-        let result = self._measure(alpha.to_probability(), beta.to_probability());
-        if result {
-            self.kets = one_kets;
+        let total = weights[0] + weights[1];
+        if total == 0.0 {
+            return [0.0, 0.0];
         }
-        else {
-            self.kets = zero_kets;
+        [weights[0]/total, weights[1]/total]
+    }
+
+    /// Projects the state onto the given outcome of the target qubit and renormalizes it,
+    /// returning the probability that a measurement would have produced that outcome. Fails,
+    /// leaving the state untouched, if the outcome is impossible.
+    pub fn postselect(&mut self, qubit:usize, outcome:bool) -> Result<f64, PostselectionError> {
+        if qubit >= self.num_qubits {
+            return Err(PostselectionError::NonExistentQubit{system: self.symbol.clone(), qubit});
         }
-        result
+        let probability = self.get_probabilities(qubit)[if outcome { 1 } else { 0 }];
+        if probability == 0.0 {
+            return Err(PostselectionError::ZeroProbability{system: self.symbol.clone(), qubit, outcome});
+        }
+        self.traced("postselect", &[qubit], |state| {
+            state.kets.retain(|ket| ket.get_bit(qubit) == Some(outcome));
+            let total_probability:f64 = state.parallelism.map_chunks(&state.kets, |kets| {
+                kets.iter().map(|ket| ket.get_probability()).sum::<f64>()
+            }).iter().sum();
+            let norm_factor = 1.0/total_probability.sqrt();
+            state.parallelism.for_each(&mut state.kets, |ket| {
+                let mut coefficient = ket.get_coefficient();
+                coefficient.multiply_by_number(norm_factor);
+                ket.set_coefficient(coefficient);
+            });
+            vec![if outcome { 1.0 } else { 0.0 }, probability]
+        });
+        Ok(probability)
     }
 
     /// Normalizes the current quantum state.    
     pub fn normalize(&mut self) {
-        let mut total_probability = 0.0;
-        let mut unique_kets:Vec<Ket> = vec![];
+        self.traced("normalize", &[], |state| vec![state._normalize()]);
+    }
 
-        for ket in &self.kets {
-            let mut already_found = false;
-            for unique_ket in &unique_kets {
-                if ket.get_val() == unique_ket.get_val() {
-                    already_found = true;
-                    unique_ket.get_coefficient().add_to_complex_coefficient(ket.get_coefficient());
-                }
-            }
-            if !already_found {
-                unique_kets.push(ket.clone());
-            }
-        }
-        for unique_ket in &unique_kets {
-            total_probability += unique_ket.get_probability();
-        }
-        let norm_factor = 1.0/((total_probability as f64).sqrt());
+    fn _normalize(&mut self) -> f64 {
+        let partially_merged = self.parallelism.map_chunks(&self.kets, merge_duplicate_kets);
+        let mut unique_kets = merge_duplicate_kets(&partially_merged.concat());
+        unique_kets.retain(|ket| ket.get_probability() != 0.0);
+
+        let total_probability:f64 = self.parallelism.map_chunks(&unique_kets, |kets| {
+            kets.iter().map(|ket| ket.get_probability()).sum::<f64>()
+        }).iter().sum();
+        let norm_factor = 1.0/total_probability.sqrt();
         if total_probability != 1.0 {
-            for unique_ket in &unique_kets {
-                unique_ket.get_coefficient().multiply_by_number(norm_factor);
-            }
+            self.parallelism.for_each(&mut unique_kets, |ket| {
+                let mut coefficient = ket.get_coefficient();
+                coefficient.multiply_by_number(norm_factor);
+                ket.set_coefficient(coefficient);
+            });
         }
         self.kets = unique_kets;
-        println!("normalizing factor: {}", norm_factor);
+        norm_factor
     }
 
-
     /// Used pseudo-random number generation to simulate the probabilistic outcome of a qubit 
     /// measurement. Update the quantum system with the measurement results.
     pub fn _measure(&self, alpha:f64, beta:f64) -> bool {
-        let cutoff = alpha*100.0;
+        if alpha + beta == 0.0 {
+            return false;
+        }
+        let cutoff = alpha/(alpha + beta);
         let mut rng = rand::thread_rng();
         let outcome:f64 = rng.gen();
-        if outcome < cutoff {
-            false
-        }
-        else {
-            true
-        }
+        outcome >= cutoff
     }
 
     /// Prints the full quantum state.        
@@ -274,4 +518,32 @@ impl State {
             qubit += 1;
         }
     }
-}
\ No newline at end of file
+}
+
+fn empty_complex_coefficient() -> ComplexCoefficient {
+    let empty_coefficient = coefficient::create_coefficient(0.0, false);
+    let empty_imaginary_coefficient = coefficient::create_coefficient(0.0, true);
+    coefficient::create_complex_coefficient(empty_coefficient, empty_imaginary_coefficient)
+}
+
+/// Combines kets with equal qubit strings and entanglement records by summing their
+/// coefficients, keeping the position of the first occurrence of each. Kets which depend on
+/// different outcomes elsewhere in an ensemble are kept apart so that measuring those qubits can
+/// still collapse them.
+fn merge_duplicate_kets(kets:&[Ket]) -> Vec<Ket> {
+    let mut unique_kets:Vec<Ket> = vec![];
+    let mut positions:HashMap<(&BasisState, &[Entanglement]), usize> = HashMap::new();
+    for ket in kets {
+        match positions.get(&(ket.get_val(), ket.get_entanglements())) {
+            Some(&position) => {
+                let unique_ket = &mut unique_kets[position];
+                unique_ket.set_coefficient(unique_ket.get_coefficient().add_to_complex_coefficient(ket.get_coefficient()));
+            },
+            None => {
+                positions.insert((ket.get_val(), ket.get_entanglements()), unique_kets.len());
+                unique_kets.push(ket.clone());
+            }
+        }
+    }
+    unique_kets
+}
@@ -1,11 +1,25 @@
 extern crate bit_vec;
 
 use bit_vec::BitVec;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 
+use crate::coefficient::ComplexCoefficient;
 use crate::ket::Ket;
-use crate::state::State;
-use crate::parser::execute_qasm;
+use crate::state::{AmplitudeError, PostselectionError, State};
+use crate::snapshot;
+use crate::snapshot::{Format, SnapshotError};
+use crate::truncation::Truncation;
+use crate::create_circuit_builder;
+use crate::exporter;
+use crate::expression;
+use crate::expression::ExpressionError;
+use crate::optimization;
+use crate::parser;
+use crate::parser::{MAX_GATE_DEPTH, MAX_INSTRUCTIONS};
+use crate::qasm3;
+use crate::routing;
+use crate::transpiler;
+use crate::unitary;
 
 fn create_ket() -> Ket {
     let imaginary_coeff = super::coefficient::create_coefficient(1.0, true);
@@ -16,6 +30,23 @@ fn create_ket() -> Ket {
     ket
 }
 
+fn assert_same_unitary(expected:&[Vec<ComplexCoefficient>], actual:&[Vec<ComplexCoefficient>]) {
+    assert_eq!(expected.len(), actual.len());
+    for (expected_row, actual_row) in expected.iter().zip(actual.iter()) {
+        for (expected, actual) in expected_row.iter().zip(actual_row.iter()) {
+            let mut difference = *actual;
+            difference.negate_magnitude();
+            assert!(expected.add_to_complex_coefficient(difference).modulus() < 1e-9);
+        }
+    }
+}
+
+fn create_eleven_qubit_ket() -> Ket {
+    let mut ket = create_ket();
+    ket.set_val(BitVec::from_elem(11, false));
+    ket
+}
+
 #[test]
 fn test_create_coefficient() {
     let mut magnitude:f64 = 1.00;
@@ -49,7 +80,7 @@ fn test_create_ket() {
     let mut init_state = BitVec::from_elem(3, false);
     let mut ket = super::ket::create_ket(complex_coeff, init_state.clone(), vec![]);
     assert!(ket.get_coefficient().equals_complex_coefficient(complex_coeff));
-    assert_eq!(ket.get_val(), init_state);
+    assert_eq!(ket.get_val().to_bitvec(), init_state);
 
     imaginary_coeff = super::coefficient::create_coefficient(0.0, true);
     real_coeff = super::coefficient::create_coefficient(0.5, false);
@@ -57,21 +88,21 @@ fn test_create_ket() {
     init_state = BitVec::from_elem(3, false);
     ket = super::ket::create_ket(complex_coeff, init_state.clone(), vec![]);
     assert!(ket.get_coefficient().equals_complex_coefficient(complex_coeff));
-    assert_eq!(ket.get_val(), init_state);
+    assert_eq!(ket.get_val().to_bitvec(), init_state);
 }
 
 #[test]
 fn test_create_state() {
     let mut kets = vec![create_ket(), create_ket(), create_ket()];
     let mut num_qubits = 3;
-    let mut symbol = 'p';
+    let mut symbol = "p";
     let mut state = super::state::create_state(kets, num_qubits, symbol);
     assert_eq!(state.num_qubits, num_qubits);
     assert_eq!(state.symbol, symbol);
 
     kets = vec![create_ket(), create_ket()];
     num_qubits = 2;
-    symbol = 'q';
+    symbol = "q";
     state = super::state::create_state(kets, num_qubits, symbol);
     assert_eq!(state.num_qubits, num_qubits);
     assert_eq!(state.symbol, symbol);
@@ -80,21 +111,21 @@ fn test_create_state() {
 #[test]
 fn test_create_ensemble() {
     let num_qubits = 3;
-    let first_symbol = 'p';
-    let second_symbol = 'q';
+    let first_symbol = "p";
+    let second_symbol = "q";
     let first_state = super::state::create_state(vec![create_ket(), create_ket(), create_ket()], num_qubits, first_symbol);
     let second_state = super::state::create_state(vec![create_ket(), create_ket(), create_ket()], num_qubits, second_symbol);
-    let mut subsystems:BTreeMap<char, State> = BTreeMap::new();
-    subsystems.insert(first_symbol, first_state);
-    subsystems.insert(second_symbol, second_state);
+    let mut subsystems:BTreeMap<String, State> = BTreeMap::new();
+    subsystems.insert(first_symbol.to_string(), first_state);
+    subsystems.insert(second_symbol.to_string(), second_state);
     let ensemble = super::ensemble::create_ensemble(subsystems);
 
-    let subsystem_p = match ensemble.subsystems.get(&first_symbol) {
+    let subsystem_p = match ensemble.subsystems.get(first_symbol) {
         Some(subsystem_p) => subsystem_p,
         None => panic!("could not retrieve subsystem from ensemble")
     };
 
-    let subsystem_q = match ensemble.subsystems.get(&second_symbol) {
+    let subsystem_q = match ensemble.subsystems.get(second_symbol) {
         Some(subsystem_q) => subsystem_q,
         None => panic!("could not retrieve subsystem from ensemble")
     };
@@ -102,3 +133,819 @@ fn test_create_ensemble() {
     assert_eq!(subsystem_p.symbol, first_symbol);
     assert_eq!(subsystem_q.symbol, second_symbol);
 }
+
+#[test]
+fn test_ensemble_cx_updates_subsystems() {
+    let mut first_state = super::parser::init_state(2, "p");
+    first_state.x(0);
+    let mut subsystems:BTreeMap<String, State> = BTreeMap::new();
+    subsystems.insert("p".to_string(), first_state);
+    subsystems.insert("q".to_string(), super::parser::init_state(1, "q"));
+    let mut ensemble = super::ensemble::create_ensemble(subsystems);
+
+    ensemble.cx("p", 0, "p", 1);
+    assert_eq!(ensemble.subsystems["p"].kets.len(), 1);
+    assert_eq!(ensemble.subsystems["p"].kets[0].get_bit(1), Some(true));
+
+    ensemble.cx("p", 0, "q", 0);
+    let flipped:Vec<&Ket> = ensemble.subsystems["q"].kets.iter().filter(|ket| ket.get_bit(0) == Some(true)).collect();
+    assert_eq!(flipped.len(), 1);
+    assert!((flipped[0].get_probability() - 1.0).abs() < 1e-12);
+    assert!(flipped[0].is_entangled_with("p", 0));
+}
+
+#[test]
+fn test_recording_tracer() {
+    let tracer = super::tracer::create_recording_tracer(true);
+    let mut state = super::state::create_state(vec![create_ket()], 3, "q");
+    state.set_tracer(tracer.clone());
+    state.x(0);
+    state.cx(0, 1);
+
+    let timeline = tracer.get_timeline();
+    assert_eq!(timeline.len(), 2);
+    assert_eq!(timeline[0].get_operation(), "x");
+    assert_eq!(timeline[1].get_operation(), "cx");
+    assert_eq!(timeline[1].get_qubits().to_vec(), vec![("q".to_string(), 0), ("q".to_string(), 1)]);
+    assert_eq!(timeline[1].label(), "cx (0 -> 1)");
+
+    let after = timeline[1].get_after().unwrap();
+    assert_eq!(after[0].get_val().to_bitvec(), BitVec::from_fn(3, |i| i < 2));
+}
+
+#[test]
+fn test_recording_tracer_without_snapshots() {
+    let tracer = super::tracer::create_recording_tracer(false);
+    let mut state = super::state::create_state(vec![create_ket()], 3, "q");
+    state.set_tracer(tracer.clone());
+    state.h(2);
+
+    let timeline = tracer.get_timeline();
+    assert_eq!(timeline.len(), 1);
+    assert!(timeline[0].get_before().is_none());
+    assert!(timeline[0].get_after().is_none());
+}
+
+#[test]
+fn test_normalize_merges_duplicate_kets() {
+    let mut one = BitVec::from_elem(3, false);
+    one.set(0, true);
+    let mut other = create_ket();
+    other.set_val(one);
+    let mut cancelled = create_ket();
+    cancelled.set_val(BitVec::from_elem(3, true));
+    let mut opposite = cancelled.clone();
+    let mut coefficient = opposite.get_coefficient();
+    coefficient.negate_magnitude();
+    opposite.set_coefficient(coefficient);
+    let mut state = super::state::create_state(vec![create_ket(), create_ket(), other, cancelled, opposite], 3, "q");
+    state.normalize();
+
+    assert_eq!(state.kets.len(), 2);
+    assert!((state.kets[0].get_probability() - 0.8).abs() < 1e-12);
+    assert!((state.kets[1].get_probability() - 0.2).abs() < 1e-12);
+}
+
+#[test]
+fn test_parallel_gates_match_serial() {
+    let run = |threads:usize| {
+        let mut state = super::state::create_state(vec![create_eleven_qubit_ket()], 11, "q");
+        state.set_threads(threads);
+        for qubit in 0..11 {
+            state.h(qubit);
+        }
+        state.cx(0, 10);
+        state.z(3);
+        state.normalize();
+        state
+    };
+    let serial = run(1);
+    let parallel = run(4);
+
+    assert_eq!(parallel.get_threads(), 4);
+    assert_eq!(serial.kets.len(), 2048);
+    assert_eq!(serial.kets.len(), parallel.kets.len());
+    for (a, b) in serial.kets.iter().zip(parallel.kets.iter()) {
+        assert_eq!(a.get_val(), b.get_val());
+        assert!(a.get_coefficient().equals_complex_coefficient(b.get_coefficient()));
+    }
+    let total:f64 = parallel.kets.iter().map(|ket| ket.get_probability()).sum();
+    assert!((total - 1.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_basis_state_inline_and_wide() {
+    let mut inline = super::basis::create_basis_state(128);
+    inline.flip(127);
+    inline.set(3, true);
+    assert_eq!(inline.get(127), Some(true));
+    assert_eq!(inline.get(3), Some(true));
+    assert_eq!(inline.get(128), None);
+    assert_eq!(inline.to_int(), Some((1 << 127) | (1 << 3)));
+
+    let mut wide = super::basis::create_basis_state(130);
+    wide.flip(129);
+    assert_eq!(wide.get(129), Some(true));
+    assert_eq!(wide.to_int(), None);
+
+    let bits = BitVec::from_fn(5, |i| i % 2 == 0);
+    let from_bits = super::basis::BasisState::from(bits.clone());
+    assert_eq!(from_bits, super::basis::create_basis_state_from_int(0b10101, 5));
+    assert_eq!(from_bits.to_bitvec(), bits);
+    assert_eq!(format!("{:?}", from_bits), format!("{:?}", bits));
+}
+
+#[test]
+fn test_ket_gates_on_wide_register() {
+    let mut ket = create_ket();
+    ket.set_val(super::basis::create_basis_state(200));
+    ket.x(150);
+    ket.cx(150, 199);
+    assert_eq!(ket.get_bit(199), Some(true));
+    assert_eq!(ket.get_bit(0), Some(false));
+}
+
+fn create_normalized_state(symbol:&str) -> State {
+    let mut other = create_ket();
+    other.set_val(BitVec::from_fn(3, |i| i == 1));
+    let mut state = super::state::create_state(vec![create_ket(), other], 3, symbol);
+    state.normalize();
+    state
+}
+
+#[test]
+fn test_state_snapshot_round_trip() {
+    let state = create_normalized_state("q");
+    for format in [Format::Json, Format::Binary] {
+        let bytes = snapshot::save_state(&state, format).unwrap();
+        let loaded = snapshot::load_state(&bytes, format).unwrap();
+        assert_eq!(loaded.symbol, state.symbol);
+        assert_eq!(loaded.num_qubits, state.num_qubits);
+        assert_eq!(loaded.kets.len(), state.kets.len());
+        for (a, b) in loaded.kets.iter().zip(state.kets.iter()) {
+            assert_eq!(a.get_val(), b.get_val());
+            assert!(a.get_coefficient().equals_complex_coefficient(b.get_coefficient()));
+        }
+    }
+}
+
+#[test]
+fn test_ensemble_snapshot_round_trip() {
+    let mut entangled = create_normalized_state("r");
+    entangled.kets[0].entangle(true, "q", 2);
+    let mut subsystems:BTreeMap<String, State> = BTreeMap::new();
+    subsystems.insert("q".to_string(), create_normalized_state("q"));
+    subsystems.insert("r".to_string(), entangled);
+    let mut regs = BTreeMap::new();
+    regs.insert(0, 1);
+    let mut classical_regs = BTreeMap::new();
+    classical_regs.insert("c".to_string(), regs);
+    let original = snapshot::create_snapshot(super::ensemble::create_ensemble(subsystems), classical_regs.clone());
+
+    for format in [Format::Json, Format::Binary] {
+        let bytes = snapshot::save_snapshot(&original, format).unwrap();
+        let loaded = snapshot::load_snapshot(&bytes, format).unwrap();
+        assert_eq!(loaded.classical_regs, classical_regs);
+        assert!(loaded.ensemble.subsystems["r"].kets[0].is_entangled_with("q", 2));
+    }
+}
+
+#[test]
+fn test_snapshot_load_validates() {
+    let unnormalized = super::state::create_state(vec![create_ket()], 3, "q");
+    let bytes = snapshot::save_state(&unnormalized, Format::Json).unwrap();
+    match snapshot::load_state(&bytes, Format::Json) {
+        Err(SnapshotError::NotNormalized{system, ..}) => assert_eq!(system, "q"),
+        _ => panic!("expected an unnormalized state to be rejected")
+    }
+
+    let mut wrong_width = create_normalized_state("q");
+    wrong_width.num_qubits = 4;
+    let bytes = snapshot::save_state(&wrong_width, Format::Binary).unwrap();
+    assert_eq!(snapshot::load_state(&bytes, Format::Binary).err(), Some(SnapshotError::QubitCount{system: "q".to_string(), expected: 4, found: 3}));
+
+    let mut dangling = create_normalized_state("q");
+    dangling.kets[0].entangle(false, "z", 0);
+    let mut subsystems:BTreeMap<String, State> = BTreeMap::new();
+    subsystems.insert("q".to_string(), dangling);
+    let bytes = snapshot::save_ensemble(&super::ensemble::create_ensemble(subsystems), Format::Json).unwrap();
+    assert_eq!(snapshot::load_ensemble(&bytes, Format::Json).err(), Some(SnapshotError::DanglingEntanglement{system: "z".to_string(), qubit: 0}));
+
+    assert!(snapshot::load_state(b"not a state", Format::Json).is_err());
+}
+
+fn complex(real:f64, imaginary:f64) -> ComplexCoefficient {
+    super::coefficient::create_complex_coefficient_from_parts(real, imaginary)
+}
+
+/// The squared overlap of two states, which is one when they are equal up to a global phase.
+fn fidelity(a:&State, b:&State) -> f64 {
+    let mut overlap = complex(0.0, 0.0);
+    for ket in &a.kets {
+        for other in &b.kets {
+            if ket.get_val() == other.get_val() {
+                let mut conjugate = ket.get_coefficient();
+                conjugate.complex_conjugate();
+                overlap = overlap.add_to_complex_coefficient(conjugate.multiply_by_complex_coefficient(other.get_coefficient()));
+            }
+        }
+    }
+    overlap.to_probability()
+}
+
+#[test]
+fn test_init_state_is_all_zero() {
+    let state = super::parser::init_state(3, "q");
+    assert_eq!(state.kets.len(), 1);
+    assert_eq!(*state.kets[0].get_val(), super::basis::create_basis_state(3));
+    assert_eq!(state.kets[0].get_probability(), 1.0);
+}
+
+#[test]
+fn test_state_from_amplitudes_and_sparse() {
+    let half = 0.5;
+    let state = State::from_amplitudes(&[complex(half, 0.0), complex(0.0, 0.0), complex(0.0, half), complex(-half, half)], "q").unwrap();
+    assert_eq!(state.num_qubits, 2);
+    assert_eq!(state.kets.len(), 3);
+    assert_eq!(state.kets[1].get_val().to_int(), Some(2));
+    assert!(state.kets[1].get_coefficient().equals_complex_coefficient(complex(0.0, half)));
+
+    let sparse = State::from_sparse(vec![
+        (super::basis::create_basis_state_from_int(0, 2), complex(half, 0.0)),
+        (super::basis::create_basis_state_from_int(2, 2), complex(0.0, half)),
+        (super::basis::create_basis_state_from_int(3, 2), complex(-half, half))
+    ], 2, "q").unwrap();
+    assert!((fidelity(&state, &sparse) - 1.0).abs() < 1e-12);
+
+    assert_eq!(State::from_amplitudes(&[complex(half, 0.0); 3], "q").err(), Some(AmplitudeError::NotPowerOfTwo(3)));
+    assert_eq!(State::from_amplitudes(&[complex(0.0, 0.0); 4], "q").err(), Some(AmplitudeError::ZeroNorm));
+    let wide = vec![(super::basis::create_basis_state_from_int(0, 3), complex(1.0, 0.0))];
+    assert_eq!(State::from_sparse(wide, 2, "q").err(), Some(AmplitudeError::WidthMismatch{expected: 2, found: 3}));
+}
+
+#[test]
+fn test_rotations() {
+    let mut state = super::parser::init_state(1, "q");
+    state.ry(0, std::f64::consts::PI);
+    assert_eq!(state.kets.len(), 1);
+    assert_eq!(state.kets[0].get_bit(0), Some(true));
+
+    state.rx(0, std::f64::consts::PI/2.0);
+    state.rz(0, 1.0);
+    let total:f64 = state.kets.iter().map(|ket| ket.get_probability()).sum();
+    assert_eq!(state.kets.len(), 2);
+    assert!((total - 1.0).abs() < 1e-12);
+    assert!((state.kets[0].get_probability() - 0.5).abs() < 1e-12);
+}
+
+#[test]
+fn test_prepare_state_from_amplitudes() {
+    let amplitudes:Vec<ComplexCoefficient> = (0..8).map(|i| {
+        let i = i as f64;
+        complex((1.0 + i).cos(), (2.0*i).sin()/(1.0 + i))
+    }).collect();
+    let norm:f64 = amplitudes.iter().map(|a| a.to_probability()).sum::<f64>().sqrt();
+    let normalized:Vec<ComplexCoefficient> = amplitudes.iter().map(|a| {
+        let mut a = *a;
+        a.multiply_by_number(1.0/norm);
+        a
+    }).collect();
+
+    let gates = super::preparation::prepare(&normalized).unwrap();
+    let mut prepared = super::parser::init_state(3, "q");
+    prepared.apply_gates(&gates);
+
+    let expected = State::from_amplitudes(&normalized, "q").unwrap();
+    assert_eq!(prepared.kets.len(), 8);
+    assert!((fidelity(&prepared, &expected) - 1.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_prepare_sparse_real_state() {
+    let h = std::f64::consts::FRAC_1_SQRT_2;
+    let bell = [complex(h, 0.0), complex(0.0, 0.0), complex(0.0, 0.0), complex(h, 0.0)];
+    let gates = super::preparation::prepare(&bell).unwrap();
+    assert!(gates.iter().all(|gate| gate.get_name() != "rz"));
+
+    let mut prepared = super::parser::init_state(2, "q");
+    prepared.apply_gates(&gates);
+    assert_eq!(prepared.kets.len(), 2);
+    assert!((fidelity(&prepared, &State::from_amplitudes(&bell, "q").unwrap()) - 1.0).abs() < 1e-12);
+
+    assert_eq!(super::preparation::prepare(&bell[..3]).err(), Some(AmplitudeError::NotPowerOfTwo(3)));
+    assert_eq!(super::preparation::prepare(&[complex(0.0, 0.0); 2]).err(), Some(AmplitudeError::ZeroNorm));
+}
+
+#[test]
+fn test_state_postselect() {
+    let h = std::f64::consts::FRAC_1_SQRT_2;
+    let mut state = State::from_amplitudes(&[complex(h, 0.0), complex(0.0, 0.0), complex(0.0, 0.0), complex(h, 0.0)], "q").unwrap();
+
+    assert_eq!(state.postselect(0, false).map(|p| (p - 0.5).abs() < 1e-12), Ok(true));
+    assert_eq!(state.kets.len(), 1);
+    assert_eq!(state.kets[0].get_val().to_int(), Some(0));
+    assert!((state.kets[0].get_probability() - 1.0).abs() < 1e-12);
+
+    assert_eq!(state.postselect(1, true), Err(PostselectionError::ZeroProbability{system: "q".to_string(), qubit: 1, outcome: true}));
+    assert_eq!(state.kets.len(), 1);
+    assert_eq!(state.postselect(2, true), Err(PostselectionError::NonExistentQubit{system: "q".to_string(), qubit: 2}));
+}
+
+#[test]
+fn test_ensemble_cx_pairs_flipped_target_with_control_one() {
+    let mut subsystems:BTreeMap<String, State> = BTreeMap::new();
+    subsystems.insert("p".to_string(), State::from_amplitudes(&[complex(0.6, 0.0), complex(0.8, 0.0)], "p").unwrap());
+    subsystems.insert("q".to_string(), super::parser::init_state(1, "q"));
+    let mut ensemble = super::ensemble::create_ensemble(subsystems);
+    ensemble.cx("p", 0, "q", 0);
+
+    let target = &ensemble.subsystems["q"];
+    assert_eq!(target.kets.len(), 2);
+    for ket in &target.kets {
+        let flipped = ket.get_bit(0) == Some(true);
+        let expected = if flipped { 0.64 } else { 0.36 };
+        assert!((ket.get_probability() - expected).abs() < 1e-12);
+        let entanglements = ket.get_entanglements();
+        assert_eq!(entanglements.len(), 1);
+        assert_eq!(entanglements[0].get_outcome(), flipped);
+    }
+}
+
+#[test]
+fn test_ensemble_measurement_collapses_measured_system() {
+    let h = std::f64::consts::FRAC_1_SQRT_2;
+    let mut subsystems:BTreeMap<String, State> = BTreeMap::new();
+    subsystems.insert("p".to_string(), State::from_amplitudes(&[complex(h, 0.0), complex(h, 0.0)], "p").unwrap());
+    let mut ensemble = super::ensemble::create_ensemble(subsystems);
+
+    let outcome = ensemble.m("p", 0);
+    let measured = &ensemble.subsystems["p"];
+    assert_eq!(measured.kets.len(), 1);
+    assert_eq!(measured.kets[0].get_bit(0), Some(outcome));
+    assert_eq!(ensemble.m("p", 0), outcome);
+}
+
+#[test]
+fn test_ensemble_h_after_cross_system_cx() {
+    let h = std::f64::consts::FRAC_1_SQRT_2;
+    let mut subsystems:BTreeMap<String, State> = BTreeMap::new();
+    subsystems.insert("p".to_string(), State::from_amplitudes(&[complex(h, 0.0), complex(h, 0.0)], "p").unwrap());
+    subsystems.insert("q".to_string(), super::parser::init_state(1, "q"));
+    let mut ensemble = super::ensemble::create_ensemble(subsystems);
+    ensemble.cx("p", 0, "q", 0);
+    ensemble.subsystems.get_mut("q").unwrap().h(0);
+    assert_eq!(ensemble.subsystems["q"].kets.len(), 4);
+
+    let outcome = ensemble.m("p", 0);
+    let target = &ensemble.subsystems["q"];
+    assert_eq!(target.kets.len(), 2);
+    assert!(target.kets.iter().all(|ket| ket.get_entanglements()[0].get_outcome() == outcome));
+}
+
+#[test]
+fn test_ensemble_postselect_collapses_entangled_kets() {
+    let h = std::f64::consts::FRAC_1_SQRT_2;
+    let mut subsystems:BTreeMap<String, State> = BTreeMap::new();
+    subsystems.insert("p".to_string(), State::from_amplitudes(&[complex(h, 0.0), complex(h, 0.0)], "p").unwrap());
+    subsystems.insert("q".to_string(), super::parser::init_state(1, "q"));
+    let mut ensemble = super::ensemble::create_ensemble(subsystems);
+    ensemble.cx("p", 0, "q", 0);
+
+    let probability = ensemble.postselect("p", 0, true).unwrap();
+    assert!((probability - 0.5).abs() < 1e-12);
+
+    let target = &ensemble.subsystems["q"];
+    assert_eq!(target.kets.len(), 1);
+    assert_eq!(target.kets[0].get_bit(0), Some(true));
+    assert!((target.kets[0].get_probability() - 1.0).abs() < 1e-12);
+
+    assert_eq!(ensemble.postselect("z", 0, true), Err(PostselectionError::NonExistentSystem{system: "z".to_string()}));
+}
+
+#[test]
+fn test_measurement_follows_probabilities() {
+    let mut ones = 0;
+    for _ in 0..200 {
+        let mut state = State::from_amplitudes(&[complex(0.5, 0.0), complex(0.75f64.sqrt(), 0.0)], "q").unwrap();
+        if state.m(0) {
+            ones += 1;
+        }
+        assert_eq!(state.kets.len(), 1);
+    }
+    assert!(ones > 100 && ones < 200);
+
+    let state = super::parser::init_state(1, "q");
+    assert!(!state._measure(1.0, 0.0));
+    assert!(state._measure(0.0, 2.0));
+    assert!(!state._measure(0.0, 0.0));
+}
+
+#[test]
+fn test_truncation_tracks_fidelity() {
+    let mut state = super::parser::init_state(1, "q");
+    state.set_truncation(Some(Truncation::Threshold(0.2)));
+    state.ry(0, 0.2);
+    assert_eq!(state.kets.len(), 1);
+    assert!((state.kets[0].get_probability() - 1.0).abs() < 1e-12);
+    assert!((state.get_fidelity() - 0.1f64.cos().powi(2)).abs() < 1e-12);
+
+    let mut state = super::parser::init_state(2, "q");
+    state.set_truncation(Some(Truncation::TopK(2)));
+    state.h(0);
+    assert_eq!(state.kets.len(), 2);
+    state.h(1);
+    assert_eq!(state.kets.len(), 2);
+    assert!((state.get_fidelity() - 0.5).abs() < 1e-12);
+    let total:f64 = state.kets.iter().map(|ket| ket.get_probability()).sum();
+    assert!((total - 1.0).abs() < 1e-12);
+
+    let mut exact = super::parser::init_state(2, "q");
+    exact.h(0);
+    exact.h(1);
+    assert_eq!(exact.kets.len(), 4);
+    assert_eq!(exact.get_fidelity(), 1.0);
+}
+
+#[test]
+fn test_optimization_passes() {
+    let circuit = create_circuit_builder()
+        .qreg("q", 2)
+        .h(("q", 0)).x(("q", 1)).h(("q", 0))
+        .cx(("q", 0), ("q", 1)).rz(0.3, ("q", 1)).rz(-0.3, ("q", 1)).cx(("q", 0), ("q", 1))
+        .ry(0.5, ("q", 0)).ry(0.25, ("q", 0))
+        .z(("q", 1)).barrier().z(("q", 1))
+        .build();
+
+    let (optimized, report) = optimization::default_pass_manager().run(&circuit);
+    let names:Vec<&str> = optimized.get_instructions().iter().map(|instruction| instruction.get_name()).collect();
+    assert_eq!(names, vec!["x", "ry", "z", "barrier", "z"]);
+    assert_eq!(optimized.get_instructions()[1].get_params(), &[0.75]);
+    assert_eq!(report.get_gates_before(), 11);
+    assert_eq!(report.get_gates_after(), 4);
+
+    assert_same_unitary(&unitary::build_circuit_unitary(&circuit).unwrap(), &unitary::build_circuit_unitary(&optimized).unwrap());
+
+    let reset = create_circuit_builder().qreg("q", 2).h(("q", 0)).cx(("q", 1), ("q", 0)).x(("q", 1)).reset(("q", 1)).build();
+    let (optimized, _) = optimization::default_pass_manager().run(&reset);
+    assert_eq!(optimized.get_instructions().len(), 3);
+}
+
+#[test]
+fn test_inverse_and_controlled_circuits() {
+    let circuit = create_circuit_builder()
+        .qreg("q", 2)
+        .h(("q", 0)).cx(("q", 0), ("q", 1)).ry(0.4, ("q", 1)).rz(1.1, ("q", 0)).y(("q", 1))
+        .build();
+
+    let mut round_trip = circuit.clone();
+    let inverse = circuit.inverse().unwrap();
+    for instruction in inverse.get_instructions() {
+        round_trip.push(instruction.clone());
+    }
+    let identity:Vec<Vec<ComplexCoefficient>> = (0..4).map(|row| {
+        (0..4).map(|column| complex(if row == column { 1.0 } else { 0.0 }, 0.0)).collect()
+    }).collect();
+    assert_same_unitary(&identity, &unitary::build_circuit_unitary(&round_trip).unwrap());
+
+    // With the control as the most significant qubit, the controlled unitary is the identity
+    // followed by the original unitary along the diagonal.
+    let original = unitary::build_circuit_unitary(&circuit).unwrap();
+    let controlled = circuit.controlled(1).unwrap();
+    assert_eq!(controlled.get_instructions()[1].get_name(), "ccx");
+    let expected:Vec<Vec<ComplexCoefficient>> = (0..8).map(|row| {
+        (0..8).map(|column| match (row < 4, column < 4) {
+            (true, true) => complex(if row == column { 1.0 } else { 0.0 }, 0.0),
+            (false, false) => original[row - 4][column - 4],
+            _ => complex(0.0, 0.0)
+        }).collect()
+    }).collect();
+    assert_same_unitary(&expected, &unitary::build_circuit_unitary(&controlled).unwrap());
+
+    let measured = create_circuit_builder().qreg("q", 1).creg("c", 1).measure(("q", 0), ("c", 0)).build();
+    assert_eq!(measured.inverse(), Err(unitary::UnitaryError::NonUnitaryOperation("a measurement".to_string())));
+}
+
+#[test]
+fn test_transpile_to_basis() {
+    let circuit = create_circuit_builder()
+        .qreg("q", 3)
+        .h(("q", 0)).gate("ccx", vec![("q", 0).into(), ("q", 1).into(), ("q", 2).into()], vec![])
+        .gate("swap", vec![("q", 1).into(), ("q", 2).into()], vec![])
+        .gate("cp", vec![("q", 2).into(), ("q", 0).into()], vec![0.7])
+        .gate("cu3", vec![("q", 0).into(), ("q", 1).into()], vec![0.3, 1.2, -0.4])
+        .gate("ch", vec![("q", 1).into(), ("q", 2).into()], vec![])
+        .gate("t", vec![("q", 1).into()], vec![]).ry(0.9, ("q", 2)).y(("q", 0))
+        .build();
+    let expected = unitary::build_circuit_unitary(&circuit).unwrap();
+
+    for basis in [&transpiler::IBM_BASIS[..], &transpiler::U3_BASIS[..], &["rz", "ry", "cz"][..]] {
+        let transpiled = transpiler::transpile(&circuit, basis).unwrap();
+        assert!(transpiled.get_instructions().iter().all(|instruction| basis.contains(&instruction.get_name())));
+        let actual = unitary::build_circuit_unitary(&transpiled).unwrap();
+        // Single qubit gates are reproduced up to a global phase.
+        let phase = expected.iter().flatten().zip(actual.iter().flatten())
+            .find(|(expected, _)| expected.modulus() > 0.1)
+            .map(|(expected, actual)| actual.argument() - expected.argument())
+            .unwrap();
+        let rephased:Vec<Vec<ComplexCoefficient>> = expected.iter().map(|row| {
+            row.iter().map(|entry| entry.multiply_by_complex_coefficient(super::coefficient::create_complex_coefficient_from_polar(1.0, phase))).collect()
+        }).collect();
+        assert_same_unitary(&rephased, &actual);
+    }
+
+    assert_eq!(transpiler::transpile(&circuit, &["u3"]).err(), Some(transpiler::TranspileError::UnsupportedBasis("neither cx nor cz is in the basis".to_string())));
+}
+
+#[test]
+fn test_routing_on_coupling_map() {
+    let circuit = create_circuit_builder()
+        .qreg("q", 3)
+        .qreg("a", 2)
+        .h(("q", 0))
+        .cx(("q", 0), ("a", 1))
+        .cx(("q", 1), ("a", 0))
+        .rz(0.4, ("a", 1))
+        .cx(("q", 2), ("q", 0))
+        .cx(("a", 1), ("q", 1))
+        .gate("swap", vec![("q", 0).into(), ("a", 0).into()], vec![])
+        .ry(1.1, ("q", 2))
+        .cx(("a", 0), ("q", 2))
+        .build();
+
+    for coupling_map in [routing::create_line_coupling_map(5), routing::create_ring_coupling_map(6), routing::create_grid_coupling_map(2, 3)].iter() {
+        let routed = routing::route(&circuit, coupling_map).unwrap();
+        for instruction in routed.get_circuit().get_instructions() {
+            if instruction.get_qubits().len() == 2 {
+                let qubits = instruction.get_qubits();
+                assert!(coupling_map.are_coupled(qubits[0].get_index(), qubits[1].get_index()), "{} is not on coupled qubits", instruction);
+            }
+        }
+        assert!(routing::verify_routing(&circuit, &routed).unwrap());
+    }
+
+    let line = routing::create_line_coupling_map(5);
+    let routed = routing::route_with_layout(&circuit, &line, &[0, 1, 2, 3, 4]).unwrap();
+    assert!(routed.get_num_swaps() > 0);
+    assert_eq!(routed.get_initial_layout(), &[0, 1, 2, 3, 4]);
+    assert!(routing::verify_routing(&circuit, &routed).unwrap());
+
+    assert_eq!(routing::route(&circuit, &routing::create_line_coupling_map(4)).err(), Some(routing::RoutingError::TooFewPhysicalQubits(5, 4)));
+    assert_eq!(routing::route_with_layout(&circuit, &line, &[0, 1, 2, 3, 3]).err(), Some(routing::RoutingError::InvalidLayout(vec![0, 1, 2, 3, 3])));
+    assert_eq!(routing::create_coupling_map(2, &[(1, 1)]).err(), Some(routing::RoutingError::InvalidEdge(1, 1)));
+    assert_eq!(routing::create_coupling_map(2, &[(0, 2)]).err(), Some(routing::RoutingError::InvalidEdge(0, 2)));
+
+    // The physical register is renamed rather than clash with a classical register.
+    let circuit = create_circuit_builder().qreg("a", 2).creg("q", 2).h(("a", 0)).cx(("a", 0), ("a", 1)).measure_all("a", "q").build();
+    let routed = routing::route(&circuit, &routing::create_line_coupling_map(2)).unwrap();
+    assert_eq!(routed.get_circuit().get_qregs()[0].get_name(), "q1");
+    let exported = exporter::export_qasm(routed.get_circuit()).unwrap();
+    assert_eq!(super::parser::parse_qasm(&exported).unwrap().get_cregs(), circuit.get_cregs());
+}
+
+#[test]
+fn test_export_state() {
+    let amplitudes = vec![complex(0.5, 0.0), complex(0.0, 0.5), complex(0.0, 0.0), complex(-0.5, 0.5)];
+    let state = State::from_amplitudes(&amplitudes, "q").unwrap();
+
+    let exported = exporter::export_state_qasm(&state).unwrap();
+    let circuit = super::parser::parse_qasm(&exported).unwrap();
+    let unitary = unitary::build_circuit_unitary(&circuit).unwrap();
+    // The first column is the prepared state, up to a global phase.
+    let phase = unitary[0][0].argument();
+    for (row, amplitude) in unitary.iter().zip(amplitudes.iter()) {
+        let expected = amplitude.multiply_by_complex_coefficient(super::coefficient::create_complex_coefficient_from_polar(1.0, phase));
+        let mut difference = row[0];
+        difference.negate_magnitude();
+        assert!(expected.add_to_complex_coefficient(difference).modulus() < 1e-6);
+    }
+}
+
+#[test]
+fn test_evaluate_expression() {
+    let bindings:HashMap<&str, f64> = vec![("theta", 0.5), ("phi", 2.0)].into_iter().collect();
+    let evaluate = |expression:&str| expression::evaluate(expression, &bindings);
+    assert_eq!(evaluate(" - pi / 2 "), Ok(-std::f64::consts::PI/2.0));
+    assert_eq!(evaluate("(theta + phi)*2 - 1e-1"), Ok(4.9));
+    assert_eq!(evaluate("-2^2"), Ok(-4.0));
+    assert_eq!(evaluate("2^3^2"), Ok(512.0));
+    assert_eq!(evaluate("sqrt(phi)*sqrt(2)"), Ok(2.0f64.sqrt()*2.0f64.sqrt()));
+    assert_eq!(evaluate("ln(exp(theta)) + sin(0) + cos(0) + tan(0)"), Ok(1.5));
+    assert_eq!(evaluate("arcsin(1) + arccos(1) + arctan(0) + log(1)"), Ok(std::f64::consts::PI/2.0));
+    // The qasm crate writes `exp` as `^`.
+    assert_eq!(evaluate(" ^ ( 0 ) "), Ok(1.0));
+
+    assert_eq!(evaluate("theta +"), Err(ExpressionError::UnexpectedEnd));
+    assert_eq!(evaluate("(theta"), Err(ExpressionError::UnexpectedEnd));
+    assert_eq!(evaluate("theta phi"), Err(ExpressionError::UnexpectedToken("phi".to_string())));
+    assert_eq!(evaluate("lambda/2"), Err(ExpressionError::UndefinedIdentifier("lambda".to_string())));
+    assert_eq!(evaluate("theta % 2"), Err(ExpressionError::UnexpectedCharacter('%')));
+    assert_eq!(evaluate("1/0"), Err(ExpressionError::NotFinite(f64::INFINITY)));
+    assert!(matches!(evaluate("arcsin(2)"), Err(ExpressionError::NotFinite(value)) if value.is_nan()));
+
+    // Nesting is bounded rather than overflowing the stack.
+    let nested = |depth:usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+    assert_eq!(evaluate(&nested(expression::MAX_DEPTH - 1)), Ok(1.0));
+    assert_eq!(evaluate(&nested(20000)), Err(ExpressionError::TooDeep));
+    assert_eq!(evaluate(&format!("{}1", "-".repeat(20000))), Err(ExpressionError::TooDeep));
+    assert_eq!(evaluate(&format!("{}1{}", "sin(".repeat(20000), ")".repeat(20000))), Err(ExpressionError::TooDeep));
+    assert_eq!(evaluate(&vec!["1"; 20000].join("+")), Ok(20000.0));
+}
+
+fn qasm3_message(source:&str) -> String {
+    match qasm3::parse_qasm3(source) {
+        Ok(_) => panic!("expected {} to fail", source),
+        Err(e) => e.get_message().to_string()
+    }
+}
+
+/// The value of an expression after the declarations, read back from the angle of a gate.
+fn qasm3_value(declarations:&str, expression:&str) -> f64 {
+    let source = format!("OPENQASM 3;\nqubit q;\n{}\nU({}, 0, 0) q;", declarations, expression);
+    let circuit = qasm3::parse_qasm3(&source).unwrap_or_else(|e| panic!("{}: {}", source, e));
+    circuit.get_instructions().last().unwrap().get_params()[0]
+}
+
+#[test]
+fn test_qasm3_lexer() {
+    let tokens = qasm3::lex("x += 1_000; // comment\n/* a\nb */ y = .5e-1 \"f.inc\";").unwrap();
+    let kinds:Vec<qasm3::Token> = tokens.iter().map(|spanned| spanned.token.clone()).collect();
+    assert_eq!(kinds, vec![
+        qasm3::Token::Identifier("x".to_string()), qasm3::Token::Symbol("+="), qasm3::Token::Integer(1000), qasm3::Token::Symbol(";"),
+        qasm3::Token::Identifier("y".to_string()), qasm3::Token::Symbol("="), qasm3::Token::Float(0.05), qasm3::Token::Text("f.inc".to_string()),
+        qasm3::Token::Symbol(";"), qasm3::Token::End
+    ]);
+    assert_eq!(tokens[4].position, (3, 6));
+    assert_eq!(tokens[6].text, ".5e-1");
+
+    // The longest symbol is taken.
+    let symbols:Vec<String> = qasm3::lex("a <<= b << c <= d < e").unwrap().iter().map(|spanned| spanned.text.clone()).collect();
+    assert_eq!(symbols, vec!["a", "<<=", "b", "<<", "c", "<=", "d", "<", "e", ""]);
+
+    assert_eq!(qasm3::lex("x = 1;\n  $").err().map(|e| (e.get_line(), e.get_column())), Some((2, 3)));
+    assert_eq!(qasm3::lex("/* never closed").err().map(|e| e.get_message().to_string()), Some("unterminated comment".to_string()));
+    assert_eq!(qasm3::lex("include \"stdgates.inc;").err().map(|e| e.get_message().to_string()), Some("unterminated string".to_string()));
+    assert_eq!(qasm3::lex("99999999999999999999").err().map(|e| e.get_message().to_string()), Some("invalid number 99999999999999999999".to_string()));
+}
+
+#[test]
+fn test_qasm3_precedence() {
+    assert_eq!(qasm3_value("", "1 + 2*3"), 7.0);
+    assert_eq!(qasm3_value("", "(1 + 2)*3"), 9.0);
+    assert_eq!(qasm3_value("", "10 - 4 - 3"), 3.0);
+    assert_eq!(qasm3_value("", "-2**2"), -4.0);
+    assert_eq!(qasm3_value("", "2**3**2"), 512.0);
+    assert_eq!(qasm3_value("", "1 << 2 + 1"), 8.0);
+    assert_eq!(qasm3_value("", "1 | 2 ^ 3 & 1"), 3.0);
+    assert_eq!(qasm3_value("", "1 < 2 == 2 > 1"), 1.0);
+    assert_eq!(qasm3_value("", "false || true && false"), 0.0);
+    assert_eq!(qasm3_value("", "7/2"), 3.0);
+    assert_eq!(qasm3_value("", "7.0/2"), 3.5);
+    assert_eq!(qasm3_value("", "-7 % 3"), -1.0);
+    assert_eq!(qasm3_value("", "~0"), -1.0);
+    assert_eq!(qasm3_value("", "2*pi - tau"), 0.0);
+}
+
+#[test]
+fn test_qasm3_integer_wrapping() {
+    assert_eq!(qasm3_value("int[8] x = 127;\nx += 1;", "x"), -128.0);
+    assert_eq!(qasm3_value("int[4] x = 8;", "x"), -8.0);
+    assert_eq!(qasm3_value("uint[8] x = 255;\nx += 1;", "x"), 0.0);
+    assert_eq!(qasm3_value("uint[4] x = -1;", "x"), 15.0);
+    assert_eq!(qasm3_value("uint[4] x = 6;\nx <<= 2;", "x"), 8.0);
+    assert_eq!(qasm3_value("int x = 9223372036854775807;\nx += 1;", "x == -9223372036854775807 - 1"), 1.0);
+    assert_eq!(qasm3_value("bool b = 5;", "b"), 1.0);
+    assert_eq!(qasm3_message("OPENQASM 3;\nint x = 1.5;"), "cannot assign Float(1.5) to an integer");
+    assert_eq!(qasm3_message("OPENQASM 3;\nint[65] x;"), "type widths must be integers from 1 to 64");
+}
+
+#[test]
+fn test_qasm3_negative_indices() {
+    let circuit = qasm3::parse_qasm3("OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit[3] q;\nx q[-1];\nx q[-3];").unwrap();
+    let qubits:Vec<usize> = circuit.get_instructions().iter().map(|instruction| instruction.get_qubits()[0].get_index()).collect();
+    assert_eq!(qubits, vec![2, 0]);
+    assert_eq!(qasm3_message("OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit[3] q;\nx q[-4];"), "index -4 is out of range for q[3]");
+    assert_eq!(qasm3_message("OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit[3] q;\nx q[3];"), "index 3 is out of range for q[3]");
+
+    // A condition reads bits counting from the end of its register too.
+    let circuit = qasm3::parse_qasm3("OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit q;\nbit[2] c;\nif (c[-1]) x q;").unwrap();
+    let values:Vec<u64> = circuit.get_instructions().iter().map(|instruction| instruction.get_condition().unwrap().get_value()).collect();
+    assert_eq!(values, vec![2, 3]);
+}
+
+#[test]
+fn test_qasm3_nested_branch_errors() {
+    let header = "OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit q;\nbit a;\nbit b;\nint n = 0;\n";
+    assert_eq!(qasm3_message(&format!("{}if (a) {{ if (b) x q; }}", header)), "a condition on b cannot be nested in one on a");
+    assert_eq!(qasm3_message(&format!("{}if (a) {{ n = 1; }}", header)), "cannot assign n in a branch conditioned on measurements of a");
+    assert_eq!(qasm3_message(&format!("{}if (a) {{ a = measure q; }}", header)), "cannot measure into a in a branch conditioned on it");
+    assert_eq!(qasm3_message(&format!("{}if (a == b) x q;", header)), "a condition can only read one bit register, not a and b");
+    assert_eq!(qasm3_message(&format!("{}n = a;", header)), "measurement results in a can only be read by conditions");
+
+    // Nesting on the same register narrows the values the inner branch runs for.
+    let circuit = qasm3::parse_qasm3("OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit q;\nbit[2] c;\nif (c[0]) { int m = 1; if (c[1]) x q; }").unwrap();
+    let values:Vec<u64> = circuit.get_instructions().iter().map(|instruction| instruction.get_condition().unwrap().get_value()).collect();
+    assert_eq!(values, vec![3]);
+}
+
+#[test]
+fn test_qasm3_error_paths() {
+    let error = qasm3::parse_qasm3("OPENQASM 3;\nqubit q;\n  h q;").err().unwrap();
+    assert_eq!((error.get_line(), error.get_column()), (3, 3));
+    assert_eq!(error.get_message(), "unknown gate h (is stdgates.inc included?)");
+    assert_eq!(error.to_string(), "line 3, column 3: unknown gate h (is stdgates.inc included?)");
+
+    assert_eq!(qasm3_message("OPENQASM 2.0;"), "only OpenQASM 3 is supported");
+    assert_eq!(qasm3_message("OPENQASM 3;\nqubit q\nbit c;"), "expected `;`, found bit");
+    assert_eq!(qasm3_message("OPENQASM 3;\nqubit q;\nwhile (true) { }"), "unsupported statement while");
+    assert_eq!(qasm3_message("OPENQASM 3;\ninclude \"other.inc\";"), "cannot include other.inc");
+    assert_eq!(qasm3_message("OPENQASM 3;\nqubit q;\nqubit q;"), "q is already declared");
+    assert_eq!(qasm3_message("OPENQASM 3;\nqubit[0] q;"), "register size 0 is not positive");
+    assert_eq!(qasm3_message("OPENQASM 3;\nfor int i in [0:1] { qubit q; }"), "registers and gates can only be declared globally");
+    assert_eq!(qasm3_message("OPENQASM 3;\nconst int n;"), "constant n has no value");
+    assert_eq!(qasm3_message("OPENQASM 3;\nconst int n = 1;\nn = 2;"), "cannot assign constant n");
+    assert_eq!(qasm3_message("OPENQASM 3;\nm = 2;"), "undeclared variable m");
+    assert_eq!(qasm3_message("OPENQASM 3;\nqubit q;\nU(unknown, 0, 0) q;"), "undeclared identifier unknown");
+    assert_eq!(qasm3_message("OPENQASM 3;\nqubit q;\nU(atan2(1), 0, 0) q;"), "unknown function atan2");
+    assert_eq!(qasm3_message("OPENQASM 3;\nqubit q;\nreset r;"), "undeclared qubit register r");
+    assert_eq!(qasm3_message("OPENQASM 3;\nqubit q;\nmeasure q -> c;"), "undeclared bit register c");
+    assert_eq!(qasm3_message("OPENQASM 3;\nint x = 1/0;"), "division by zero");
+    assert_eq!(qasm3_message("OPENQASM 3;\nqubit q;\nU(1.0/0, 0, 0) q;"), "parameter of U: expression evaluates to inf");
+    assert_eq!(qasm3_message("OPENQASM 3;\nqubit q;\nU(sqrt(-1), 0, 0) q;"), "parameter of U: expression evaluates to NaN");
+    assert_eq!(qasm3_message("OPENQASM 3;\nfor int i in [0:0:3] { }"), "loop step cannot be zero");
+    assert_eq!(qasm3_message("OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit[2] q;\ncx q[0], q[0];"), "cx is applied to the same qubit more than once");
+    assert_eq!(qasm3_message("OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit q;\nrx q;"), "gate rx takes 1 parameters and 1 qubits");
+    assert_eq!(qasm3_message("OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit q;\nctrl(2) @ x q;"), "x has 2 control qubits but only 1 qubits");
+    assert_eq!(qasm3_message("OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit q;\npow(0.5) @ h q;"), "only rotations can be raised to fractional powers");
+    assert_eq!(
+        qasm3_message("OPENQASM 3;\nqubit q;\ngate g a { g a; }\ng q;"),
+        format!("gate definitions are nested more than {} deep", MAX_GATE_DEPTH)
+    );
+    assert_eq!(qasm3_message("OPENQASM 3;\nqubit q;\ngate g a { U(0, 0, 0) q; }\ng q;"), "gate bodies can only use their arguments, not q");
+}
+
+const MINIMUM_INT:&str = "OPENQASM 3;\nint x = -9223372036854775807 - 1;\n";
+
+#[test]
+fn test_qasm3_division_overflow() {
+    assert_eq!(qasm3_message(&format!("{}int y = x / -1;", MINIMUM_INT)), "-9223372036854775808 / -1 overflows");
+    assert_eq!(qasm3_message(&format!("{}int y = x % -1;", MINIMUM_INT)), "-9223372036854775808 % -1 overflows");
+    assert!(qasm3::parse_qasm3(&format!("{}int y = x / 2;", MINIMUM_INT)).is_ok());
+}
+
+#[test]
+fn test_qasm3_negation_overflow() {
+    assert_eq!(qasm3_message(&format!("{}int y = -x;", MINIMUM_INT)), "-(-9223372036854775808) overflows");
+    assert!(qasm3::parse_qasm3(&format!("{}int y = -(x + 1);", MINIMUM_INT)).is_ok());
+}
+
+#[test]
+fn test_qasm3_power_within_instruction_limit() {
+    let source = "OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit q;\npow(20000000) @ x q;";
+    let start = std::time::Instant::now();
+    assert_eq!(qasm3_message(source), format!("program unrolls to more than {} instructions", MAX_INSTRUCTIONS));
+    assert!(start.elapsed().as_secs() < 1);
+
+    let source = "OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit q;\nx q;\npow(-999999) @ x q;";
+    assert_eq!(qasm3::parse_qasm3(source).unwrap().get_instructions().len(), MAX_INSTRUCTIONS);
+    let source = "OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit q;\nx q;\npow(1000000) @ x q;";
+    assert_eq!(qasm3_message(source), format!("program unrolls to more than {} instructions", MAX_INSTRUCTIONS));
+}
+
+#[test]
+fn test_qasm3_expression_depth() {
+    let limit = format!("expression is nested more than {} deep", expression::MAX_DEPTH);
+    let parameter = |expression:String| qasm3_message(&format!("OPENQASM 3;\nqubit q;\nU({}, 0, 0) q;", expression));
+    let nested = |depth:usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+    assert_eq!(qasm3_value("", &nested(expression::MAX_DEPTH - 1)), 1.0);
+    assert_eq!(parameter(nested(20000)), limit);
+    assert_eq!(parameter(format!("{}1", "-".repeat(20000))), limit);
+    assert_eq!(parameter(format!("{}1{}", "sin(".repeat(20000), ")".repeat(20000))), limit);
+    // A long chain of operators nests as deeply as it is long.
+    assert_eq!(parameter(vec!["1"; 20000].join("+")), limit);
+    assert_eq!(parameter(vec!["2"; 20000].join("**")), limit);
+    assert_eq!(qasm3_value("", &vec!["1"; expression::MAX_DEPTH].join("+")), expression::MAX_DEPTH as f64);
+    let error = parser::parse_qasm(&format!("OPENQASM 3;\nqubit q;\nU({}, 0, 0) q;", nested(20000))).unwrap_err();
+    assert_eq!(error.get_kind(), "resource_limit");
+}
+
+#[test]
+fn test_qasm3_step_limit() {
+    let limit = format!("program takes more than {} steps to unroll", qasm3::MAX_STEPS);
+    assert_eq!(qasm3_message("OPENQASM 3;\nfor int i in [0:99999] { for int j in [0:99999] { } }"), limit);
+    // Applying a gate is a step even when its definition is empty.
+    assert_eq!(qasm3_message("OPENQASM 3;\nqubit q;\ngate f a { }\ngate g a { f a; }\nfor int i in [0:999999] { g q; }"), limit);
+}
+
+#[test]
+fn test_qasm3_range_overflow() {
+    assert_eq!(
+        qasm3_message("OPENQASM 3;\nfor int i in [-9223372036854775807:9223372036854775807] { }"),
+        format!("loop has more than {} iterations", MAX_INSTRUCTIONS)
+    );
+    assert_eq!(
+        qasm3_message("OPENQASM 3;\nfor int i in [9223372036854775807:-1:-9223372036854775807] { }"),
+        format!("loop has more than {} iterations", MAX_INSTRUCTIONS)
+    );
+}
+
+
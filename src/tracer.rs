@@ -0,0 +1,151 @@
+//! # Tracer
+//! Observers that are notified of every operation the simulator performs, so that execution can be
+//! logged or recorded without the gates themselves writing to standard output.
+
+use std::sync::{Arc, Mutex};
+use crate::ket::Ket;
+
+#[derive(Clone)]
+pub struct TraceEvent {
+    operation: String,
+    qubits: Vec<(String, usize)>,
+    params: Vec<f64>,
+    before: Option<Vec<Ket>>,
+    after: Option<Vec<Ket>>
+}
+
+/// Initializes a trace event for an operation on the given (system, qubit) pairs.
+pub fn create_trace_event(operation:&str, qubits:Vec<(String, usize)>, params:Vec<f64>, before:Option<Vec<Ket>>, after:Option<Vec<Ket>>) -> TraceEvent {
+    TraceEvent{operation: operation.to_string(), qubits, params, before, after}
+}
+
+impl TraceEvent {
+
+    /// The name of the operation, e.g. `h`, `cx`, `measure` or `normalize`.
+    pub fn get_operation(&self) -> &str {
+        &self.operation
+    }
+
+    /// The qubits operated on, as (system, qubit) pairs with controls first.
+    pub fn get_qubits(&self) -> &[(String, usize)] {
+        &self.qubits
+    }
+
+    /// Numeric parameters of the operation, such as a measurement outcome or normalizing factor.
+    pub fn get_params(&self) -> &[f64] {
+        &self.params
+    }
+
+    /// The kets of the affected state before the operation, if snapshots were requested.
+    pub fn get_before(&self) -> Option<&Vec<Ket>> {
+        self.before.as_ref()
+    }
+
+    /// The kets of the affected state after the operation, if snapshots were requested.
+    pub fn get_after(&self) -> Option<&Vec<Ket>> {
+        self.after.as_ref()
+    }
+
+    /// Formats the operation and its qubits, e.g. `cx (0 -> 1)` or `cx (q[0] -> r[1])`.
+    pub fn label(&self) -> String {
+        let spans_systems = self.qubits.windows(2).any(|pair| pair[0].0 != pair[1].0);
+        let qubits:Vec<String> = self.qubits.iter().map(|(system, qubit)| {
+            if spans_systems { format!("{}[{}]", system, qubit) } else { qubit.to_string() }
+        }).collect();
+        format!("{} ({})", self.operation, qubits.join(" -> "))
+    }
+}
+
+/// Receives a notification for every operation applied to a state.
+pub trait Tracer: Send + Sync {
+
+    /// Whether the simulator should capture ket snapshots before and after each operation.
+    /// Snapshots clone the full ket list, so they are only taken when a tracer asks for them.
+    fn wants_snapshots(&self) -> bool {
+        false
+    }
+
+    /// Called once an operation has been applied.
+    fn on_operation(&self, event:&TraceEvent);
+}
+
+/// A tracer which ignores all events. This is the default for new states.
+pub struct NoopTracer;
+
+impl Tracer for NoopTracer {
+    fn on_operation(&self, _event:&TraceEvent) {}
+}
+
+/// Returns a shared handle to the no-op tracer.
+pub fn noop_tracer() -> Arc<dyn Tracer> {
+    Arc::new(NoopTracer)
+}
+
+/// A tracer which prints each operation and the kets it transforms to standard output.
+pub struct PrintTracer;
+
+impl Tracer for PrintTracer {
+    fn wants_snapshots(&self) -> bool {
+        true
+    }
+
+    fn on_operation(&self, event:&TraceEvent) {
+        match event.get_operation() {
+            "normalize" => println!("normalizing factor: {}", event.get_params()[0]),
+            "measure" => println!("{} = {}", event.label(), event.get_params()[0]),
+            _ => {
+                let before = event.get_before().cloned().unwrap_or_default();
+                let after = event.get_after().cloned().unwrap_or_default();
+                if before.len() == after.len() {
+                    for (old, new) in before.iter().zip(after.iter()) {
+                        println!("{}{} ={}", event.label(), old, new);
+                    }
+                }
+                else {
+                    let old:String = before.iter().map(|ket| ket.to_string()).collect();
+                    let new:String = after.iter().map(|ket| ket.to_string()).collect();
+                    println!("{}{} ={}", event.label(), old, new);
+                }
+            }
+        }
+    }
+}
+
+/// Returns a shared handle to a new printing tracer.
+pub fn print_tracer() -> Arc<dyn Tracer> {
+    Arc::new(PrintTracer)
+}
+
+/// A tracer which records every event into a timeline for later inspection.
+pub struct RecordingTracer {
+    snapshots: bool,
+    timeline: Mutex<Vec<TraceEvent>>
+}
+
+/// Initializes a recording tracer, optionally capturing ket snapshots with each event.
+pub fn create_recording_tracer(snapshots:bool) -> Arc<RecordingTracer> {
+    Arc::new(RecordingTracer{snapshots, timeline: Mutex::new(vec![])})
+}
+
+impl RecordingTracer {
+
+    /// Returns a copy of the events recorded so far, in the order they occurred.
+    pub fn get_timeline(&self) -> Vec<TraceEvent> {
+        self.timeline.lock().unwrap().clone()
+    }
+
+    /// Discards all recorded events.
+    pub fn clear(&self) {
+        self.timeline.lock().unwrap().clear();
+    }
+}
+
+impl Tracer for RecordingTracer {
+    fn wants_snapshots(&self) -> bool {
+        self.snapshots
+    }
+
+    fn on_operation(&self, event:&TraceEvent) {
+        self.timeline.lock().unwrap().push(event.clone());
+    }
+}
@@ -0,0 +1,195 @@
+//! # Transpiler
+//! Rewriting of circuits into a target basis of gates, so that the gate sets of real hardware can
+//! be emulated. Multi-qubit gates are first decomposed into `cx` and single qubit gates through a
+//! library of standard decompositions, and every single qubit gate is then resynthesized from its
+//! Euler angles using whichever rotations the basis provides. Single qubit gates are reproduced up
+//! to a global phase, which cannot be observed.
+
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fmt;
+use crate::circuit;
+use crate::circuit::{Bit, Circuit, Instruction};
+use crate::coefficient::ComplexCoefficient;
+use crate::gate;
+
+/// The basis of IBM's superconducting devices.
+pub const IBM_BASIS:[&str; 4] = ["rz", "sx", "x", "cx"];
+
+/// A basis of arbitrary single qubit rotations and `cx`.
+pub const U3_BASIS:[&str; 2] = ["u3", "cx"];
+
+/// Angles within this distance of a multiple of 2π are treated as zero.
+pub const ANGLE_TOLERANCE:f64 = 1e-12;
+
+#[derive(Debug, PartialEq)]
+pub enum TranspileError {
+    /// The circuit uses a gate with no known decomposition.
+    UnsupportedGate(String),
+    /// The basis cannot express some gate, e.g. because it has no entangling gate.
+    UnsupportedBasis(String)
+}
+
+impl fmt::Display for TranspileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TranspileError::UnsupportedGate(gate) => write!(f, "no decomposition of gate {}", gate),
+            TranspileError::UnsupportedBasis(reason) => write!(f, "unsupported basis: {}", reason)
+        }
+    }
+}
+
+impl Error for TranspileError {}
+
+/// Rewrites a circuit so that every gate is in the basis. Measurements, resets and barriers are
+/// kept as they are, and each gate's classical condition is carried over to its decomposition.
+pub fn transpile(circuit:&Circuit, basis:&[&str]) -> Result<Circuit, TranspileError> {
+    let mut instructions:Vec<Instruction> = vec![];
+    for instruction in circuit.get_instructions() {
+        lower(instruction, basis, &mut instructions)?;
+    }
+    let mut transpiled = circuit.clone();
+    transpiled.set_instructions(instructions);
+    Ok(transpiled)
+}
+
+/// Appends the basis gates implementing an instruction.
+fn lower(instruction:&Instruction, basis:&[&str], output:&mut Vec<Instruction>) -> Result<(), TranspileError> {
+    if !instruction.is_gate() || basis.contains(&instruction.get_name()) {
+        output.push(instruction.clone());
+        return Ok(());
+    }
+    let qubits = instruction.get_qubits();
+    if qubits.len() == 1 {
+        let matrix = match gate::get_matrix(instruction.get_name(), instruction.get_params()) {
+            Some(matrix) if gate::get_signature(instruction.get_name()) == Some((1, instruction.get_params().len())) => matrix,
+            _ => return Err(TranspileError::UnsupportedGate(instruction.to_string()))
+        };
+        let (theta, phi, lambda, _) = get_euler_angles(&matrix);
+        let synthesized = synthesize(&qubits[0], theta, phi, lambda, basis)?;
+        for mut gate in synthesized {
+            gate.set_condition(instruction.get_condition().cloned());
+            output.push(gate);
+        }
+        return Ok(());
+    }
+    for mut gate in decompose(instruction, basis)? {
+        gate.set_condition(instruction.get_condition().cloned());
+        lower(&gate, basis, output)?;
+    }
+    Ok(())
+}
+
+/// Decomposes a multi-qubit gate into `cx`, `ccx` and single qubit gates, or into `cz` if the
+/// basis has no `cx`.
+fn decompose(instruction:&Instruction, basis:&[&str]) -> Result<Vec<Instruction>, TranspileError> {
+    let q = instruction.get_qubits();
+    let params = instruction.get_params();
+    let g = |name:&str, qubits:&[&Bit], params:Vec<f64>| circuit::create_instruction(name, qubits.iter().map(|qubit| (*qubit).clone()).collect(), params);
+    let gates = match (instruction.get_name(), q.len(), params.len()) {
+        ("cx", 2, 0) => {
+            if !basis.contains(&"cz") {
+                return Err(TranspileError::UnsupportedBasis("neither cx nor cz is in the basis".to_string()));
+            }
+            vec![g("h", &[&q[1]], vec![]), g("cz", &[&q[0], &q[1]], vec![]), g("h", &[&q[1]], vec![])]
+        },
+        ("swap", 2, 0) => vec![g("cx", &[&q[0], &q[1]], vec![]), g("cx", &[&q[1], &q[0]], vec![]), g("cx", &[&q[0], &q[1]], vec![])],
+        ("rzz", 2, 1) => vec![g("cx", &[&q[0], &q[1]], vec![]), g("rz", &[&q[1]], vec![params[0]]), g("cx", &[&q[0], &q[1]], vec![])],
+        ("rxx", 2, 1) => vec![
+            g("h", &[&q[0]], vec![]), g("h", &[&q[1]], vec![]),
+            g("rzz", &[&q[0], &q[1]], vec![params[0]]),
+            g("h", &[&q[0]], vec![]), g("h", &[&q[1]], vec![])
+        ],
+        ("ccx", 3, 0) => vec![
+            g("h", &[&q[2]], vec![]), g("cx", &[&q[1], &q[2]], vec![]), g("tdg", &[&q[2]], vec![]),
+            g("cx", &[&q[0], &q[2]], vec![]), g("t", &[&q[2]], vec![]), g("cx", &[&q[1], &q[2]], vec![]),
+            g("tdg", &[&q[2]], vec![]), g("cx", &[&q[0], &q[2]], vec![]), g("t", &[&q[1]], vec![]),
+            g("t", &[&q[2]], vec![]), g("h", &[&q[2]], vec![]), g("cx", &[&q[0], &q[1]], vec![]),
+            g("t", &[&q[0]], vec![]), g("tdg", &[&q[1]], vec![]), g("cx", &[&q[0], &q[1]], vec![])
+        ],
+        ("ccz", 3, 0) => vec![g("h", &[&q[2]], vec![]), g("ccx", &[&q[0], &q[1], &q[2]], vec![]), g("h", &[&q[2]], vec![])],
+        ("cswap", 3, 0) => vec![g("cx", &[&q[2], &q[1]], vec![]), g("ccx", &[&q[0], &q[1], &q[2]], vec![]), g("cx", &[&q[2], &q[1]], vec![])],
+        (name, 2, _) => {
+            // Any other singly controlled gate: the controlled u3 of its Euler angles, with its
+            // global phase applied to the control.
+            let matrix = match gate::split_controls(name) {
+                Some((1, base)) if gate::get_signature(name) == Some((2, params.len())) => gate::get_matrix(base, params).unwrap(),
+                _ => return Err(TranspileError::UnsupportedGate(instruction.to_string()))
+            };
+            let (theta, phi, lambda, alpha) = get_euler_angles(&matrix);
+            vec![
+                g("u1", &[&q[0]], vec![alpha + (lambda + phi)/2.0]),
+                g("u1", &[&q[1]], vec![(lambda - phi)/2.0]),
+                g("cx", &[&q[0], &q[1]], vec![]),
+                g("u3", &[&q[1]], vec![-theta/2.0, 0.0, -(phi + lambda)/2.0]),
+                g("cx", &[&q[0], &q[1]], vec![]),
+                g("u3", &[&q[1]], vec![theta/2.0, phi, 0.0])
+            ]
+        },
+        _ => return Err(TranspileError::UnsupportedGate(instruction.to_string()))
+    };
+    Ok(gates)
+}
+
+/// The angles `(θ, φ, λ, α)` for which a single qubit unitary equals `e^{iα} u3(θ, φ, λ)`.
+fn get_euler_angles(matrix:&[[ComplexCoefficient; 2]; 2]) -> (f64, f64, f64, f64) {
+    let [[a, b], [c, d]] = *matrix;
+    let mut negative_b = b;
+    negative_b.negate_magnitude();
+    let theta = 2.0*c.modulus().atan2(a.modulus());
+    if a.modulus() < ANGLE_TOLERANCE {
+        let alpha = negative_b.argument();
+        (theta, c.argument() - alpha, 0.0, alpha)
+    }
+    else if c.modulus() < ANGLE_TOLERANCE {
+        let alpha = a.argument();
+        (theta, d.argument() - alpha, 0.0, alpha)
+    }
+    else {
+        let alpha = a.argument();
+        (theta, c.argument() - alpha, negative_b.argument() - alpha, alpha)
+    }
+}
+
+/// The gates of the basis implementing `u3(θ, φ, λ)` up to a global phase.
+fn synthesize(qubit:&Bit, theta:f64, phi:f64, lambda:f64, basis:&[&str]) -> Result<Vec<Instruction>, TranspileError> {
+    let g = |name:&str, params:Vec<f64>| circuit::create_instruction(name, vec![qubit.clone()], params);
+    if let Some(name) = ["u3", "u"].iter().find(|name| basis.contains(name)) {
+        return Ok(vec![g(name, vec![theta, phi, lambda])]);
+    }
+    let z = match ["rz", "p", "u1"].iter().find(|name| basis.contains(name)) {
+        Some(z) => *z,
+        None => return Err(TranspileError::UnsupportedBasis("no single qubit rotations about Z".to_string()))
+    };
+
+    let mut gates:Vec<Instruction> = vec![];
+    if is_zero(theta) {
+        gates.push(g(z, vec![normalize(phi + lambda)]));
+    }
+    else if basis.contains(&"ry") {
+        gates.extend(vec![g(z, vec![normalize(lambda)]), g("ry", vec![normalize(theta)]), g(z, vec![normalize(phi)])]);
+    }
+    else if basis.contains(&"sx") {
+        gates.extend(vec![
+            g(z, vec![normalize(lambda)]), g("sx", vec![]), g(z, vec![normalize(theta + PI)]), g("sx", vec![]), g(z, vec![normalize(phi + PI)])
+        ]);
+    }
+    else if basis.contains(&"rx") {
+        gates.extend(vec![g(z, vec![normalize(lambda - PI/2.0)]), g("rx", vec![normalize(theta)]), g(z, vec![normalize(phi + PI/2.0)])]);
+    }
+    else {
+        return Err(TranspileError::UnsupportedBasis("no single qubit rotations about X or Y".to_string()));
+    }
+    gates.retain(|gate| gate.get_name() != z || !is_zero(gate.get_params()[0]));
+    Ok(gates)
+}
+
+fn is_zero(angle:f64) -> bool {
+    normalize(angle).abs() < ANGLE_TOLERANCE
+}
+
+/// The angle equivalent to the given one in the range (-π, π].
+fn normalize(angle:f64) -> f64 {
+    let wrapped = (angle + PI).rem_euclid(2.0*PI) - PI;
+    if wrapped <= -PI + ANGLE_TOLERANCE { PI } else { wrapped }
+}
@@ -0,0 +1,50 @@
+//! # Truncation
+//! Policies for approximate simulation of wide circuits. After each gate, kets with negligible
+//! amplitude are discarded so that the ket list stays small. The probability carried away by each
+//! truncation is recorded, and the product of the probability kept at every step is a lower bound
+//! on the fidelity of the approximate state with the exact one.
+
+use serde::{Deserialize, Serialize};
+use crate::ket::Ket;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Truncation {
+    /// Discard kets whose amplitude has a modulus below the threshold.
+    Threshold(f64),
+    /// Keep only the given number of most probable kets.
+    TopK(usize)
+}
+
+/// Applies the policy to a list of kets, returning the kets kept, in their original order, and
+/// the fraction of the total probability they carry. The most probable ket is always kept, so a
+/// non-empty list never truncates to nothing.
+pub fn truncate(kets:Vec<Ket>, policy:Truncation) -> (Vec<Ket>, f64) {
+    let total_probability:f64 = kets.iter().map(|ket| ket.get_probability()).sum();
+    if kets.is_empty() || total_probability == 0.0 {
+        return (kets, 1.0);
+    }
+
+    let mut by_probability:Vec<usize> = (0..kets.len()).collect();
+    by_probability.sort_by(|a, b| kets[*b].get_probability().total_cmp(&kets[*a].get_probability()));
+    let mut keep = vec![false; kets.len()];
+    keep[by_probability[0]] = true;
+
+    match policy {
+        Truncation::Threshold(threshold) => {
+            for (index, ket) in kets.iter().enumerate() {
+                if ket.get_coefficient().modulus() >= threshold {
+                    keep[index] = true;
+                }
+            }
+        },
+        Truncation::TopK(count) => {
+            for index in by_probability.into_iter().take(count) {
+                keep[index] = true;
+            }
+        }
+    }
+
+    let kept:Vec<Ket> = kets.into_iter().zip(keep).filter(|(_, keep)| *keep).map(|(ket, _)| ket).collect();
+    let kept_probability:f64 = kept.iter().map(|ket| ket.get_probability()).sum();
+    (kept, kept_probability/total_probability)
+}
@@ -0,0 +1,106 @@
+//! # Unitary
+//! Extraction of the dense unitary matrix implemented by a measurement-free sequence of gates, by
+//! evolving every computational basis state through the simulator's gate implementations.
+
+use std::error::Error;
+use std::fmt;
+use crate::basis;
+use crate::circuit;
+use crate::circuit::Circuit;
+use crate::coefficient;
+use crate::coefficient::ComplexCoefficient;
+use crate::gate;
+use crate::gate::Gate;
+use crate::state::State;
+
+/// The widest circuit whose unitary will be built. A 10 qubit unitary has roughly a million
+/// entries, and each additional qubit quadruples that.
+pub const MAX_UNITARY_QUBITS:usize = 10;
+
+#[derive(Debug, PartialEq)]
+pub enum UnitaryError {
+    /// The program could not be lexed or parsed.
+    Parse(String),
+    /// The program measures, resets or conditions on classical bits, so it is not unitary.
+    NonUnitaryOperation(String),
+    /// The program uses a gate the simulator does not implement.
+    UnsupportedGate(String),
+    /// The program refers to a register which was never declared.
+    UndeclaredRegister(String),
+    /// A gate acts on a qubit beyond the end of its register, e.g. `q[3]`.
+    QubitOutOfRange(String),
+    /// The program has more qubits than `MAX_UNITARY_QUBITS`.
+    TooManyQubits(usize)
+}
+
+impl fmt::Display for UnitaryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnitaryError::Parse(message) => write!(f, "could not parse program: {}", message),
+            UnitaryError::NonUnitaryOperation(operation) => write!(f, "program is not unitary: it contains {}", operation),
+            UnitaryError::UnsupportedGate(gate) => write!(f, "unsupported gate {}", gate),
+            UnitaryError::UndeclaredRegister(register) => write!(f, "undeclared register {}", register),
+            UnitaryError::QubitOutOfRange(qubit) => write!(f, "qubit {} is out of range", qubit),
+            UnitaryError::TooManyQubits(num_qubits) => write!(f, "program has {} qubits, more than the {} a unitary can be built for", num_qubits, MAX_UNITARY_QUBITS)
+        }
+    }
+}
+
+impl Error for UnitaryError {}
+
+/// Builds the 2^n x 2^n unitary implemented by the gates, indexed `[row][column]`, where bit `i`
+/// of a row or column index is the value of qubit `i`. Column `j` is the state the gates produce
+/// from basis state `j`.
+pub fn build_unitary(gates:&[Gate], num_qubits:usize) -> Result<Vec<Vec<ComplexCoefficient>>, UnitaryError> {
+    if num_qubits > MAX_UNITARY_QUBITS {
+        return Err(UnitaryError::TooManyQubits(num_qubits));
+    }
+    for gate in gates {
+        if gate::get_signature(gate.get_name()) != Some((gate.get_qubits().len(), gate.get_params().len())) {
+            return Err(UnitaryError::UnsupportedGate(gate.to_string()));
+        }
+        if let Some(qubit) = gate.get_qubits().iter().find(|qubit| **qubit >= num_qubits) {
+            return Err(UnitaryError::QubitOutOfRange(format!("{} of {}", qubit, gate)));
+        }
+    }
+    let dimension = 1usize << num_qubits;
+    let zero = coefficient::create_complex_coefficient_from_parts(0.0, 0.0);
+    let one = coefficient::create_complex_coefficient_from_parts(1.0, 0.0);
+    let mut unitary = vec![vec![zero; dimension]; dimension];
+
+    for column in 0..dimension {
+        let input = basis::create_basis_state_from_int(column as u128, num_qubits);
+        let mut state = State::from_sparse(vec![(input, one)], num_qubits, "u").expect("a basis state is a valid state");
+        state.apply_gates(gates);
+        for ket in &state.kets {
+            let row = &mut unitary[ket.get_val().to_int().unwrap_or(0) as usize];
+            row[column] = row[column].add_to_complex_coefficient(ket.get_coefficient());
+        }
+    }
+    Ok(unitary)
+}
+
+/// Builds the unitary implemented by a circuit, with its qubits numbered across registers in
+/// declaration order. Barriers are ignored, and measurements, resets and classically conditioned
+/// instructions are rejected.
+pub fn build_circuit_unitary(circuit:&Circuit) -> Result<Vec<Vec<ComplexCoefficient>>, UnitaryError> {
+    let mut gates:Vec<Gate> = vec![];
+    for instruction in circuit.get_instructions() {
+        circuit::check_unitary(instruction)?;
+        if instruction.get_name() == circuit::BARRIER {
+            continue;
+        }
+        let mut qubits:Vec<usize> = vec![];
+        for qubit in instruction.get_qubits() {
+            match circuit.get_qubit_index(qubit) {
+                Some(index) => qubits.push(index),
+                None if circuit.get_qregs().iter().any(|register| register.get_name() == qubit.get_register()) => {
+                    return Err(UnitaryError::QubitOutOfRange(qubit.to_string()));
+                },
+                None => return Err(UnitaryError::UndeclaredRegister(qubit.get_register().to_string()))
+            }
+        }
+        gates.push(gate::create_gate(instruction.get_name(), qubits, instruction.get_params().to_vec()));
+    }
+    build_unitary(&gates, circuit.num_qubits())
+}
@@ -0,0 +1,70 @@
+//! # Validation
+//! Semantic checks of a circuit before it is simulated: that every bit it refers to belongs to a
+//! declared register, that no name is declared twice, that no gate acts on the same qubit twice and
+//! that every gate is one the simulator implements, with the right numbers of qubits and
+//! parameters. Every problem is reported at once, rather than the first one found partway through
+//! a simulation.
+
+use std::collections::HashSet;
+use crate::circuit;
+use crate::circuit::{Bit, Circuit, Instruction, Register};
+use crate::gate;
+use crate::parser::SimError;
+
+/// Every problem which would stop the circuit from being simulated, in the order of its registers
+/// and then its instructions. The circuit can be simulated if there are none.
+pub fn validate_circuit(circuit:&Circuit) -> Vec<SimError> {
+    let mut diagnostics:Vec<SimError> = vec![];
+    let mut names:HashSet<&str> = HashSet::new();
+    for register in circuit.get_qregs().iter().chain(circuit.get_cregs()) {
+        if !names.insert(register.get_name()) {
+            diagnostics.push(SimError::DuplicateDeclaration(register.get_name().to_string()));
+        }
+    }
+    for instruction in circuit.get_instructions() {
+        diagnostics.extend(validate_instruction(instruction, circuit));
+    }
+    diagnostics
+}
+
+fn validate_instruction(instruction:&Instruction, circuit:&Circuit) -> Vec<SimError> {
+    let qubits = instruction.get_qubits();
+    let mut diagnostics:Vec<SimError> = qubits.iter().filter_map(|qubit| check_bit(qubit, circuit.get_qregs()).err())
+        .chain(instruction.get_clbits().iter().filter_map(|clbit| check_bit(clbit, circuit.get_cregs()).err()))
+        .collect();
+    if let Some(condition) = instruction.get_condition() {
+        if !circuit.get_cregs().iter().any(|creg| creg.get_name() == condition.get_register()) {
+            diagnostics.push(SimError::UndeclaredRegister(condition.get_register().to_string()));
+        }
+    }
+    let repeated = qubits.iter().enumerate().any(|(index, qubit)| qubits[..index].contains(qubit));
+    if repeated && instruction.get_name() != circuit::BARRIER {
+        diagnostics.push(SimError::RepeatedQubit(instruction.to_string()));
+    }
+
+    let name = instruction.get_name();
+    if [circuit::MEASURE, circuit::RESET, circuit::BARRIER].contains(&name) {
+        return diagnostics;
+    }
+    match gate::get_signature(name) {
+        None => diagnostics.push(SimError::UnsupportedGate(instruction.to_string())),
+        Some((num_qubits, num_params)) if (num_qubits, num_params) != (qubits.len(), instruction.get_params().len()) => {
+            diagnostics.push(SimError::ArityMismatch(format!("gate {} takes {} parameters and {} qubits", name, num_params, num_qubits)));
+        },
+        // Only cx can act between the subsystems registers are simulated as.
+        _ if name != "cx" && qubits.iter().any(|qubit| qubit.get_register() != qubits[0].get_register()) => {
+            diagnostics.push(SimError::UnsupportedGate(instruction.to_string()));
+        },
+        _ => {}
+    }
+    diagnostics
+}
+
+/// Checks that a bit belongs to one of the registers.
+pub(crate) fn check_bit(bit:&Bit, registers:&[Register]) -> Result<(), SimError> {
+    match registers.iter().find(|register| register.get_name() == bit.get_register()) {
+        Some(register) if bit.get_index() < register.get_size() => Ok(()),
+        Some(register) => Err(SimError::IndexOutOfRange{register: bit.get_register().to_string(), index: bit.get_index(), size: register.get_size()}),
+        None => Err(SimError::UndeclaredRegister(bit.get_register().to_string()))
+    }
+}
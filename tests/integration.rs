@@ -1,7 +1,18 @@
 extern crate qasm;
 
 use std::collections::BTreeMap;
-use rustsimulationservice::parser::execute_qasm;
+use rustsimulationservice::coefficient;
+use rustsimulationservice::coefficient::ComplexCoefficient;
+use rustsimulationservice::circuit;
+use rustsimulationservice::create_circuit_builder;
+use rustsimulationservice::drawer;
+use rustsimulationservice::exporter;
+use rustsimulationservice::parser;
+use rustsimulationservice::routing;
+use rustsimulationservice::transpiler;
+use rustsimulationservice::parser::{execute_qasm, unitary_qasm, SimError};
+use rustsimulationservice::unitary::UnitaryError;
+use rustsimulationservice::validation;
 
 #[test]
 fn test_lexer() {
@@ -53,12 +64,669 @@ fn test_parser() {
     measure q[0]->c[2];
     "#;
 
-    let result = execute_qasm(source);
+    let result = execute_qasm(source).unwrap();
     let mut expect = BTreeMap::new();
     let mut regs = BTreeMap::new();
     regs.insert(0, 1);
     regs.insert(1, 0);
     regs.insert(2, 1);
-    expect.insert('c', regs);
+    expect.insert("c".to_string(), regs);
     assert_eq!(result, expect);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_parse_circuit() {
+    let source = r#"
+    OPENQASM 2.0;
+    qreg q[2];
+    creg c[2];
+    x q[0];
+    rz(0.5) q[1];
+    cx q[0], q[1];
+    barrier q;
+    measure q[1]->c[1];
+    if(c==2) x q[0];
+    "#;
+
+    let circuit = parser::parse_qasm(source).unwrap();
+    assert_eq!(circuit.num_qubits(), 2);
+    assert_eq!(circuit.num_clbits(), 2);
+    let instructions = circuit.get_instructions();
+    assert_eq!(instructions.len(), 6);
+    assert_eq!(instructions[1].get_params(), &[0.5]);
+    assert_eq!(instructions[3].get_qubits(), &[circuit::create_bit("q", 0), circuit::create_bit("q", 1)]);
+    assert_eq!(instructions[4].get_clbits(), &[circuit::create_bit("c", 1)]);
+    assert_eq!(instructions[5].get_condition(), Some(&circuit::create_condition("c", 2)));
+
+    // The same circuit can be simulated any number of times.
+    for _ in 0..2 {
+        let result = parser::execute_circuit(&circuit, &parser::create_execution_options()).unwrap();
+        assert_eq!(result.get_classical_regs()["c"].get(&1), Some(&1));
+    }
+}
+
+#[test]
+fn test_export_round_trip() {
+    let circuit = create_circuit_builder()
+        .qreg("q", 3)
+        .qreg("anc", 1)
+        .creg("c", 3)
+        .h(("q", 0))
+        .cx(("q", 0), ("anc", 0))
+        .rz(-0.5, ("q", 1))
+        .gate("cp", vec![("q", 1).into(), ("q", 2).into()], vec![0.25])
+        .gate("ccz", vec![("q", 0).into(), ("q", 1).into(), ("q", 2).into()], vec![])
+        .gate("sx", vec![("anc", 0).into()], vec![])
+        .barrier()
+        .measure(("q", 2), ("c", 2))
+        .x(("q", 0)).c_if("c", 4)
+        .reset(("anc", 0))
+        .build();
+
+    let exported = exporter::export_qasm(&circuit).unwrap();
+    assert!(exported.starts_with("OPENQASM 2.0;\ninclude \"qelib1.inc\";\n"));
+    assert!(exported.contains("rz(-0.5) q[1];"));
+    assert!(exported.contains("if(c==4) x q[0];"));
+
+    // ccz is not in qelib1.inc, so it is written as h, ccx, h.
+    let parsed = parser::parse_qasm(&exported).unwrap();
+    let expected = transpiler::transpile(&circuit, &exporter::QELIB1_GATES).unwrap();
+    assert_eq!(parsed.get_qregs(), expected.get_qregs());
+    assert_eq!(parsed.get_cregs(), expected.get_cregs());
+    assert_eq!(parsed.get_instructions().len(), expected.get_instructions().len() + 1);
+    let gates = |circuit:&circuit::Circuit| -> Vec<String> {
+        circuit.get_instructions().iter().filter(|instruction| instruction.get_name() != circuit::BARRIER).map(|instruction| instruction.to_string()).collect()
+    };
+    assert_eq!(gates(&parsed), gates(&expected));
+    assert_eq!(exporter::export_qasm(&parsed).unwrap(), exporter::export_qasm(&expected).unwrap());
+
+    let mut invalid = circuit::create_circuit();
+    invalid.add_qreg("Q", 1);
+    assert_eq!(exporter::export_qasm(&invalid), Err(exporter::ExportError::InvalidIdentifier("Q".to_string())));
+}
+
+#[test]
+fn test_circuit_builder_matches_parsed_qasm() {
+    let source = r#"
+    OPENQASM 2.0;
+    qreg q[3];
+    creg c[3];
+    h q[0];
+    h q[1];
+    h q[2];
+    cx q[0], q[2];
+    rz(0.25) q[1];
+    barrier q;
+    measure q[0]->c[0];
+    measure q[1]->c[1];
+    measure q[2]->c[2];
+    if(c==5) x q[1];
+    "#;
+
+    let circuit = create_circuit_builder()
+        .qreg("q", 3)
+        .creg("c", 3)
+        .h_all("q")
+        .cx(("q", 0), ("q", 2))
+        .rz(0.25, ("q", 1))
+        .barrier()
+        .measure_all("q", "c")
+        .x(("q", 1)).c_if("c", 5)
+        .build();
+    assert_eq!(circuit, parser::parse_qasm(source).unwrap());
+
+    let bell = create_circuit_builder().qreg("q", 2).creg("c", 2).x(("q", 0)).cx(("q", 0), ("q", 1)).measure_all("q", "c").build();
+    let result = parser::execute_circuit(&bell, &parser::create_execution_options()).unwrap();
+    assert_eq!(result.get_classical_regs()["c"].values().cloned().collect::<Vec<usize>>(), vec![1, 1]);
+}
+
+#[test]
+fn test_metrics() {
+    let source = r#"
+    OPENQASM 2.0;
+    qreg q[3];
+    creg c[3];
+    h q[0];
+    cx q[0], q[1];
+    cx q[1], q[2];
+    x q[0];
+    barrier q;
+    measure q[2]->c[2];
+    if(c==1) x q[0];
+    "#;
+
+    let metrics = parser::metrics_qasm(source).unwrap();
+    assert_eq!(metrics.get_depth(), 5);
+    assert_eq!(metrics.get_critical_path(), &[0, 1, 2, 5, 6]);
+    assert_eq!(metrics.get_width(), 3);
+    assert_eq!(metrics.get_num_gates(), 5);
+    assert_eq!(metrics.get_two_qubit_gates(), 2);
+    assert_eq!(metrics.get_t_count(), 0);
+    assert_eq!(metrics.get_gate_counts().get("x"), Some(&2));
+    assert_eq!(metrics.get_gate_counts().get("measure"), Some(&1));
+    assert_eq!(metrics.get_gate_counts().get("barrier"), None);
+}
+
+#[test]
+fn test_execute_transpiled() {
+    let source = r#"
+    OPENQASM 2.0;
+    qreg q[3];
+    creg c[3];
+    x q[0];
+    h q[1];
+    z q[1];
+    h q[1];
+    ccx q[0], q[1], q[2];
+    swap q[0], q[1];
+    measure q[0]->c[0];
+    measure q[1]->c[1];
+    measure q[2]->c[2];
+    "#;
+
+    let mut options = parser::create_execution_options();
+    options.set_basis(Some(vec!["rz".to_string(), "sx".to_string(), "x".to_string(), "cx".to_string()]));
+    let result = parser::simulate_qasm(source, &options).unwrap();
+    let transpiled = result.get_transpiled().unwrap();
+    assert!(transpiled.get_instructions().iter().all(|instruction| {
+        ["rz", "sx", "x", "cx", circuit::MEASURE].contains(&instruction.get_name())
+    }));
+    let expected:BTreeMap<usize, usize> = vec![(0, 1), (1, 1), (2, 1)].into_iter().collect();
+    assert_eq!(result.get_classical_regs().get("c"), Some(&expected));
+}
+
+#[test]
+fn test_execute_routed() {
+    let source = r#"
+    OPENQASM 2.0;
+    qreg q[4];
+    creg c[4];
+    x q[0];
+    cx q[0], q[3];
+    cx q[3], q[1];
+    measure q[0]->c[0];
+    measure q[1]->c[1];
+    measure q[2]->c[2];
+    measure q[3]->c[3];
+    "#;
+
+    let mut options = parser::create_execution_options();
+    options.set_coupling_map(Some(routing::create_line_coupling_map(4)));
+    let result = parser::simulate_qasm(source, &options).unwrap();
+    let routed = result.get_routing().unwrap();
+    for instruction in routed.get_circuit().get_instructions() {
+        let qubits = instruction.get_qubits();
+        assert!(qubits.len() < 2 || (qubits[0].get_index() as i64 - qubits[1].get_index() as i64).abs() == 1);
+    }
+    let expected:BTreeMap<usize, usize> = vec![(0, 1), (1, 1), (2, 0), (3, 1)].into_iter().collect();
+    assert_eq!(result.get_classical_regs().get("c"), Some(&expected));
+}
+
+#[test]
+fn test_conditional_execution() {
+    // Teleports |1> from q[0] to q[2], correcting q[2] according to the measurements.
+    let source = r#"
+    OPENQASM 2.0;
+    include "qelib1.inc";
+    qreg q[3];
+    creg a[1];
+    creg b[1];
+    creg r[1];
+    x q[0];
+    h q[1];
+    cx q[1], q[2];
+    cx q[0], q[1];
+    h q[0];
+    measure q[0] -> a[0];
+    measure q[1] -> b[0];
+    if(b==1) x q[2];
+    if(a==1) z q[2];
+    measure q[2] -> r[0];
+    "#;
+
+    for _ in 0..10 {
+        assert_eq!(execute_qasm(source).unwrap().get("r").and_then(|r| r.get(&0)), Some(&1));
+    }
+
+    // Conditions compare the whole register, with c[0] as its least significant bit.
+    let source = r#"
+    OPENQASM 2.0;
+    qreg q[4];
+    creg c[3];
+    x q[0];
+    x q[2];
+    measure q[0] -> c[0];
+    measure q[2] -> c[2];
+    if(c==5) x q[3];
+    if(c==1) x q[1];
+    reset q[0];
+    measure q[0] -> c[0];
+    measure q[3] -> c[1];
+    "#;
+    let expected:BTreeMap<usize, usize> = vec![(0, 0), (1, 1), (2, 1)].into_iter().collect();
+    assert_eq!(execute_qasm(source).unwrap().get("c"), Some(&expected));
+    assert_eq!(parser::get_classical_reg_value(&expected), Some(6));
+    assert_eq!(parser::get_classical_reg_value(&parser::init_classical_reg(3)), Some(0));
+
+    // Bits which are never measured read as zero.
+    let expected:BTreeMap<usize, usize> = vec![(0, 0), (1, 0), (2, 1)].into_iter().collect();
+    assert_eq!(execute_qasm("OPENQASM 2.0;\nqreg q[1];\ncreg c[3];\nx q[0];\nmeasure q[0] -> c[2];").unwrap().get("c"), Some(&expected));
+}
+
+#[test]
+fn test_register_broadcasting() {
+    let source = r#"
+    OPENQASM 2.0;
+    include "qelib1.inc";
+    qreg q[3];
+    qreg r[3];
+    qreg a[1];
+    creg c[3];
+    creg d[3];
+    x q;
+    cx q, r;
+    cx a[0], r;
+    measure q -> c;
+    measure r -> d;
+    "#;
+
+    let circuit = parser::parse_qasm(source).unwrap();
+    assert_eq!(circuit.get_instructions().iter().filter(|instruction| instruction.get_name() == "cx").count(), 6);
+    assert_eq!(circuit.get_instructions()[6].to_string(), "cx a[0], r[0]");
+    let result = execute_qasm(source).unwrap();
+    let ones:BTreeMap<usize, usize> = vec![(0, 1), (1, 1), (2, 1)].into_iter().collect();
+    assert_eq!(result.get("c"), Some(&ones));
+    assert_eq!(result.get("d"), Some(&ones));
+
+    assert_eq!(parser::parse_qasm("OPENQASM 2.0;\nqreg q[2];\nqreg r[3];\ncx q, r;").unwrap_err(), SimError::ArityMismatch("cannot apply cx to registers of 2 and 3 qubits".to_string()));
+    assert_eq!(parser::parse_qasm("OPENQASM 2.0;\nqreg q[2];\ncreg c[3];\nmeasure q -> c;").unwrap_err(), SimError::ArityMismatch("cannot measure 2 qubits into 3 bits".to_string()));
+    assert_eq!(parser::parse_qasm("OPENQASM 2.0;\nqreg q[2];\nh r;").unwrap_err(), SimError::UndeclaredRegister("r".to_string()));
+}
+
+#[test]
+fn test_full_register_names() {
+    let source = r#"
+    OPENQASM 2.0;
+    include "qelib1.inc";
+    qreg anc[2];
+    qreg a[3];
+    creg cout[2];
+    creg c[3];
+    x a[1];
+    cx a[1], anc[0];
+    measure anc -> cout;
+    measure a -> c;
+    "#;
+
+    let result = execute_qasm(source).unwrap();
+    let cout:BTreeMap<usize, usize> = vec![(0, 1), (1, 0)].into_iter().collect();
+    let c:BTreeMap<usize, usize> = vec![(0, 0), (1, 1), (2, 0)].into_iter().collect();
+    assert_eq!(result.get("cout"), Some(&cout));
+    assert_eq!(result.get("c"), Some(&c));
+}
+
+#[test]
+fn test_gate_definitions() {
+    let source = r#"
+    OPENQASM 2.0;
+    include "qelib1.inc";
+    gate bell a, b { h a; cx a, b; }
+    gate twist(theta, phi) a, b { bell b, a; rz(-theta/2) a; crz((theta + phi)*2) a, b; }
+    qreg q[3];
+    creg c[1];
+    twist(0.5, pi/4) q[2], q[0];
+    rzz(0.3) q[0], q[1];
+    if(c==1) bell q[0], q[1];
+    "#;
+
+    let circuit = parser::parse_qasm(source).unwrap();
+    let statements:Vec<String> = circuit.get_instructions().iter().map(|instruction| instruction.to_string()).collect();
+    let angle = (0.5 + std::f64::consts::PI/4.0)*2.0;
+    assert_eq!(statements, vec![
+        "h q[0]".to_string(), "cx q[0], q[2]".to_string(), "rz(-0.25) q[2]".to_string(), format!("crz({}) q[2], q[0]", angle),
+        "cx q[0], q[1]".to_string(), "u1(0.3) q[1]".to_string(), "cx q[0], q[1]".to_string(),
+        "if(c==1) h q[0]".to_string(), "if(c==1) cx q[0], q[1]".to_string()
+    ]);
+
+    // Gates of qelib1.inc which the simulator does not implement are expanded from the library.
+    let c3x = unitary_qasm("OPENQASM 2.0;\ninclude \"qelib1.inc\";\nqreg q[4];\nc3x q[0], q[1], q[2], q[3];").unwrap();
+    let cccx = unitary_qasm("OPENQASM 2.0;\nqreg q[4];\ncccx q[0], q[1], q[2], q[3];").unwrap();
+    for (row, expected) in c3x.iter().zip(cccx.iter()) {
+        for (actual, expected) in row.iter().zip(expected.iter()) {
+            assert_close(actual, expected.get_real_component().get_magnitude(), expected.get_imaginary_component().get_magnitude());
+        }
+    }
+
+    assert_eq!(parser::parse_qasm("OPENQASM 2.0;\ngate g(a) x { rz(a) x; }\nqreg q[1];\ng q[0];").unwrap_err(), SimError::ArityMismatch("gate g takes 1 parameters and 1 qubits".to_string()));
+    assert_eq!(parser::parse_qasm("OPENQASM 2.0;\ngate g x { g x; }\nqreg q[1];\ng q[0];").unwrap_err(), SimError::ResourceLimit("gate definitions are nested more than 64 deep".to_string()));
+    assert_eq!(parser::parse_qasm("OPENQASM 2.0;\ninclude \"qelib1.inc\";\ngate cz a, b { h b; cx a, b; h b; }").unwrap_err(), SimError::DuplicateDeclaration("cz".to_string()));
+    assert_eq!(parser::parse_qasm("OPENQASM 2.0;\ninclude \"other.inc\";").unwrap_err(), SimError::Parse{line: 2, column: 1, message: "cannot include other.inc".to_string()});
+}
+
+#[test]
+fn test_parameter_expressions() {
+    let source = r#"
+    OPENQASM 2.0;
+    gate rot(theta, phi) a { u3(theta/2, -phi, sqrt(theta)*cos(phi)) a; }
+    qreg q[1];
+    rz(-pi/4 + 2*(1 - 0.5)) q[0];
+    rot(ln(4), pi) q[0];
+    p(exp(1)) q[0];
+    "#;
+
+    let circuit = parser::parse_qasm(source).unwrap();
+    let params:Vec<&[f64]> = circuit.get_instructions().iter().map(|instruction| instruction.get_params()).collect();
+    let pi = std::f64::consts::PI;
+    assert_eq!(params[0], &[-pi/4.0 + 1.0]);
+    assert_eq!(params[1], &[4f64.ln()/2.0, -pi, 4f64.ln().sqrt()*pi.cos()]);
+    assert_eq!(params[2], &[1f64.exp()]);
+
+    let circuit = parser::parse_qasm("OPENQASM 2.0;\nqreg q[1];\nrz(arcsin(1) + log(1)) q[0];").unwrap();
+    assert_eq!(circuit.get_instructions()[0].get_params(), &[pi/2.0]);
+
+    assert_eq!(
+        parser::parse_qasm("OPENQASM 2.0;\nqreg q[1];\nrz(theta) q[0];").unwrap_err(),
+        SimError::Invalid("could not evaluate parameter theta of rz: undefined identifier theta".to_string())
+    );
+    assert_eq!(
+        parser::parse_qasm("OPENQASM 2.0;\nqreg q[1];\nrz(1/(1-1)) q[0];").unwrap_err(),
+        SimError::Invalid("could not evaluate parameter 1/(1-1) of rz: expression evaluates to inf".to_string())
+    );
+}
+
+#[test]
+fn test_parse_qasm3() {
+    let source = r#"
+    OPENQASM 3.0;
+    include "stdgates.inc";
+    qubit[3] q;
+    bit[2] c;
+    const int n = 3;
+    h q[0];
+    for int i in [1:n - 1] {
+        cx q[i - 1], q[i];
+    }
+    c[0] = measure q[0];
+    measure q[1] -> c[1];
+    if (c == 3) {
+        x q[2];
+    } else if (c[0]) {
+        z q[2];
+    }
+    reset q;
+    "#;
+
+    let circuit = parser::parse_qasm(source).unwrap();
+    assert_eq!(circuit.get_qregs(), &[circuit::create_register("q", 3)]);
+    assert_eq!(circuit.get_cregs(), &[circuit::create_register("c", 2)]);
+    let statements:Vec<String> = circuit.get_instructions().iter().map(|instruction| instruction.to_string()).collect();
+    assert_eq!(&statements[..5], &["h q[0]", "cx q[0], q[1]", "cx q[1], q[2]", "measure q[0] -> c[0]", "measure q[1] -> c[1]"]);
+    // The else branch runs when c is 1, the only other value with c[0] set.
+    assert_eq!(circuit.get_instructions()[5].get_condition(), Some(&circuit::create_condition("c", 3)));
+    assert_eq!(circuit.get_instructions()[6].get_name(), "z");
+    assert_eq!(circuit.get_instructions()[6].get_condition(), Some(&circuit::create_condition("c", 1)));
+    assert_eq!(circuit.get_instructions()[7..].iter().filter(|instruction| instruction.get_name() == circuit::RESET).count(), 3);
+
+    // Programs are executed along the same path as OpenQASM 2.0 ones.
+    let result = execute_qasm("OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit[2] q;\nbit[2] c;\nx q;\nc = measure q;").unwrap();
+    let expected:BTreeMap<usize, usize> = vec![(0, 1), (1, 1)].into_iter().collect();
+    assert_eq!(result.get("c"), Some(&expected));
+
+    // Problems get the same kinds of error as in OpenQASM 2.0, and the rest keep their position.
+    let error = parser::parse_qasm("OPENQASM 3;\nqubit q;\nx q");
+    assert_eq!(error.unwrap_err().get_position(), Some((3, 4)));
+    let error = |body:&str| parser::parse_qasm(&format!("OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit[2] q;\nbit[2] c;\n{}", body)).unwrap_err();
+    assert_eq!(parser::parse_qasm("OPENQASM 3;\nqubit q;\nh q;").unwrap_err(), SimError::UnsupportedGate("h (is stdgates.inc included?)".to_string()));
+    assert_eq!(error("$"), SimError::Lex{line: 5, column: 1, message: "unexpected character $".to_string()});
+    assert_eq!(error("x r;"), SimError::UndeclaredRegister("r".to_string()));
+    assert_eq!(error("x q[2];"), SimError::IndexOutOfRange{register: "q".to_string(), index: 2, size: 2});
+    assert_eq!(error("qubit q;"), SimError::DuplicateDeclaration("q".to_string()));
+    assert_eq!(error("cx q[0], q[0];"), SimError::RepeatedQubit("cx".to_string()));
+    assert_eq!(error("qubit[3] r;\ncx q, r;"), SimError::ArityMismatch("cannot apply cx to registers of 2 and 3 qubits".to_string()));
+    assert_eq!(error("rx q[0];").get_kind(), "arity_mismatch");
+    assert_eq!(error("for int i in [0:2000000] x q;").get_kind(), "resource_limit");
+}
+
+#[test]
+fn test_qasm3_comparison_conditions() {
+    let source = r#"
+    OPENQASM 3;
+    include "stdgates.inc";
+    qubit[3] q;
+    qubit r;
+    bit[3] c;
+    bit[12] wide;
+    x q[1];
+    x q[2];
+    c = measure q;
+    if (c >= 4) x r; else h r;
+    if (2 < wide) z r;
+    "#;
+
+    // A comparison is one condition, and the else branch runs under its negation.
+    let circuit = parser::parse_qasm(source).unwrap();
+    let conditions:Vec<&circuit::Condition> = circuit.get_instructions().iter().filter_map(|instruction| instruction.get_condition()).collect();
+    assert_eq!(conditions, vec![
+        &circuit::create_comparison_condition("c", circuit::Comparison::GreaterEqual, 4),
+        &circuit::create_comparison_condition("c", circuit::Comparison::Less, 4),
+        &circuit::create_comparison_condition("wide", circuit::Comparison::Greater, 2)
+    ]);
+    assert_eq!(circuit.get_instructions()[5].to_string(), "if(c>=4) x r[0]");
+
+    let result = execute_qasm(&source.replace("c = measure q;", "c = measure q;\nbit[1] out;\n").replace("z r;", "z r;\nout = measure r;")).unwrap();
+    assert_eq!(result.get("out").and_then(|out| out.get(&0)), Some(&1));
+    let wide = "OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit[12] w;\nqubit r;\nbit[12] wide;\nbit out;\nx w[11];\nwide = measure w;\nif (wide > 2000) x r;\nout = measure r;";
+    assert_eq!(execute_qasm(wide).unwrap().get("out").and_then(|out| out.get(&0)), Some(&1));
+
+    // The exporter writes a comparison as an equality for each value which satisfies it.
+    let exported = exporter::export_qasm(&parser::parse_qasm(&source.replace("if (2 < wide) z r;", "")).unwrap()).unwrap();
+    assert_eq!(exported.matches("if(c==").count(), 8);
+    assert_eq!(
+        exporter::export_qasm(&circuit).unwrap_err(),
+        exporter::ExportError::UnsupportedCondition("if(wide>2) z r[0]".to_string())
+    );
+    assert!(drawer::draw(&circuit, drawer::Style::Ascii, drawer::DEFAULT_WIDTH).contains("[>2]"));
+}
+
+#[test]
+fn test_qasm3_gate_modifiers() {
+    let unitary = |body:&str| unitary_qasm(&format!("OPENQASM 3;\ninclude \"stdgates.inc\";\nqubit[3] q;\n{}", body)).unwrap();
+    let equivalent = [
+        ("ctrl @ x q[0], q[1];", "cx q[0], q[1];"),
+        ("ctrl(2) @ x q[0], q[1], q[2];", "ccx q[0], q[1], q[2];"),
+        ("ctrl @ ctrl @ x q[2], q[0], q[1];", "ccx q[2], q[0], q[1];"),
+        ("negctrl @ x q[0], q[1];", "x q[0]; cx q[0], q[1]; x q[0];"),
+        ("inv @ s q[0];", "sdg q[0];"),
+        ("inv @ ctrl @ rx(0.3) q[0], q[1];", "crx(-0.3) q[0], q[1];"),
+        ("pow(2) @ t q[1];", "s q[1];"),
+        ("pow(-1) @ s q[1];", "sdg q[1];"),
+        ("pow(0.5) @ rz(pi/3) q[1];", "rz(pi/6) q[1];"),
+        ("ctrl @ swap q[0], q[1], q[2];", "cswap q[0], q[1], q[2];"),
+        ("gate g(a) x, y { h x; cp(a) x, y; } inv @ g(0.4) q[1], q[2];", "cp(-0.4) q[1], q[2]; h q[1];"),
+        ("cu(pi, 0, pi, 0) q[0], q[2];", "cx q[0], q[2];"),
+        ("cu(0, 0, 0, 0.4) q[0], q[2];", "p(0.4) q[0];")
+    ];
+    for (modified, expected) in equivalent.iter() {
+        let (actual, expected) = (unitary(modified), unitary(expected));
+        for (actual_row, expected_row) in actual.iter().zip(expected.iter()) {
+            for (actual, expected) in actual_row.iter().zip(expected_row.iter()) {
+                assert_close(actual, expected.get_real_component().get_magnitude(), expected.get_imaginary_component().get_magnitude());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_draw_circuit() {
+    let source = r#"
+    OPENQASM 2.0;
+    qreg q[2];
+    creg c[1];
+    h q[0];
+    cx q[0], q[1];
+    measure q[1] -> c[0];
+    "#;
+
+    let circuit = parser::parse_qasm(source).unwrap();
+    let expected = [
+        "q[0]: -[h]---*--------",
+        "             |",
+        "q[1]: ------(+)--[M]--",
+        "                  |",
+        "c[0]: ============v==="
+    ];
+    assert_eq!(drawer::draw(&circuit, drawer::Style::Ascii, drawer::DEFAULT_WIDTH), expected.join("\n"));
+
+    let wrapped = drawer::draw(&circuit, drawer::Style::Unicode, 16);
+    assert_eq!(wrapped.matches("q[0]:").count(), 2);
+    assert!(wrapped.contains("┤M├"));
+
+    // Every controlled gate has a dot on each control wire.
+    let circuit = parser::parse_qasm("OPENQASM 2.0;\nqreg q[3];\nccx q[0], q[1], q[2];\ncu1(0.5) q[2], q[0];\ncswap q[1], q[0], q[2];").unwrap();
+    let expected = [
+        "q[0]: --*---[u1(0.5)]--[swap]--",
+        "        |       |        |",
+        "q[1]: --*-------+--------*-----",
+        "        |       |        |",
+        "q[2]: -(+)------*------[swap]--"
+    ];
+    assert_eq!(drawer::draw(&circuit, drawer::Style::Ascii, drawer::DEFAULT_WIDTH), expected.join("\n"));
+}
+
+fn assert_close(actual:&ComplexCoefficient, real:f64, imaginary:f64) {
+    assert!((actual.get_real_component().get_magnitude() - real).abs() < 1e-9);
+    assert!((actual.get_imaginary_component().get_magnitude() - imaginary).abs() < 1e-9);
+}
+
+#[test]
+fn test_unitary_of_hadamard_and_cx() {
+    let source = r#"
+    OPENQASM 2.0;
+    qreg q[1];
+    qreg r[1];
+    h q[0];
+    cx q[0], r[0];
+    "#;
+
+    let unitary = unitary_qasm(source).unwrap();
+    let h = std::f64::consts::FRAC_1_SQRT_2;
+    assert_eq!(unitary.len(), 4);
+    // |00> -> (|00> + |11>)/sqrt(2), with q[0] as bit 0 and r[0] as bit 1.
+    assert_close(&unitary[0][0], h, 0.0);
+    assert_close(&unitary[3][0], h, 0.0);
+    assert_close(&unitary[1][0], 0.0, 0.0);
+    // |01> -> (|00> - |11>)/sqrt(2)
+    assert_close(&unitary[0][1], h, 0.0);
+    assert_close(&unitary[3][1], -h, 0.0);
+}
+
+#[test]
+fn test_unitary_is_unitary() {
+    let source = r#"
+    OPENQASM 2.0;
+    qreg q[3];
+    h q[0];
+    ry(0.3) q[1];
+    cx q[0], q[2];
+    rz(1.1) q[2];
+    y q[1];
+    rx(0.7) q[0];
+    cx q[2], q[1];
+    "#;
+
+    let unitary = unitary_qasm(source).unwrap();
+    for i in 0..8 {
+        for j in 0..8 {
+            let mut sum = coefficient::create_complex_coefficient_from_parts(0.0, 0.0);
+            for row in &unitary {
+                let mut conjugate = row[i];
+                conjugate.complex_conjugate();
+                sum = sum.add_to_complex_coefficient(conjugate.multiply_by_complex_coefficient(row[j]));
+            }
+            assert_close(&sum, if i == j { 1.0 } else { 0.0 }, 0.0);
+        }
+    }
+}
+
+#[test]
+fn test_unitary_rejects_measurement() {
+    let source = r#"
+    OPENQASM 2.0;
+    qreg q[1];
+    creg c[1];
+    h q[0];
+    measure q[0] -> c[0];
+    "#;
+
+    assert_eq!(unitary_qasm(source).err(), Some(UnitaryError::NonUnitaryOperation("a measurement".to_string())));
+    assert_eq!(unitary_qasm("OPENQASM 2.0; qreg q[1]; h r[0];").err(), Some(UnitaryError::UndeclaredRegister("r".to_string())));
+    assert_eq!(unitary_qasm("OPENQASM 2.0; qreg q[1]; h q[1];").err(), Some(UnitaryError::QubitOutOfRange("q[1]".to_string())));
+    assert_eq!(unitary_qasm("OPENQASM 2.0; qreg q[1]; foo q[0];").err(), Some(UnitaryError::UnsupportedGate("foo 0".to_string())));
+}
+
+#[test]
+fn test_execution_errors() {
+    let error = |source:&str| execute_qasm(source).unwrap_err();
+    assert_eq!(error("OPENQASM 2.0;\nqreg q[2];\nx q[0]\nx q[1];"), SimError::Parse{line: 4, column: 1, message: "Missing Semicolon".to_string()});
+    assert_eq!(error("OPENQASM 2.0;\nqreg q[2];\ncreg c[2];\nif(c=1) x q[0];"), SimError::Lex{line: 4, column: 5, message: "expected ==".to_string()});
+    assert_eq!(error("OPENQASM 2.0;\nqreg q[99999999999];").get_position(), Some((2, 8)));
+    assert_eq!(error("OPENQASM 2.0;\ngate g a {\n  x a;"), SimError::Parse{line: 2, column: 10, message: "unclosed {".to_string()});
+    assert_eq!(error("OPENQASM 2.0;\nqreg q[2];\nx q[0]").get_kind(), "parse");
+
+    assert_eq!(error("OPENQASM 2.0;\nqreg q[2];\nx q[2];"), SimError::IndexOutOfRange{register: "q".to_string(), index: 2, size: 2});
+    assert_eq!(error("OPENQASM 2.0;\nqreg q[2];\ncreg c[2];\nif(d==1) x q[0];"), SimError::UndeclaredRegister("d".to_string()));
+    assert_eq!(error("OPENQASM 2.0;\nqreg q[2];\nfoo q[0];"), SimError::UnsupportedGate("foo q[0]".to_string()));
+    assert_eq!(error("OPENQASM 2.0;\nqreg q[1];\nqreg r[1];\ncz q[0], r[0];"), SimError::UnsupportedGate("cz q[0], r[0]".to_string()));
+    assert_eq!(error("OPENQASM 2.0;\nqreg q[2];\nh q[0], q[1];"), SimError::ArityMismatch("gate h takes 0 parameters and 1 qubits".to_string()));
+
+    // Each definition applies the next 16 times, so the program expands to 16^5 instructions.
+    let mut source = "OPENQASM 2.0;\n".to_string();
+    for level in 0..4 {
+        source.push_str(&format!("gate g{} a {{ {} }}\n", level, format!("g{} a; ", level + 1).repeat(16)));
+    }
+    source.push_str(&format!("gate g4 a {{ {} }}\nqreg q[1];\ng0 q[0];", "x a; ".repeat(16)));
+    assert_eq!(error(&source), SimError::ResourceLimit("program expands to more than 1000000 instructions".to_string()));
+
+    // A circuit which is not parsed is checked before it is simulated.
+    let circuit = create_circuit_builder().qreg("q", 1).x(("q", 1)).build();
+    assert_eq!(parser::execute_circuit(&circuit, &parser::create_execution_options()).err(), Some(SimError::IndexOutOfRange{register: "q".to_string(), index: 1, size: 1}));
+}
+
+#[test]
+fn test_validate_qasm() {
+    let source = r#"
+    OPENQASM 2.0;
+    include "qelib1.inc";
+    qreg q[2];
+    creg c[2];
+    qreg c[3];
+    x q[2];
+    cx q[0], q[0];
+    foo q[1];
+    rz q[0];
+    measure q[1] -> c[5];
+    h q[1];
+    "#;
+
+    assert_eq!(parser::validate_qasm(source), vec![
+        SimError::DuplicateDeclaration("c".to_string()),
+        SimError::IndexOutOfRange{register: "q".to_string(), index: 2, size: 2},
+        SimError::ArityMismatch("gate rz takes 1 parameters and 1 qubits".to_string()),
+        SimError::IndexOutOfRange{register: "c".to_string(), index: 5, size: 2},
+        SimError::RepeatedQubit("cx q[0], q[0]".to_string()),
+        SimError::UnsupportedGate("foo q[1]".to_string())
+    ]);
+    assert_eq!(parser::validate_qasm("OPENQASM 2.0;\nqreg q[2];\nh q[0];\ncx q[0], q[1];"), vec![]);
+    assert_eq!(parser::validate_qasm("OPENQASM 2.0;\nqreg q[2]\nh q[0];").len(), 1);
+
+    // Execution stops at the first of the same problems.
+    assert_eq!(execute_qasm(source).unwrap_err(), SimError::DuplicateDeclaration("c".to_string()));
+    assert_eq!(execute_qasm("OPENQASM 2.0;\nqreg q[1];\ncx q[0], q[0];").unwrap_err().get_kind(), "repeated_qubit");
+
+    let circuit = create_circuit_builder().qreg("q", 2).qreg("q", 1).creg("c", 1).cx(("q", 0), ("q", 2)).build();
+    assert_eq!(validation::validate_circuit(&circuit), vec![
+        SimError::DuplicateDeclaration("q".to_string()),
+        SimError::IndexOutOfRange{register: "q".to_string(), index: 2, size: 2}
+    ]);
+}
+